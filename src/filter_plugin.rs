@@ -0,0 +1,428 @@
+use std::ffi;
+use std::mem;
+use std::os::raw;
+use std::ptr;
+use std::sync::Arc;
+
+use lv2_raw::core::*;
+use lv2_raw::log::*;
+use lv2_raw::urid::*;
+use lv2_raw::worker::*;
+use lv2::core::*;
+use lv2::log::*;
+use lv2::worker::*;
+use synth;
+use synth::worker::{Job, Response};
+use ports::{PluginInfo, PortInfo};
+
+use WorkMessage;
+
+// A stereo low pass effect built from the synth's output filter.
+
+// Indices into FILTER_PORTS.
+#[derive(Clone)]
+#[derive(Copy)]
+enum Port {
+    InputLeft = 0,
+    InputRight = 1,
+    OutputLeft = 2,
+    OutputRight = 3,
+    Cutoff = 4,
+}
+
+const FILTER_PORTS: [PortInfo; 5] = [
+    PortInfo::audio_input("in_l", "Left In").designation("pg:left"),
+    PortInfo::audio_input("in_r", "Right In").designation("pg:right"),
+    PortInfo::audio_output("out_l", "Left Out").designation("pg:left"),
+    PortInfo::audio_output("out_r", "Right Out").designation("pg:right"),
+    PortInfo::control("cutoff", "Cutoff", 1.0, 0.0, 1.0),
+];
+
+pub const FILTER_URI: *const u8 = b"http://quaddmg.com/plugins/synthz-filter\0" as *const u8;
+
+const FILTER_OPTIONAL_FEATURES: [&str; 4] = ["lv2:hardRTCapable", "log:log", "urid:map", "work:schedule"];
+const FILTER_EXTENSION_DATA: [&str; 1] = ["work:interface"];
+
+pub const FILTER: PluginInfo = PluginInfo {
+    uri: FILTER_URI,
+    name: "SynthZ Filter",
+    class: "lv2:LowpassPlugin",
+    ttl: "synthz-filter.ttl",
+    required_features: &[],
+    optional_features: &FILTER_OPTIONAL_FEATURES,
+    extension_data: &FILTER_EXTENSION_DATA,
+    supported_options: &[],
+    parameter_base: FILTER_URI,
+    parameters: &[],
+    path_parameters: &[],
+    ports: &FILTER_PORTS,
+    presets: &[],
+    presets_ttl: "",
+};
+
+struct FilterFx {
+    // Connected port buffers, by index.
+    ports: [*mut raw::c_void; FILTER_PORTS.len()],
+    rate: f32,
+    // Control value the current filter was designed for.
+    cutoff: f32,
+    // Control value of the filter the worker is designing, if it is designing one. Only one is
+    // asked for at a time, so a moving control does not flood the worker.
+    designing: Option<f32>,
+    filter: Option<Box<[synth::filter::Filter; 2]>>,
+    // Without a worker, filters are designed here in place of the one playing, which is then
+    // kept for the next design rather than freed in the audio thread.
+    spare: Option<Box<[synth::filter::Filter; 2]>>,
+    schedule: Option<WorkerSchedule>,
+    logger: Arc<Logger>,
+}
+
+impl FilterFx {
+    fn audio(&self, port: Port) -> *mut f32 {
+        self.ports[port as usize] as *mut f32
+    }
+
+    // Value of a control port, or its default if the host has not connected it.
+    fn control(&self, port: Port) -> f32 {
+        match self.ports[port as usize] as *const f32 {
+            p if p.is_null() => FILTER_PORTS[port as usize].default,
+            p => unsafe { *p },
+        }
+    }
+
+    // Swaps in a new filter, carrying on from the old one, which is returned to be dropped
+    // outside the audio thread.
+    fn apply(&mut self, response: Response) -> Option<Response> {
+        match response {
+            Response::Filter(mut f) => {
+                if let Some(ref old) = self.filter {
+                    for (new, old) in f.iter_mut().zip(old.iter()) {
+                        new.continue_from(old);
+                    }
+                }
+                if let Some(cutoff) = self.designing.take() {
+                    self.cutoff = cutoff;
+                }
+                self.filter.replace(f).map(Response::Filter)
+            }
+            // Never requested here.
+            other => Some(other),
+        }
+    }
+
+    fn request_filter(&mut self, control: f32) {
+        let cutoff = synth::filter_cutoff(control, self.rate);
+        match self.schedule {
+            Some(ref schedule) => {
+                let job = Job::DesignFilter { cutoff, rate: self.rate };
+                if schedule.schedule(WorkMessage::Job(job)).is_err() {
+                    // Asked for again next block.
+                    self.logger.rt(LogLevel::Warning, format_args!("Worker queue full, job deferred"));
+                } else {
+                    self.designing = Some(control);
+                }
+            }
+            None => {
+                if let (Some(filter), Some(spare)) = (self.filter.as_mut(), self.spare.as_mut()) {
+                    for (new, old) in spare.iter_mut().zip(filter.iter()) {
+                        synth::worker::redesign_lowpass(new, cutoff, self.rate);
+                        new.continue_from(old);
+                    }
+                    mem::swap(filter, spare);
+                    self.cutoff = control;
+                }
+            }
+        }
+    }
+}
+
+extern "C" fn instantiate(_descriptor: *const LV2_Descriptor,
+                          rate: f64,
+                          _path: *const raw::c_char,
+                          features: *const *const LV2_Feature) -> LV2_Handle {
+    guard(ptr::null_mut(), || {
+        let mut urid_map = Feature::<LV2_URID_Map>::optional(LV2_URID_map);
+        let mut log = Feature::<LV2_Log_Log>::optional(LV2_LOG_log);
+        let mut schedule = Feature::<LV2_Worker_Schedule>::optional(LV2_WORKER_schedule);
+        let extracted = extract_features(features, vec!(&mut urid_map, &mut log, &mut schedule));
+
+        let logger = Arc::new(Logger::new(log.get(), urid_map.get()));
+        if let Err(e) = extracted {
+            logger.log(LogLevel::Error, format_args!("Cannot instantiate: {}", e));
+            return ptr::null_mut();
+        }
+
+        let schedule = schedule.get().map(WorkerSchedule::new);
+        // Without a worker both filters are made now, to be redesigned by run.
+        let (filter, spare) = match schedule {
+            Some(_) => (None, None),
+            None => {
+                let filter = synth::worker::lowpass(synth::filter_cutoff(1.0, rate as f32), rate as f32);
+                (Some(Box::new([filter.clone(), filter.clone()])), Some(Box::new([filter.clone(), filter])))
+            }
+        };
+        let fx = Box::new(FilterFx {
+            ports: [ptr::null_mut(); FILTER_PORTS.len()],
+            rate: rate as f32,
+            // Not a valid control value, so the first block designs a filter.
+            cutoff: -1.0,
+            designing: None,
+            filter,
+            spare,
+            schedule,
+            logger,
+        });
+        Box::into_raw(fx) as LV2_Handle
+    })
+}
+
+extern "C" fn connect_port(instance: LV2_Handle, port: u32, data: *mut raw::c_void) {
+    guard((), || {
+        let fx = unsafe { &mut *(instance as *mut FilterFx) };
+        if (port as usize) < fx.ports.len() {
+            fx.ports[port as usize] = data;
+        } else {
+            fx.logger.log(LogLevel::Warning, format_args!("Connect to unknown port {}", port));
+        }
+    })
+}
+
+extern "C" fn activate(instance: LV2_Handle) {
+    guard((), || {
+        let fx = unsafe { &*(instance as *const FilterFx) };
+        fx.logger.flush();
+    })
+}
+
+extern "C" fn run(instance: LV2_Handle, n_samples: u32) {
+    guard((), || {
+        let fx = unsafe { &mut *(instance as *mut FilterFx) };
+        let n_samples = n_samples as usize;
+
+        // Moves made while a filter is designed are caught up with once it arrives.
+        let cutoff = fx.control(Port::Cutoff);
+        if cutoff != fx.cutoff && fx.designing.is_none() {
+            fx.request_filter(cutoff);
+        }
+
+        // Hosts may process in place, so the buffers are not borrowed as slices.
+        let (input_left, input_right) = (fx.audio(Port::InputLeft), fx.audio(Port::InputRight));
+        let (output_left, output_right) = (fx.audio(Port::OutputLeft), fx.audio(Port::OutputRight));
+        unsafe {
+            match fx.filter.as_mut() {
+                Some(f) => {
+                    let [ref mut filter_left, ref mut filter_right] = **f;
+                    process(input_left, output_left, n_samples, filter_left);
+                    process(input_right, output_right, n_samples, filter_right);
+                }
+                // Pass through until the first filter arrives.
+                None => {
+                    ptr::copy(input_left, output_left, n_samples);
+                    ptr::copy(input_right, output_right, n_samples);
+                }
+            }
+        }
+
+        if let (Some(schedule), true) = (fx.schedule.as_ref(), fx.logger.needs_flush()) {
+            let _ = schedule.schedule(WorkMessage::FlushLog);
+        }
+    })
+}
+
+// Reads each sample before writing it, so input and output may be the same buffer.
+unsafe fn process(input: *const f32, output: *mut f32, len: usize, filter: &mut synth::filter::Filter) {
+    for i in 0..len {
+        *output.add(i) = filter.filter(*input.add(i));
+    }
+}
+
+extern "C" fn deactivate(instance: LV2_Handle) {
+    guard((), || {
+        let fx = unsafe { &*(instance as *const FilterFx) };
+        fx.logger.flush();
+    })
+}
+
+extern "C" fn cleanup(instance: LV2_Handle) {
+    guard((), || {
+        let fx = unsafe { Box::from_raw(instance as *mut FilterFx) };
+        fx.logger.flush();
+    })
+}
+
+extern "C" fn work(instance: LV2_Handle,
+                   respond_fn: LV2_Worker_Respond_Function,
+                   handle: LV2_Worker_Respond_Handle,
+                   size: u32,
+                   data: *const raw::c_void) -> LV2_Worker_Status {
+    guard(LV2_WORKER_ERR_UNKNOWN, || {
+        // Runs alongside the audio thread, so only the logger may be touched here.
+        let logger = unsafe { &(*(instance as *const FilterFx)).logger };
+
+        match unsafe { read_message::<WorkMessage>(size, data) } {
+            Some(WorkMessage::Job(job)) => {
+                match job.run() {
+                    Ok(response) => {
+                        if respond(respond_fn, handle, response).is_err() {
+                            logger.log(LogLevel::Warning, format_args!("Worker response dropped"));
+                        }
+                    }
+                    Err(e) => logger.log(LogLevel::Error, format_args!("{}", e)),
+                }
+            }
+            Some(WorkMessage::Dispose(response)) => drop(response),
+            Some(WorkMessage::FlushLog) => logger.flush(),
+            None => return LV2_WORKER_ERR_UNKNOWN,
+        }
+        LV2_WORKER_SUCCESS
+    })
+}
+
+extern "C" fn work_response(instance: LV2_Handle, size: u32, data: *const raw::c_void) -> LV2_Worker_Status {
+    guard(LV2_WORKER_ERR_UNKNOWN, || {
+        let fx = unsafe { &mut *(instance as *mut FilterFx) };
+
+        let response = match unsafe { read_message::<Response>(size, data) } {
+            Some(r) => r,
+            None => return LV2_WORKER_ERR_UNKNOWN,
+        };
+        if let (Some(old), Some(schedule)) = (fx.apply(response), fx.schedule.as_ref()) {
+            if let Err(msg) = schedule.schedule(WorkMessage::Dispose(old)) {
+                // Leak rather than free memory in the audio thread.
+                mem::forget(msg);
+            }
+        }
+        LV2_WORKER_SUCCESS
+    })
+}
+
+extern "C" fn end_run(_instance: LV2_Handle) -> LV2_Worker_Status {
+    guard(LV2_WORKER_ERR_UNKNOWN, || LV2_WORKER_SUCCESS)
+}
+
+static WORKER_INTERFACE: LV2_Worker_Interface = LV2_Worker_Interface {
+    work,
+    work_response,
+    end_run,
+};
+
+extern "C" fn extension_data(uri: *const raw::c_char) -> *mut raw::c_void {
+    guard(ptr::null_mut(), || {
+        let uri = unsafe { ffi::CStr::from_ptr(uri) };
+        let worker_uri = unsafe { ffi::CStr::from_ptr(LV2_WORKER_interface as *const raw::c_char) };
+        if uri == worker_uri {
+            &WORKER_INTERFACE as *const LV2_Worker_Interface as *mut raw::c_void
+        } else {
+            ptr::null_mut()
+        }
+    })
+}
+
+pub const DESCRIPTOR: LV2_Descriptor = LV2_Descriptor {
+    URI: FILTER_URI as *const raw::c_char,
+    instantiate,
+    connect_port,
+    activate,
+    run,
+    deactivate,
+    cleanup,
+    extension_data
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::{allocations, Host, NO_MORE_OPTIONS};
+
+    const BLOCK: usize = 64;
+
+    // An instance with its ports connected to buf, and its cutoff control to cutoff.
+    fn connect(handle: LV2_Handle, buf: &mut [Vec<f32>; 4], cutoff: &mut f32) {
+        for (port, buf) in buf.iter_mut().enumerate() {
+            connect_port(handle, port as u32, buf.as_mut_ptr() as *mut raw::c_void);
+        }
+        connect_port(handle, Port::Cutoff as u32, cutoff as *mut f32 as *mut raw::c_void);
+    }
+
+    fn fx<'a>(handle: LV2_Handle) -> &'a mut FilterFx {
+        unsafe { &mut *(handle as *mut FilterFx) }
+    }
+
+    #[test]
+    fn moving_cutoff_keeps_one_design_in_flight() {
+        let host = Host::new();
+        let handle = host.instantiate_with_worker(&DESCRIPTOR, &[NO_MORE_OPTIONS]);
+        let mut buf = [vec![0.5; BLOCK], vec![0.5; BLOCK], vec![0.0; BLOCK], vec![0.0; BLOCK]];
+        let mut cutoff = 0.2;
+        connect(handle, &mut buf, &mut cutoff);
+
+        run(handle, BLOCK as u32);
+        assert_eq!(host.scheduled(), 1);
+        // Passed through until the filter arrives.
+        assert_eq!(buf[Port::OutputLeft as usize], vec![0.5; BLOCK]);
+        for &moved in &[0.3, 0.4, 0.5] {
+            unsafe { *fx(handle).ports[Port::Cutoff as usize].cast::<f32>() = moved };
+            run(handle, BLOCK as u32);
+        }
+        assert_eq!(host.scheduled(), 1);
+
+        host.work(handle, &WORKER_INTERFACE);
+        assert_eq!(fx(handle).cutoff, 0.2);
+        // The latest value is asked for next, and nothing after it.
+        run(handle, BLOCK as u32);
+        assert_eq!(host.scheduled(), 1);
+        host.work(handle, &WORKER_INTERFACE);
+        assert_eq!(fx(handle).cutoff, 0.5);
+        // Only the old filter goes back to the worker, to be freed.
+        run(handle, BLOCK as u32);
+        assert_eq!(host.scheduled(), 1);
+        host.work(handle, &WORKER_INTERFACE);
+        run(handle, BLOCK as u32);
+        assert_eq!(host.scheduled(), 0);
+        assert!(fx(handle).designing.is_none());
+        cleanup(handle);
+    }
+
+    #[test]
+    fn designs_the_worker_cannot_take_are_asked_for_again() {
+        let host = Host::with_queue_size(0);
+        let handle = host.instantiate_with_worker(&DESCRIPTOR, &[NO_MORE_OPTIONS]);
+        let mut buf = [vec![0.5; BLOCK], vec![0.5; BLOCK], vec![0.0; BLOCK], vec![0.0; BLOCK]];
+        let mut cutoff = 0.2;
+        connect(handle, &mut buf, &mut cutoff);
+        run(handle, BLOCK as u32);
+        // Nothing is left in flight, so the next block asks again.
+        assert!(fx(handle).designing.is_none());
+        assert_eq!(fx(handle).cutoff, -1.0);
+        cleanup(handle);
+    }
+
+    #[test]
+    fn without_a_worker_filters_are_designed_in_place() {
+        let host = Host::new();
+        let handle = host.instantiate(&DESCRIPTOR, &[NO_MORE_OPTIONS]);
+        let mut buf = [vec![0.5; BLOCK], vec![0.5; BLOCK], vec![0.0; BLOCK], vec![0.0; BLOCK]];
+        let mut cutoff = 1.0;
+        connect(handle, &mut buf, &mut cutoff);
+        let filters = |fx: &FilterFx| (&**fx.filter.as_ref().unwrap() as *const _, &**fx.spare.as_ref().unwrap() as *const _);
+        let (first, second) = filters(fx(handle));
+        let mut last = 0.0;
+
+        for (i, &moved) in [0.3, 0.4, 0.35].iter().enumerate() {
+            unsafe { *fx(handle).ports[Port::Cutoff as usize].cast::<f32>() = moved };
+            for b in buf[..2].iter_mut() {
+                b.iter_mut().for_each(|v| *v = 0.5);
+            }
+            assert_eq!(allocations(|| run(handle, BLOCK as u32)), 0);
+            assert_eq!(fx(handle).cutoff, moved);
+            // The two filters take turns, so the old one is kept rather than freed.
+            let playing = filters(fx(handle));
+            assert_eq!(playing, if i % 2 == 0 { (second, first) } else { (first, second) });
+            // Carried on from the old filter, so there is no jump at the swap.
+            let out = &buf[Port::OutputLeft as usize];
+            assert!((out[0] - last).abs() < 0.02);
+            last = out[BLOCK - 1];
+        }
+        cleanup(handle);
+    }
+}
@@ -2,105 +2,339 @@
 mod lv2_raw;
 mod lv2;
 mod synth;
+mod filter_plugin;
+mod ports;
+#[cfg(test)]
+mod testing;
 
 use std::ptr;
 use std::mem;
 use std::f32;
 use std::ffi;
 use std::os::raw;
-use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
 
 use lv2_raw::core::*;
 use lv2_raw::urid::*;
 use lv2_raw::atom::*;
+use lv2_raw::buf_size::*;
+use lv2_raw::log::*;
 use lv2_raw::midi::*;
+use lv2_raw::options::*;
+use lv2_raw::parameters::*;
+use lv2_raw::patch::*;
+use lv2_raw::state::*;
+use lv2_raw::time::*;
+use lv2_raw::worker::*;
 use lv2::atom::*;
 use lv2::urid::*;
 use lv2::core::*;
+use lv2::log::*;
 use lv2::midi::*;
+use lv2::options::*;
+use lv2::state::*;
+use lv2::worker::*;
+use synth::params;
+use synth::Mix;
+use synth::presets::{self, Controls};
+use ports::{PathParameter, PluginInfo, PortInfo, ScalePoint};
 
-const CONTROL_INPUT: u32 = 0;
-const SYNTH_OUTPUT: u32 = 1;
-const WAVEFORM: u32 = 2;
-const ATTACK: u32 = 3;
-const DECAY: u32 = 4;
-const SUSTAIN: u32 = 5;
-const RELEASE: u32 = 6;
-const SEC_WAVEFORM: u32 = 7;
-const SEC_FREQ_MUL: u32 = 8;
-const SEC_DEPTH: u32 = 9;
-const FILTER_FREQ: u32 = 10;
-const FILTER_ON: u32 = 11;
+// Indices into SYNTH_PORTS.
+#[derive(Clone)]
+#[derive(Copy)]
+enum Port {
+    Control = 0,
+    OutputLeft = 1,
+    Waveform = 2,
+    Attack = 3,
+    Decay = 4,
+    Sustain = 5,
+    Release = 6,
+    SecWaveform = 7,
+    SecFreqMul = 8,
+    SecDepth = 9,
+    FilterFreq = 10,
+    FilterOn = 11,
+    Notify = 12,
+    OutputRight = 13,
+    Pan = 14,
+    Spread = 15,
+    // Mono lead only.
+    Glide = 16,
+    InputLeft = 17,
+    InputRight = 18,
+    InputLevel = 19,
+    InputFm = 20,
+    CvFm = 21,
+    CvGate = 22,
+    CvPan = 23,
+    CvEnvelope = 24,
+    CvOscillator = 25,
+}
+
+// Values the oscillators read as each waveform.
+const WAVEFORMS: [ScalePoint; 5] = [
+    ScalePoint { label: "Sine", value: 0.0 },
+    ScalePoint { label: "Square", value: 0.2 },
+    ScalePoint { label: "Sawtooth", value: 0.4 },
+    ScalePoint { label: "Triangle", value: 0.6 },
+    ScalePoint { label: "Noise", value: 0.8 },
+];
+
+const SYNTH_PORTS: [PortInfo; 26] = [
+    PortInfo::atom_input("control", "Control", &["midi:MidiEvent", "time:Position", "patch:Message"])
+        .designation("lv2:control"),
+    // Still out from when the synth was mono, so saved sessions find it by symbol.
+    PortInfo::audio_output("out", "Left Out").designation("pg:left"),
+    PortInfo::control("waveform", "Waveform", 0.0, 0.0, 1.0)
+        .properties(&["lv2:enumeration"])
+        .scale_points(&WAVEFORMS),
+    PortInfo::control("attack", "Attack", 0.01, 0.0, 1.0),
+    PortInfo::control("decay", "Decay", 0.013, 0.0, 1.0),
+    PortInfo::control("sustain", "Sustain", 0.5, 0.0, 1.0),
+    PortInfo::control("release", "Release", 0.25, 0.0, 1.0),
+    PortInfo::control("sec_waveform", "Secondary Waveform", 0.0, 0.0, 1.0)
+        .properties(&["lv2:enumeration"])
+        .scale_points(&WAVEFORMS),
+    PortInfo::control("sec_freq_mul", "Secondary Frequency Multiplier", 0.0, 0.0, 1.0),
+    PortInfo::control("sec_depth", "Secondary Depth", 0.0, 0.0, 1.0),
+    PortInfo::control("filter_freq", "Filter Frequency", 0.0, 0.0, 1.0),
+    PortInfo::control("filter_on", "Filter On/Off", 0.0, 0.0, 1.0).properties(&["lv2:toggled"]),
+    PortInfo::atom_output("notify", "Notify", &["patch:Message"]).designation("lv2:control"),
+    PortInfo::audio_output("out_r", "Right Out").designation("pg:right"),
+    PortInfo::control("pan", "Pan", 0.0, -1.0, 1.0),
+    PortInfo::control("spread", "Stereo Spread", 0.0, 0.0, 1.0),
+    PortInfo::control("glide", "Glide", 0.0, 0.0, 1.0).unit("units:s"),
+    PortInfo::audio_input("in_l", "Left In").properties(&["lv2:connectionOptional"]),
+    PortInfo::audio_input("in_r", "Right In").properties(&["lv2:connectionOptional"]),
+    PortInfo::control("input_level", "Input Level", 0.0, 0.0, 1.0),
+    PortInfo::control("input_fm", "Input FM Depth", 0.0, 0.0, 1.0),
+    PortInfo::cv_input("cv_fm", "FM CV").properties(&["lv2:connectionOptional"]),
+    PortInfo::cv_input("cv_gate", "Gate CV").properties(&["lv2:connectionOptional"]),
+    PortInfo::cv_input("cv_pan", "Pan CV").properties(&["lv2:connectionOptional"]),
+    PortInfo::cv_output("cv_env", "Envelope CV").properties(&["lv2:connectionOptional"]),
+    PortInfo::cv_output("cv_osc", "Oscillator CV").properties(&["lv2:connectionOptional"]),
+];
+
+// Rack connectors patched from CV inputs. A CV input joins whatever the rack cables to that input,
+// so hosts that zero unconnected CV ports leave the patch as it was. The gate CV is taken with the
+// note gate by max, so either can open the envelope.
+const CV_INPUTS: [(Port, &str, &str, Mix); 3] = [
+    (Port::CvFm, "primary_osc", "fm_in", Mix::Add),
+    (Port::CvGate, "envelope", "trigger", Mix::Max),
+    (Port::CvPan, "panner", "pan", Mix::Add),
+];
+
+// Rack outputs copied to CV outputs, mixed across voices at the 1/sqrt(voices) scale of the audio.
+const CV_OUTPUTS: [(Port, &str, &str); 2] = [
+    (Port::CvEnvelope, "envelope", "output"),
+    (Port::CvOscillator, "primary_osc", "output"),
+];
+
+const SYNTH_REQUIRED_FEATURES: [&str; 1] = ["urid:map"];
+const SYNTH_OPTIONAL_FEATURES: [&str; 4] = ["lv2:hardRTCapable", "log:log", "opts:options", "work:schedule"];
+const SYNTH_EXTENSION_DATA: [&str; 3] = ["opts:interface", "state:interface", "work:interface"];
+const SYNTH_OPTIONS: [&str; 3] = ["bufsz:maxBlockLength", "bufsz:nominalBlockLength", "param:sampleRate"];
+
+const SYNTHZ: PluginInfo = PluginInfo {
+    uri: AMP_URI,
+    name: "SynthZ",
+    class: "lv2:InstrumentPlugin",
+    ttl: "synthz.ttl",
+    required_features: &SYNTH_REQUIRED_FEATURES,
+    optional_features: &SYNTH_OPTIONAL_FEATURES,
+    extension_data: &SYNTH_EXTENSION_DATA,
+    supported_options: &SYNTH_OPTIONS,
+    parameter_base: AMP_URI,
+    parameters: &params::PARAMS,
+    path_parameters: &[PathParameter { symbol: "patch", name: "Patch" }],
+    // Everything but glide.
+    ports: &[
+        SYNTH_PORTS[0], SYNTH_PORTS[1], SYNTH_PORTS[2], SYNTH_PORTS[3], SYNTH_PORTS[4], SYNTH_PORTS[5],
+        SYNTH_PORTS[6], SYNTH_PORTS[7], SYNTH_PORTS[8], SYNTH_PORTS[9], SYNTH_PORTS[10], SYNTH_PORTS[11],
+        SYNTH_PORTS[12], SYNTH_PORTS[13], SYNTH_PORTS[14], SYNTH_PORTS[15], SYNTH_PORTS[17],
+        SYNTH_PORTS[18], SYNTH_PORTS[19], SYNTH_PORTS[20], SYNTH_PORTS[21], SYNTH_PORTS[22], SYNTH_PORTS[23],
+        SYNTH_PORTS[24], SYNTH_PORTS[25],
+    ],
+    presets: &presets::PRESETS,
+    presets_ttl: "synthz-presets.ttl",
+};
+
+const SYNTHZ_MONO: PluginInfo = PluginInfo {
+    uri: MONO_URI,
+    name: "SynthZ Mono Lead",
+    ttl: "synthz-mono.ttl",
+    ports: &SYNTH_PORTS,
+    presets_ttl: "synthz-mono-presets.ttl",
+    ..SYNTHZ
+};
+
+const SYNTHZ_POLY: PluginInfo = PluginInfo {
+    uri: POLY_URI,
+    name: "SynthZ Poly",
+    ttl: "synthz-poly.ttl",
+    presets_ttl: "synthz-poly-presets.ttl",
+    ..SYNTHZ
+};
+
+// Every plugin in the binary, in descriptor order.
+const PLUGINS: [&PluginInfo; 4] = [&SYNTHZ, &SYNTHZ_MONO, &SYNTHZ_POLY, &filter_plugin::FILTER];
+
+// Block length to prepare for when the host does not give one.
+const DEFAULT_BLOCK_LENGTH: usize = 4096;
+
+// Voices in the polyphonic variant.
+const POLY_VOICES: usize = 8;
+
+// How often peak levels are reported on the notify port.
+const PEAK_REPORTS_PER_SECOND: f64 = 30.0;
 
 #[derive(Debug)]
 pub struct SamplerUris {
-    pub atom_Blank: LV2_URID,
-    pub atom_Int: LV2_URID,
-    pub atom_Long: LV2_URID,
-    pub atom_Float: LV2_URID,
-    pub atom_Object: LV2_URID,
-    pub atom_Path: LV2_URID,
-    pub atom_Property: LV2_URID,
-    pub atom_Resource: LV2_URID,
-    pub atom_Sequence: LV2_URID,
-    pub atom_URID: LV2_URID,
-    pub atom_eventTransfer: LV2_URID,
-    pub midi_Event: LV2_URID,
-    pub patch_Set: LV2_URID,
+    pub atom_blank: LV2_URID,
+    pub atom_double: LV2_URID,
+    pub atom_int: LV2_URID,
+    pub atom_long: LV2_URID,
+    pub atom_float: LV2_URID,
+    pub atom_object: LV2_URID,
+    pub atom_path: LV2_URID,
+    pub atom_property: LV2_URID,
+    pub atom_resource: LV2_URID,
+    pub atom_sequence: LV2_URID,
+    pub atom_urid: LV2_URID,
+    pub atom_beat_time: LV2_URID,
+    pub atom_event_transfer: LV2_URID,
+    pub bufsz_max_block_length: LV2_URID,
+    pub bufsz_nominal_block_length: LV2_URID,
+    pub midi_event: LV2_URID,
+    pub param_sample_rate: LV2_URID,
+    pub patch_get: LV2_URID,
+    pub patch_set: LV2_URID,
     pub patch_property: LV2_URID,
     pub patch_value: LV2_URID,
+    pub time_bar: LV2_URID,
+    pub time_bar_beat: LV2_URID,
+    pub time_beat_unit: LV2_URID,
+    pub time_beats_per_bar: LV2_URID,
+    pub time_beats_per_minute: LV2_URID,
     pub time_frame: LV2_URID,
-    pub time_framesPerSecond: LV2_URID,
+    pub time_frames_per_second: LV2_URID,
     pub time_speed: LV2_URID,
-    pub time_Position: LV2_URID,
+    pub time_position: LV2_URID,
+    pub synthz_status: LV2_URID,
+    pub synthz_voices: LV2_URID,
+    pub synthz_peak: LV2_URID,
+    pub synthz_patch: LV2_URID,
+    pub synthz_rack: LV2_URID,
+    pub synthz_voicing: LV2_URID,
+    pub synthz_preset: LV2_URID,
+    pub synthz_params: Vec<LV2_URID>,
+    // State keys for the control values the engine plays, and for the port values last given to
+    // it, by port symbol.
+    pub synthz_controls: Vec<LV2_URID>,
+    pub synthz_port_controls: Vec<LV2_URID>,
 }
 
 pub fn map_sampler_uris(map: *const LV2_URID_Map) -> SamplerUris {
     SamplerUris {
-        atom_Blank: urid_for_const(map, LV2_ATOM_Blank),
-        atom_Int: urid_for_const(map, LV2_ATOM_Int),
-        atom_Long: urid_for_const(map, LV2_ATOM_Long),
-        atom_Float: urid_for_const(map, LV2_ATOM_Float),
-        atom_Object: urid_for_const(map, LV2_ATOM_Object),
-        atom_Path: urid_for_const(map, LV2_ATOM_Path),
-        atom_Property: urid_for_const(map, LV2_ATOM_Property),
-        atom_Resource: urid_for_const(map, LV2_ATOM_Resource),
-        atom_Sequence: urid_for_const(map, LV2_ATOM_Sequence),
-        atom_URID: urid_for_const(map, LV2_ATOM_URID),
-        atom_eventTransfer: urid_for_const(map, LV2_ATOM_eventTransfer),
-
-        midi_Event: urid_for_const(map, LV2_MIDI_MidiEvent),
-
-        patch_Set: urid_for_const(map, LV2_PATCH_Set),
+        atom_blank: urid_for_const(map, LV2_ATOM_Blank),
+        atom_double: urid_for_const(map, LV2_ATOM_Double),
+        atom_int: urid_for_const(map, LV2_ATOM_Int),
+        atom_long: urid_for_const(map, LV2_ATOM_Long),
+        atom_float: urid_for_const(map, LV2_ATOM_Float),
+        atom_object: urid_for_const(map, LV2_ATOM_Object),
+        atom_path: urid_for_const(map, LV2_ATOM_Path),
+        atom_property: urid_for_const(map, LV2_ATOM_Property),
+        atom_resource: urid_for_const(map, LV2_ATOM_Resource),
+        atom_sequence: urid_for_const(map, LV2_ATOM_Sequence),
+        atom_urid: urid_for_const(map, LV2_ATOM_URID),
+        atom_beat_time: urid_for_const(map, LV2_ATOM_beatTime),
+        atom_event_transfer: urid_for_const(map, LV2_ATOM_eventTransfer),
+
+        bufsz_max_block_length: urid_for_const(map, LV2_BUF_SIZE_maxBlockLength),
+        bufsz_nominal_block_length: urid_for_const(map, LV2_BUF_SIZE_nominalBlockLength),
+
+        midi_event: urid_for_const(map, LV2_MIDI_MidiEvent),
+
+        param_sample_rate: urid_for_const(map, LV2_PARAMETERS_sampleRate),
+
+        patch_get: urid_for_const(map, LV2_PATCH_Get),
+        patch_set: urid_for_const(map, LV2_PATCH_Set),
         patch_property: urid_for_const(map, LV2_PATCH_property),
         patch_value: urid_for_const(map, LV2_PATCH_value),
 
+        time_bar: urid_for_const(map, LV2_TIME_bar),
+        time_bar_beat: urid_for_const(map, LV2_TIME_barBeat),
+        time_beat_unit: urid_for_const(map, LV2_TIME_beatUnit),
+        time_beats_per_bar: urid_for_const(map, LV2_TIME_beatsPerBar),
+        time_beats_per_minute: urid_for_const(map, LV2_TIME_beatsPerMinute),
         time_frame: urid_for_const(map, LV2_TIME_frame),
-        time_framesPerSecond: urid_for_const(map, LV2_TIME_framesPerSecond),
+        time_frames_per_second: urid_for_const(map, LV2_TIME_framesPerSecond),
         time_speed: urid_for_const(map, LV2_TIME_speed),
-        time_Position: urid_for_const(map, LV2_TIME_Position),
+        time_position: urid_for_const(map, LV2_TIME_Position),
+
+        synthz_status: urid_for_plugin(map, "Status"),
+        synthz_voices: urid_for_plugin(map, "voices"),
+        synthz_peak: urid_for_plugin(map, "peak"),
+        synthz_patch: urid_for_plugin(map, "patch"),
+        synthz_rack: urid_for_plugin(map, "rack"),
+        synthz_voicing: urid_for_plugin(map, "voicing"),
+        synthz_preset: urid_for_plugin(map, "preset"),
+        synthz_params: params::PARAMS.iter().map(|p| urid_for_plugin(map, p.symbol)).collect(),
+        synthz_controls: presets::PRESETS[0].controls.values().iter()
+            .map(|&(symbol, _)| urid_for_plugin(map, &format!("playing_{}", symbol))).collect(),
+        synthz_port_controls: presets::PRESETS[0].controls.values().iter()
+            .map(|&(symbol, _)| urid_for_plugin(map, &format!("port_{}", symbol))).collect(),
     }
 }
 
-#[repr(C)]
+fn urid_for_plugin(map: *const LV2_URID_Map, name: &str) -> LV2_URID {
+    let plugin_uri = unsafe { ffi::CStr::from_ptr(AMP_URI as *const raw::c_char) };
+    let uri = ffi::CString::new(format!("{}#{}", plugin_uri.to_str().unwrap(), name)).unwrap();
+    urid_for(map, &uri)
+}
+
 struct Amp {
-    input: *const LV2_Atom,
-    output: *mut f32,
-    waveform: *mut f32,
-    attack: *mut f32,
-    decay: *mut f32,
-    sustain: *mut f32,
-    release: *mut f32,
-    sec_waveform: *mut f32,
-    sec_freq_mul: *mut f32,
-    sec_depth: *mut f32,
-    filter_freq: *mut f32,
-    filter_on: *mut f32,
+    info: &'static PluginInfo,
+    // Connected port buffers, by index in SYNTH_PORTS.
+    ports: [*mut raw::c_void; SYNTH_PORTS.len()],
     synth: synth::ToneIterator,
     sampler_uris: SamplerUris,
+    forge_uris: ForgeUris,
+    peak: f32,
+    peak_frames: u32,
+    peak_period: u32,
+    reported_voices: u32,
+    // Control port values last given to the engine, so a preset holds until a control moves.
+    controls: Option<Controls>,
+    // CV inputs with the rack inputs they feed, and CV outputs with the taps they copy.
+    cv_inputs: Vec<(Port, usize)>,
+    cv_outputs: Vec<(Port, usize)>,
+    // Option values, kept here so get can point the host at them.
+    sample_rate: f32,
+    max_block_length: i32,
+    nominal_block_length: i32,
+    schedule: Option<WorkerSchedule>,
+    logger: Arc<Logger>,
+    // The rack being played as patch text, for save. Kept by the worker once the engine plays a
+    // loaded patch, as save may run alongside the audio thread.
+    rack_text: Mutex<String>,
+}
+
+// Messages passed through the host worker. They are copied whole, so nothing in them may own
+// memory the audio thread would have to allocate.
+#[allow(clippy::large_enum_variant)]
+enum WorkMessage {
+    Job(synth::worker::Job),
+    // Data replaced in the engine, to be freed outside the audio thread. A patch the engine
+    // started playing brings its text for save.
+    Dispose(synth::worker::Response),
+    FlushLog,
 }
 
 const AMP_URI: *const u8 = b"http://quaddmg.com/plugins/synthz\0" as *const u8;
+const MONO_URI: *const u8 = b"http://quaddmg.com/plugins/synthz-mono\0" as *const u8;
+const POLY_URI: *const u8 = b"http://quaddmg.com/plugins/synthz-poly\0" as *const u8;
 
 const LV2DESCRIPTOR: LV2_Descriptor = LV2_Descriptor {
     URI: AMP_URI as *const raw::c_char,
@@ -113,230 +347,1378 @@ const LV2DESCRIPTOR: LV2_Descriptor = LV2_Descriptor {
     extension_data
 };
 
-struct UridExtractor<'a> {
-    urid_uri: &'a ffi::CStr,
-    urid_map: Option<*const LV2_URID_Map>
+// The synth variants share their callbacks, and differ in how notes are given to voices.
+const MONO_DESCRIPTOR: LV2_Descriptor = LV2_Descriptor {
+    URI: MONO_URI as *const raw::c_char,
+    ..LV2DESCRIPTOR
+};
+
+const POLY_DESCRIPTOR: LV2_Descriptor = LV2_Descriptor {
+    URI: POLY_URI as *const raw::c_char,
+    ..LV2DESCRIPTOR
+};
+
+fn plugin_info(descriptor: *const LV2_Descriptor) -> &'static PluginInfo {
+    let uri = unsafe { ffi::CStr::from_ptr((*descriptor).URI) };
+    PLUGINS.iter().cloned()
+        .find(|p| p.uri().as_bytes() == uri.to_bytes())
+        .unwrap_or(&SYNTHZ)
 }
 
-impl <'a> UridExtractor<'a> {
-    fn new() -> UridExtractor<'a> {
-        unsafe {
-            UridExtractor {
-                urid_uri: ffi::CStr::from_ptr(LV2_URID_map as *const raw::c_char),
-                urid_map: None
-            }
-        }
+fn voicing_for(descriptor: *const LV2_Descriptor) -> synth::Voicing {
+    let uri = unsafe { ffi::CStr::from_ptr((*descriptor).URI) };
+    let mono_uri = unsafe { ffi::CStr::from_ptr(MONO_URI as *const raw::c_char) };
+    let poly_uri = unsafe { ffi::CStr::from_ptr(POLY_URI as *const raw::c_char) };
+    if uri == mono_uri {
+        synth::Voicing::MonoLead
+    } else if uri == poly_uri {
+        synth::Voicing::Poly(POLY_VOICES)
+    } else {
+        synth::Voicing::Single
     }
 }
 
-impl <'a> FeatureExtractor for UridExtractor<'a> {
-    fn matches(&self, item: &ffi::CStr) -> bool {
-        *item == *self.urid_uri
+impl Amp {
+    fn port(&self, port: Port) -> *mut raw::c_void {
+        self.ports[port as usize]
     }
 
-    fn store(&mut self, data: *const raw::c_void) {
-        unsafe {
-            self.urid_map = Some(data as *const LV2_URID_Map);
+    // Value of a control port, kept within its declared range, or its default if the host has
+    // not connected it.
+    fn control(&self, port: Port) -> f32 {
+        let info = &SYNTH_PORTS[port as usize];
+        match self.port(port) as *const f32 {
+            p if p.is_null() => info.default,
+            p => unsafe { *p }.max(info.minimum).min(info.maximum),
+        }
+    }
+
+    fn controls(&self) -> Controls {
+        Controls {
+            waveform: self.control(Port::Waveform),
+            attack: self.control(Port::Attack),
+            decay: self.control(Port::Decay),
+            sustain: self.control(Port::Sustain),
+            release: self.control(Port::Release),
+            sec_waveform: self.control(Port::SecWaveform),
+            sec_freq_mul: self.control(Port::SecFreqMul),
+            sec_depth: self.control(Port::SecDepth),
+            filter_freq: self.control(Port::FilterFreq),
+            filter_on: self.control(Port::FilterOn),
+            pan: self.control(Port::Pan),
+            spread: self.control(Port::Spread),
+            glide: self.control(Port::Glide),
+            input_level: self.control(Port::InputLevel),
+            input_fm: self.control(Port::InputFm),
         }
     }
 }
 
-extern fn instantiate(descriptor: *const LV2_Descriptor,
-                      rate: f64,
-                      path: *const raw::c_char,
-                      features: *const *const LV2_Feature) -> LV2_Handle {
-    println!("SynthZ instantiate");
+extern "C" fn instantiate(descriptor: *const LV2_Descriptor,
+                          rate: f64,
+                          _path: *const raw::c_char,
+                          features: *const *const LV2_Feature) -> LV2_Handle {
+    guard(ptr::null_mut(), || {
+        let voicing = voicing_for(descriptor);
+        let mut urid_map = Feature::<LV2_URID_Map>::required(LV2_URID_map);
+        let mut log = Feature::<LV2_Log_Log>::optional(LV2_LOG_log);
+        let mut schedule = Feature::<LV2_Worker_Schedule>::optional(LV2_WORKER_schedule);
+        let mut options = Feature::<LV2_Options_Option>::optional(LV2_OPTIONS_options);
+        let extracted = extract_features(features, vec!(&mut urid_map, &mut log, &mut schedule, &mut options));
 
-    let mut urid_extractor = UridExtractor::new();
-    extract_features(features, vec!(&mut urid_extractor));
+        let logger = Arc::new(Logger::new(log.get(), urid_map.get()));
+        if let Err(e) = extracted {
+            logger.log(LogLevel::Error, format_args!("Cannot instantiate: {}", e));
+            return ptr::null_mut();
+        }
+        // Present, as extraction checks required features.
+        let urid_map = urid_map.get().unwrap();
 
-    let mut urid_map = urid_extractor.urid_map.unwrap();
+        let mut amp = Box::new(Amp {
+            info: plugin_info(descriptor),
+            ports: [ptr::null_mut(); SYNTH_PORTS.len()],
+            synth: synth::ToneIterator::new(rate as f32, DEFAULT_BLOCK_LENGTH, voicing, logger.clone()),
+            sampler_uris: map_sampler_uris(urid_map),
+            forge_uris: ForgeUris::new(urid_map),
+            peak: 0.0,
+            peak_frames: 0,
+            peak_period: (rate / PEAK_REPORTS_PER_SECOND) as u32,
+            reported_voices: 0,
+            controls: None,
+            cv_inputs: Vec::new(),
+            cv_outputs: Vec::new(),
+            sample_rate: rate as f32,
+            max_block_length: 0,
+            nominal_block_length: 0,
+            schedule: schedule.get().map(WorkerSchedule::new),
+            logger,
+            rack_text: Mutex::new(String::new()),
+        });
+        if amp.schedule.is_none() {
+            amp.synth.without_worker();
+        }
+        amp.rack_text = Mutex::new(amp.synth.patch_text());
 
-    let mut amp = Box::new(Amp {
-        input: std::ptr::null_mut(),
-        output: std::ptr::null_mut(),
-        waveform: std::ptr::null_mut(),
-        sec_waveform: std::ptr::null_mut(),
-        sec_freq_mul: std::ptr::null_mut(),
-        sec_depth: std::ptr::null_mut(),
-        attack: std::ptr::null_mut(),
-        decay: std::ptr::null_mut(),
-        sustain: std::ptr::null_mut(),
-        release: std::ptr::null_mut(),
-        filter_freq: std::ptr::null_mut(),
-        filter_on: std::ptr::null_mut(),
-        synth: synth::ToneIterator::new(rate as f32),
-        sampler_uris: map_sampler_uris(urid_map),
-    });
+        if let Some(options) = options.get() {
+            set_options(&mut amp, options);
+        }
 
-    println!("{:?}", amp.sampler_uris);
-    Box::into_raw(amp) as LV2_Handle
+        for &(port, module, connector, mix) in CV_INPUTS.iter() {
+            let input = amp.synth.patch_input(module, connector, mix);
+            amp.cv_inputs.push((port, input));
+        }
+        for &(port, module, connector) in CV_OUTPUTS.iter() {
+            let tap = amp.synth.tap(module, connector);
+            amp.cv_outputs.push((port, tap));
+        }
+
+        amp.logger.log(LogLevel::Trace, format_args!("Instantiated at {} Hz with {:?}", rate, amp.sampler_uris));
+        Box::into_raw(amp) as LV2_Handle
+    })
 }
 
-extern fn connect_port(instance: LV2_Handle, port: u32, data: *mut raw::c_void) {
-    let mut pamp = instance as *mut Amp;
+extern "C" fn connect_port(instance: LV2_Handle, port: u32, data: *mut raw::c_void) {
+    guard((), || {
+        let amp = unsafe { &mut *(instance as *mut Amp) };
+        // Variants leave out some ports, so their indices are matched to the table by symbol.
+        let index = amp.info.ports.get(port as usize)
+            .and_then(|info| SYNTH_PORTS.iter().position(|p| p.symbol == info.symbol));
+        match index {
+            Some(i) => amp.ports[i] = data,
+            None => amp.logger.log(LogLevel::Warning, format_args!("Connect to unknown port {}", port)),
+        }
+    })
+}
 
-    unsafe {
-        let amp = &mut *pamp;
-        match port {
-            CONTROL_INPUT => {
-                amp.input = data as *const LV2_Atom
-            },
-            SYNTH_OUTPUT => {
-                amp.output = data as *mut f32
-            },
-            WAVEFORM => {
-                amp.waveform = data as *mut f32
-            },
-            ATTACK => {
-                amp.attack = data as *mut f32
-            },
-            DECAY => {
-                amp.decay = data as *mut f32
-            },
-            SUSTAIN => {
-                amp.sustain = data as *mut f32
-            },
-            RELEASE => {
-                amp.release = data as *mut f32
-            },
-            SEC_WAVEFORM => {
-                amp.sec_waveform = data as *mut f32
-            },
-            SEC_FREQ_MUL => {
-                amp.sec_freq_mul = data as *mut f32
-            },
-            SEC_DEPTH => {
-                amp.sec_depth = data as *mut f32
-            }
-            FILTER_FREQ => {
-                amp.filter_freq = data as *mut f32
+extern "C" fn activate(instance: LV2_Handle) {
+    guard((), || {
+        let amp = unsafe { &*(instance as *const Amp) };
+        amp.logger.flush();
+    })
+}
+
+extern "C" fn deactivate(instance: LV2_Handle) {
+    guard((), || {
+        let amp = unsafe { &*(instance as *const Amp) };
+        amp.logger.flush();
+    })
+}
+
+// Converts event times to frames from the start of a block of samples. Beat times are counted
+// tempo segment by segment: at the old tempo up to a tempo change, and at the new one after it.
+// Times outside the block are clamped into it, so no event is held over to a later block.
+struct EventClock {
+    rate: f32,
+    samples: usize,
+    bpm: f32,
+    // Beat time and frame of the last tempo change, or of the block start.
+    beats: f64,
+    frames: f64,
+}
+
+impl EventClock {
+    fn new(bpm: f32, rate: f32, samples: usize) -> EventClock {
+        EventClock { rate, samples, bpm, beats: 0.0, frames: 0.0 }
+    }
+
+    fn unclamped(&self, time: EventTime) -> f64 {
+        match time {
+            EventTime::Frames(frames) => frames as f64,
+            EventTime::Beats(beats) if self.bpm > 0.0 => {
+                self.frames + (beats - self.beats) * 60.0 * f64::from(self.rate) / f64::from(self.bpm)
             }
-            FILTER_ON => {
-                amp.filter_on = data as *mut f32
+            EventTime::Beats(_) => self.frames,
+        }
+    }
+
+    fn frames(&self, time: EventTime) -> i64 {
+        let last = self.samples.saturating_sub(1) as i64;
+        (self.unclamped(time) as i64).clamp(0, last)
+    }
+
+    // Beats after time are at the new tempo.
+    fn set_bpm(&mut self, time: EventTime, bpm: f32) {
+        self.frames = self.unclamped(time);
+        if let EventTime::Beats(beats) = time {
+            self.beats = beats;
+        }
+        self.bpm = bpm;
+    }
+}
+
+// Queues the events in the sequence on the engine, straight from the atoms so nothing is
+// allocated.
+fn extract_sequence(seq: *const LV2_Atom_Sequence, s: &SamplerUris, samples: usize, synth: &mut synth::ToneIterator) {
+    let mut clock = EventClock::new(synth.transport().bpm, synth.rate(), samples);
+
+    let iter: AtomSequenceIter = AtomSequenceIter::new(seq, s.atom_beat_time);
+
+    for event in iter {
+        let time_frames = clock.frames(event.time);
+        if event.data_type == s.midi_event {
+            synth.add_event(synth::SynthEvent::new(time_frames,
+                                                   synth::SynthEventBody::MidiData(MidiEvent::new(event.data, event.size))));
+        } else if event.data_type == s.atom_object || event.data_type == s.atom_blank {
+            let mut patch = None;
+            extract_object(event.data as *const LV2_Atom_Object_Body, event.size, s, &mut patch, |p| {
+                if let synth::SynthProperty::Bpm(b) = p {
+                    clock.set_bpm(event.time, b);
+                }
+                synth.add_event(synth::SynthEvent::new(time_frames, synth::SynthEventBody::SynthProperty(p)));
+            });
+            if let Some(path) = patch {
+                synth.load_patch(path);
             }
-            _ => {println!("SynthZ Connect to unknown port")}
         }
     }
 }
 
-extern fn activate(instance: LV2_Handle) {
+// Gives set each property the object sets. A patch it asks to load is put in patch instead.
+fn extract_object<F>(obj: *const LV2_Atom_Object_Body,
+                     size: usize,
+                     uris: &SamplerUris,
+                     patch: &mut Option<synth::worker::PatchPath>,
+                     mut set: F) where F: FnMut(synth::SynthProperty) {
+    let iter = AtomObjectIter::new(obj, size);
+
+    let property = if iter.otype == uris.patch_set {
+        extract_patch_set(iter, uris, patch)
+    } else if iter.otype == uris.patch_get {
+        extract_patch_get(iter, uris)
+    } else {
+        return extract_position(iter, uris, set);
+    };
+    if let Some(p) = property {
+        set(p);
+    }
+}
+
+fn extract_position<F>(iter: AtomObjectIter, uris: &SamplerUris, mut set: F) where F: FnMut(synth::SynthProperty) {
+    for prop in iter {
+        // Hosts differ in which numeric types they use, so accept any of them.
+        let value = match extract_number(&prop, uris) {
+            Some(v) => v,
+            None => continue
+        };
+        if prop.key == uris.time_frame {
+            set(synth::SynthProperty::Frame(value as i64));
+        } else if prop.key == uris.time_speed {
+            set(synth::SynthProperty::Speed(value as f32));
+        } else if prop.key == uris.time_beats_per_minute {
+            set(synth::SynthProperty::Bpm(value as f32));
+        } else if prop.key == uris.time_bar {
+            set(synth::SynthProperty::Bar(value as i64));
+        } else if prop.key == uris.time_bar_beat {
+            set(synth::SynthProperty::BarBeat(value as f32));
+        } else if prop.key == uris.time_beats_per_bar {
+            set(synth::SynthProperty::BeatsPerBar(value as f32));
+        } else if prop.key == uris.time_beat_unit {
+            set(synth::SynthProperty::BeatUnit(value as i32));
+        }
+    }
+}
+
+fn extract_number(prop: &PropertyData, uris: &SamplerUris) -> Option<f64> {
+    unsafe {
+        if prop.data_type == uris.atom_float && prop.size == mem::size_of::<f32>() {
+            Some(f64::from(*(prop.data as *const f32)))
+        } else if prop.data_type == uris.atom_double && prop.size == mem::size_of::<f64>() {
+            Some(*(prop.data as *const f64))
+        } else if prop.data_type == uris.atom_int && prop.size == mem::size_of::<i32>() {
+            Some(f64::from(*(prop.data as *const i32)))
+        } else if prop.data_type == uris.atom_long && prop.size == mem::size_of::<i64>() {
+            Some(*(prop.data as *const i64) as f64)
+        } else {
+            None
+        }
+    }
+}
+
+fn param_for_urid(urid: LV2_URID, uris: &SamplerUris) -> Option<usize> {
+    uris.synthz_params.iter().position(|p| *p == urid)
 }
 
-extern fn deactivate(instance: LV2_Handle) {
+fn extract_urid(prop: &PropertyData, uris: &SamplerUris) -> Option<LV2_URID> {
+    if prop.data_type == uris.atom_urid && prop.size == mem::size_of::<LV2_URID>() {
+        Some(unsafe { *(prop.data as *const LV2_URID) })
+    } else {
+        None
+    }
 }
 
-fn extract_sequence(seq: *const LV2_Atom_Sequence, s: &SamplerUris) -> Vec<synth::SynthEvent> {
-    let mut ret = Vec::new();
+fn extract_param_value(prop: &PropertyData, uris: &SamplerUris) -> Option<params::ParamValue> {
+    extract_number(prop, uris).map(|v| params::ParamValue::Float(v as f32))
+}
 
-    let iter: AtomSequenceIter = AtomSequenceIter::new(seq);
+fn extract_path(prop: &PropertyData, uris: &SamplerUris) -> Option<synth::worker::PatchPath> {
+    if prop.data_type == uris.atom_path && prop.size > 0 {
+        let bytes = unsafe { std::slice::from_raw_parts(prop.data, prop.size) };
+        // The size includes the terminating null.
+        let path = bytes.split(|b| *b == 0).next().unwrap_or(&[]);
+        Some(synth::worker::PatchPath::new(path))
+    } else {
+        None
+    }
+}
 
-    for event in iter {
-        if event.data_type == s.midi_Event {
-            ret.push(synth::SynthEvent::new(event.time_frames,
-                                     synth::SynthEventBody::MidiData(MidiEvent::new(event.data, event.size))));
-        } else if event.data_type == s.atom_Object || event.data_type == s.atom_Blank {
-            let properties = synth::SynthEventBody::SynthProperties(
-                extract_object(event.data as *const LV2_Atom_Object_Body, event.size, s));
-            ret.push(synth::SynthEvent::new(event.time_frames, properties));
+fn extract_patch_set(iter: AtomObjectIter, uris: &SamplerUris, patch: &mut Option<synth::worker::PatchPath>)
+                     -> Option<synth::SynthProperty> {
+    let mut property = None;
+    let mut value = None;
+    let mut path = None;
+
+    for prop in iter {
+        if prop.key == uris.patch_property {
+            property = extract_urid(&prop, uris);
+        } else if prop.key == uris.patch_value {
+            value = extract_param_value(&prop, uris);
+            path = extract_path(&prop, uris);
         }
     }
 
-    ret
+    match (property, value, path) {
+        (Some(p), _, Some(path)) if p == uris.synthz_patch => {
+            *patch = Some(path);
+            None
+        }
+        (Some(p), Some(v), _) => param_for_urid(p, uris).map(|param| synth::SynthProperty::SetParameter(param, v)),
+        _ => None
+    }
 }
 
-fn extract_object(obj: *const LV2_Atom_Object_Body,
-                  size: usize,
-                  uris: &SamplerUris) -> Vec<synth::SynthProperty> {
-    unsafe {
-        let o_type = (*obj).otype;
-        let mut processed: usize = mem::size_of::<LV2_Atom_Object_Body>();
-        let mut items: Vec<synth::SynthProperty> = Vec::new();
-
-        while processed < size {
-            let pboffset = (obj as usize).checked_add(processed).unwrap();
-            let pbody: *const LV2_Atom_Property_Body = pboffset as *const LV2_Atom_Property_Body;
-            let body = &*pbody;
-
-            // TODO Get BPM, Bar (?), and BarBeat (?), BeatsPerBar (?)
-            if body.key == uris.time_frame {
-                assert_eq!(body.value.size as usize, mem::size_of::<i64>());
-                assert_eq!(body.value.atom_type, uris.atom_Long);
-                let value = pbody.offset(1) as *const i64;
-                items.push(synth::SynthProperty::Frame(*value));
-            } else if body.key == uris.time_speed {
-                assert_eq!(body.value.size as usize, mem::size_of::<f32>());
-                assert_eq!(body.value.atom_type, uris.atom_Float);
-                let value = pbody.offset(1) as *const f32;
-                items.push(synth::SynthProperty::Speed(*value));
+fn extract_patch_get(iter: AtomObjectIter, uris: &SamplerUris) -> Option<synth::SynthProperty> {
+    let mut property = None;
+
+    for prop in iter {
+        if prop.key == uris.patch_property {
+            property = Some(extract_urid(&prop, uris));
+        }
+    }
+
+    // A Get without a property asks for everything. One that is not a URID asks for nothing.
+    match property {
+        None => Some(synth::SynthProperty::GetParameter(None)),
+        Some(urid) => urid.and_then(|u| param_for_urid(u, uris)).map(|p| synth::SynthProperty::GetParameter(Some(p))),
+    }
+}
+
+extern "C" fn run(instance: LV2_Handle, n_samples: u32) {
+    guard((), || {
+        let pamp: *mut Amp = instance as *mut Amp;
+        unsafe {
+            let amp = &mut *pamp;
+            let pinput = amp.port(Port::Control) as *const LV2_Atom;
+
+            let input = &*pinput;
+
+            let controls = amp.controls();
+            let uris = &amp.sampler_uris;
+            let synth = &mut amp.synth;
+
+            controls.changes(amp.controls.as_ref(),
+                             |p| synth.add_event(synth::SynthEvent::new(0, synth::SynthEventBody::SynthProperty(p))));
+            amp.controls = Some(controls);
+
+            if input.atom_type == uris.atom_sequence {
+                extract_sequence(pinput as *const LV2_Atom_Sequence, uris, n_samples as usize, synth);
+            }
+
+            let samples = n_samples as usize;
+            let out_left = amp.ports[Port::OutputLeft as usize] as *mut f32;
+            let out_right = amp.ports[Port::OutputRight as usize] as *mut f32;
+            // A single connected input is used for both channels.
+            let input = match (amp.ports[Port::InputLeft as usize] as *const f32, amp.ports[Port::InputRight as usize] as *const f32) {
+                (l, r) if l.is_null() && r.is_null() => None,
+                (l, r) if r.is_null() => Some((l, l)),
+                (l, r) if l.is_null() => Some((r, r)),
+                (l, r) => Some((l, r)),
+            };
+
+            // Blocks longer than the engine was prepared for are rendered in pieces. Inputs may
+            // share buffers with the outputs, so each piece of input is copied before the
+            // output is written.
+            let max_block = synth.max_block();
+            let mut offset = 0;
+            while offset < samples {
+                let len = max_block.min(samples - offset);
+                if let Some((in_left, in_right)) = input {
+                    synth.feed_input(std::slice::from_raw_parts(in_left.add(offset), len),
+                                     std::slice::from_raw_parts(in_right.add(offset), len));
+                }
+                for &(port, input) in &amp.cv_inputs {
+                    let cv = amp.ports[port as usize] as *const f32;
+                    if !cv.is_null() {
+                        synth.feed_patch_input(input, std::slice::from_raw_parts(cv.add(offset), len));
+                    }
+                }
+                let (chunk_left, chunk_right) = synth.feed(len);
+                ptr::copy(chunk_left.as_ptr(), out_left.add(offset), len);
+                ptr::copy(chunk_right.as_ptr(), out_right.add(offset), len);
+                for &(port, tap) in &amp.cv_outputs {
+                    let cv = amp.ports[port as usize] as *mut f32;
+                    if !cv.is_null() {
+                        ptr::copy(synth.tapped(tap).as_ptr(), cv.add(offset), len);
+                    }
+                }
+                offset += len;
+            }
+            let left = std::slice::from_raw_parts(out_left as *const f32, samples);
+            let right = std::slice::from_raw_parts(out_right as *const f32, samples);
+
+            schedule_work(amp);
+
+            amp.peak = left.iter().chain(right.iter()).fold(amp.peak, |peak, v| peak.max(v.abs()));
+            amp.peak_frames += n_samples;
+
+            let notify = amp.port(Port::Notify) as *mut LV2_Atom_Sequence;
+            if !notify.is_null() {
+                let capacity = (*notify).atom.size as usize;
+                let buf = std::slice::from_raw_parts_mut(notify as *mut u8, capacity);
+                let mut forge = AtomForge::new(buf, amp.forge_uris);
+                if let Ok(seq) = forge.sequence_head(0) {
+                    write_notifications(&mut forge, amp);
+                    forge.pop(seq);
+                }
             }
-            processed = processed + pad_size(body.value.size) as usize + mem::size_of::<LV2_Atom_Property_Body>();
         }
-        items
+    })
+}
+
+// Hands jobs requested by the engine to the worker. Without a worker the engine asks for none,
+// and queued log messages wait for activate or deactivate.
+fn schedule_work(amp: &mut Amp) {
+    if let Some(ref schedule) = amp.schedule {
+        while let Some(job) = amp.synth.next_job() {
+            if let Err(WorkMessage::Job(job)) = schedule.schedule(WorkMessage::Job(job)) {
+                // Asked for again next block, rather than lost.
+                amp.synth.retry_job(job);
+                amp.logger.rt(LogLevel::Warning, format_args!("Worker queue full, job deferred"));
+                break;
+            }
+        }
+        if amp.logger.needs_flush() {
+            let _ = schedule.schedule(WorkMessage::FlushLog);
+        }
     }
 }
 
-extern fn run(instance: LV2_Handle, n_samples: u32) {
-    let pamp: *mut Amp = instance as *mut Amp;
-    unsafe {
-        let amp = &mut *pamp;
-        let pinput = amp.input;
+// Takes the text of a patch the engine has started playing, for save, from what apply returned.
+// The text it replaces goes with the response.
+fn keep_rack_text(rack_text: &Mutex<String>, response: &mut synth::worker::Response) {
+    if let synth::worker::Response::Patch(ref mut loaded) = *response {
+        if loaded.played {
+            if let Ok(mut text) = rack_text.lock() {
+                mem::swap(&mut *text, &mut loaded.text);
+            }
+        }
+    }
+}
 
-        let input = &*pinput;
+extern "C" fn work(instance: LV2_Handle,
+                   respond_fn: LV2_Worker_Respond_Function,
+                   handle: LV2_Worker_Respond_Handle,
+                   size: u32,
+                   data: *const raw::c_void) -> LV2_Worker_Status {
+    guard(LV2_WORKER_ERR_UNKNOWN, || {
+        // Runs alongside the audio thread, so only the logger and rack text may be touched here.
+        let amp = unsafe { &*(instance as *const Amp) };
+        let logger = &amp.logger;
 
-        let uris = &amp.sampler_uris;
+        match unsafe { read_message::<WorkMessage>(size, data) } {
+            Some(WorkMessage::Job(job)) => {
+                match job.run() {
+                    Ok(response) => {
+                        if respond(respond_fn, handle, response).is_err() {
+                            logger.log(LogLevel::Warning, format_args!("Worker response dropped"));
+                        }
+                    }
+                    Err(e) => logger.log(LogLevel::Error, format_args!("{}", e)),
+                }
+            }
+            Some(WorkMessage::Dispose(mut response)) => {
+                keep_rack_text(&amp.rack_text, &mut response);
+                drop(response);
+            }
+            Some(WorkMessage::FlushLog) => logger.flush(),
+            None => return LV2_WORKER_ERR_UNKNOWN,
+        }
+        LV2_WORKER_SUCCESS
+    })
+}
 
-        let synth = &mut amp.synth;
+extern "C" fn work_response(instance: LV2_Handle, size: u32, data: *const raw::c_void) -> LV2_Worker_Status {
+    guard(LV2_WORKER_ERR_UNKNOWN, || {
+        let amp = unsafe { &mut *(instance as *mut Amp) };
 
-        let waveform = *amp.waveform;
+        let response = match unsafe { read_message::<synth::worker::Response>(size, data) } {
+            Some(r) => r,
+            None => return LV2_WORKER_ERR_UNKNOWN,
+        };
+        if let (Some(old), Some(schedule)) = (amp.synth.apply(response), amp.schedule.as_ref()) {
+            if let Err(msg) = schedule.schedule(WorkMessage::Dispose(old)) {
+                // Leak rather than free memory in the audio thread.
+                mem::forget(msg);
+            }
+        }
+        LV2_WORKER_SUCCESS
+    })
+}
 
-        let filter_freq = *amp.filter_freq;
+extern "C" fn end_run(_instance: LV2_Handle) -> LV2_Worker_Status {
+    guard(LV2_WORKER_ERR_UNKNOWN, || LV2_WORKER_SUCCESS)
+}
 
-        let filter_on = *amp.filter_on > 0.5;
+static WORKER_INTERFACE: LV2_Worker_Interface = LV2_Worker_Interface {
+    work,
+    work_response,
+    end_run,
+};
 
-        let control = vec!(
-                synth::SynthProperty::Waveform(waveform),
-                synth::SynthProperty::Envelope(*amp.attack, *amp.decay, *amp.sustain, *amp.release),
-                synth::SynthProperty::Secondary(*amp.sec_waveform, *amp.sec_depth, *amp.sec_freq_mul),
-                synth::SynthProperty::FilterFreq(filter_freq),
-                synth::SynthProperty::FilterOn(filter_on)
-            );
-        let evs = vec!(synth::SynthEvent::new(0, synth::SynthEventBody::SynthProperties(control)));
-        synth.add_data(evs);
+// Writes a single event, discarding it if it does not fit.
+fn write_event<F>(forge: &mut AtomForge, write: F) -> ForgeResult
+    where F: FnOnce(&mut AtomForge) -> ForgeResult {
+    let mark = forge.mark();
+    let result = forge.frame_time(0).and_then(|_| write(forge));
+    if result.is_err() {
+        forge.rollback(mark);
+    }
+    result
+}
 
-        if input.atom_type == uris.atom_Sequence {
-            let midi_data = extract_sequence(pinput as *const LV2_Atom_Sequence, uris);
-            synth.add_data(midi_data);
+fn write_param(forge: &mut AtomForge, uris: &SamplerUris, param: usize, value: &params::ParamValue) -> ForgeResult {
+    let obj = forge.object(0, uris.patch_set)?;
+    forge.key(uris.patch_property)?;
+    forge.urid(uris.synthz_params[param])?;
+    forge.key(uris.patch_value)?;
+    match *value {
+        params::ParamValue::Float(f) => forge.float(f)?,
+    }
+    forge.pop(obj);
+    Ok(())
+}
+
+fn write_status(forge: &mut AtomForge, uris: &SamplerUris, voices: u32, peak: Option<f32>) -> ForgeResult {
+    let obj = forge.object(0, uris.synthz_status)?;
+    forge.key(uris.synthz_voices)?;
+    forge.int(voices as i32)?;
+    if let Some(p) = peak {
+        forge.key(uris.synthz_peak)?;
+        forge.float(p)?;
+    }
+    forge.pop(obj);
+    Ok(())
+}
+
+// Reports changed parameters, voice count and peak levels on the notify port. What does not fit
+// is reported in a later block.
+fn write_notifications(forge: &mut AtomForge, amp: &mut Amp) {
+    let uris = &amp.sampler_uris;
+
+    for param in 0..uris.synthz_params.len() {
+        if amp.synth.take_param_report(param) {
+            let value = amp.synth.param(param);
+            if write_event(forge, |f| write_param(f, uris, param, value)).is_err() {
+                amp.synth.retry_param_report(param);
+                return;
+            }
         }
+    }
 
-        let output: &mut [f32] = std::slice::from_raw_parts_mut(amp.output, n_samples as usize);
+    let voices = amp.synth.voices();
+    let peak = if amp.peak_frames >= amp.peak_period {
+        Some(amp.peak)
+    } else {
+        None
+    };
 
-        let out = synth.feed(n_samples as usize);
-        for i in 0..output.len() {
-            output[i as usize] = out[i as usize];
+    if (voices != amp.reported_voices || peak.is_some()) &&
+        write_event(forge, |f| write_status(f, uris, voices, peak)).is_ok() {
+        amp.reported_voices = voices;
+        if peak.is_some() {
+            amp.peak = 0.0;
+            amp.peak_frames = 0;
         }
     }
 }
 
-extern fn cleanup(instance: LV2_Handle) {
-    println!("SynthZ cleanup");
-    unsafe {
-        let amp: Box<Amp> = Box::from_raw(instance as *mut Amp);
-        drop(amp);
+extern "C" fn save(instance: LV2_Handle,
+                   store: LV2_State_Store_Function,
+                   handle: LV2_State_Handle,
+                   _flags: u32,
+                   _features: *const *const LV2_Feature) -> LV2_State_Status {
+    guard(LV2_STATE_ERR_UNKNOWN, || {
+        let amp = unsafe { &*(instance as *const Amp) };
+        amp.logger.flush();
+        let store = StateStore::new(store, handle);
+        let uris = &amp.sampler_uris;
+
+        for (i, key) in uris.synthz_params.iter().enumerate() {
+            let status = match *amp.synth.param(i) {
+                params::ParamValue::Float(f) => store.store_float(*key, uris.atom_float, f),
+            };
+            if status != LV2_STATE_SUCCESS {
+                return status;
+            }
+        }
+        // What the engine plays may come from a preset rather than the ports the host saves, so
+        // it is kept with the port values it was last given, which tell restore whether the ports
+        // have moved since.
+        let playing = amp.synth.controls().values();
+        let given = amp.controls.as_ref().map(|c| c.values());
+        for (i, &(_, value)) in playing.iter().enumerate() {
+            let status = store.store_float(uris.synthz_controls[i], uris.atom_float, value);
+            if status != LV2_STATE_SUCCESS {
+                return status;
+            }
+            if let Some(ref given) = given {
+                let status = store.store_float(uris.synthz_port_controls[i], uris.atom_float, given[i].1);
+                if status != LV2_STATE_SUCCESS {
+                    return status;
+                }
+            }
+        }
+        if let Some(preset) = amp.synth.preset() {
+            let status = store.store_int(uris.synthz_preset, uris.atom_int, i32::from(preset));
+            if status != LV2_STATE_SUCCESS {
+                return status;
+            }
+        }
+        let status = store.store_string(uris.synthz_voicing, amp.forge_uris.atom_string,
+                                        &amp.synth.voicing().to_string());
+        if status != LV2_STATE_SUCCESS {
+            return status;
+        }
+        // The rack as text, so the session keeps a loaded patch without its file.
+        match amp.rack_text.lock() {
+            Ok(text) => store.store_string(uris.synthz_rack, amp.forge_uris.atom_string, &text),
+            Err(_) => LV2_STATE_ERR_UNKNOWN,
+        }
+    })
+}
+
+extern "C" fn restore(instance: LV2_Handle,
+                      retrieve: LV2_State_Retrieve_Function,
+                      handle: LV2_State_Handle,
+                      _flags: u32,
+                      _features: *const *const LV2_Feature) -> LV2_State_Status {
+    guard(LV2_STATE_ERR_UNKNOWN, || {
+        let amp = unsafe { &mut *(instance as *mut Amp) };
+        let retrieve = StateRetrieve::new(retrieve, handle);
+        let uris = &amp.sampler_uris;
+
+        // Missing keys keep their current value, so older sessions still load.
+        if let Some(text) = retrieve.retrieve_string(uris.synthz_voicing, amp.forge_uris.atom_string) {
+            match synth::Voicing::parse(&text) {
+                Some(voicing) => if let Err(e) = amp.synth.set_voicing(voicing) {
+                    amp.logger.log(LogLevel::Warning, format_args!("Cannot restore voicing: {}", e));
+                },
+                None => amp.logger.log(LogLevel::Warning, format_args!("Cannot restore voicing {:?}", text)),
+            }
+        }
+        for (i, key) in uris.synthz_params.iter().enumerate() {
+            if let Some(f) = retrieve.retrieve_float(*key, uris.atom_float) {
+                amp.synth.set_param(i, params::ParamValue::Float(f));
+            }
+        }
+        let mut playing = *amp.synth.controls();
+        let mut given = amp.controls.unwrap_or(playing);
+        let (mut playing_saved, mut given_saved) = (false, false);
+        for (i, &(symbol, _)) in playing.values().iter().enumerate() {
+            if let Some(f) = retrieve.retrieve_float(uris.synthz_controls[i], uris.atom_float) {
+                playing.set(symbol, f);
+                playing_saved = true;
+            }
+            if let Some(f) = retrieve.retrieve_float(uris.synthz_port_controls[i], uris.atom_float) {
+                given.set(symbol, f);
+                given_saved = true;
+            }
+        }
+        if playing_saved {
+            let preset = retrieve.retrieve_int(uris.synthz_preset, uris.atom_int)
+                .filter(|p| (0..128).contains(p))
+                .map(|p| p as u8);
+            amp.synth.restore_controls(&playing, preset);
+        }
+        // The ports then only change what they are moved away from.
+        if given_saved {
+            amp.controls = Some(given);
+        }
+        if let Some(text) = retrieve.retrieve_string(uris.synthz_rack, amp.forge_uris.atom_string) {
+            match synth::patch::Patch::parse(&text) {
+                Ok(patch) => match amp.synth.set_patch(patch) {
+                    Ok(()) => amp.rack_text = Mutex::new(amp.synth.patch_text()),
+                    Err(e) => amp.logger.log(LogLevel::Warning, format_args!("Cannot restore patch: {}", e)),
+                },
+                Err(e) => amp.logger.log(LogLevel::Warning, format_args!("Cannot restore patch: {}", e)),
+            }
+        }
+        LV2_STATE_SUCCESS
+    })
+}
+
+static STATE_INTERFACE: LV2_State_Interface = LV2_State_Interface {
+    save,
+    restore,
+};
+
+// Applies instance options, returning the combined status. Not realtime safe.
+fn set_options(amp: &mut Amp, options: *const LV2_Options_Option) -> LV2_Options_Status {
+    let mut status = LV2_OPTIONS_SUCCESS;
+    for option in OptionsIter::new(options) {
+        let option = unsafe { &*option };
+        status |= set_option(amp, option);
     }
+
+    // Prefer the bound, as it is what run can be given.
+    let block = match (amp.max_block_length, amp.nominal_block_length) {
+        (max, _) if max > 0 => max as usize,
+        (_, nominal) if nominal > 0 => nominal as usize,
+        _ => DEFAULT_BLOCK_LENGTH,
+    };
+    amp.synth.set_max_block(block);
+    amp.synth.set_rate(amp.sample_rate);
+    amp.peak_period = (f64::from(amp.sample_rate) / PEAK_REPORTS_PER_SECOND) as u32;
+    status
 }
 
-extern fn extension_data(uri: *const raw::c_char) -> *mut raw::c_void {
-    println!("SynthZ extension_data");
-    return std::ptr::null_mut();
+fn set_option(amp: &mut Amp, option: &LV2_Options_Option) -> LV2_Options_Status {
+    if option.context != LV2_OPTIONS_INSTANCE {
+        return LV2_OPTIONS_ERR_BAD_SUBJECT;
+    }
+    let uris = &amp.sampler_uris;
+    let value = match option_number(option, &amp.forge_uris) {
+        Some(v) => v,
+        None => return LV2_OPTIONS_ERR_BAD_VALUE,
+    };
+
+    if option.key == uris.param_sample_rate && value > 0.0 {
+        amp.sample_rate = value as f32;
+    } else if option.key == uris.bufsz_max_block_length && value >= 1.0 {
+        amp.max_block_length = value as i32;
+    } else if option.key == uris.bufsz_nominal_block_length && value >= 1.0 {
+        amp.nominal_block_length = value as i32;
+    } else if option.key == uris.param_sample_rate
+            || option.key == uris.bufsz_max_block_length
+            || option.key == uris.bufsz_nominal_block_length {
+        return LV2_OPTIONS_ERR_BAD_VALUE;
+    } else {
+        return LV2_OPTIONS_ERR_BAD_KEY;
+    }
+    LV2_OPTIONS_SUCCESS
+}
+
+extern "C" fn options_get(instance: LV2_Handle, options: *mut LV2_Options_Option) -> LV2_Options_Status {
+    guard(LV2_OPTIONS_ERR_UNKNOWN, || {
+        let amp = unsafe { &*(instance as *const Amp) };
+        let uris = &amp.sampler_uris;
+        let mut status = LV2_OPTIONS_SUCCESS;
+
+        for option in OptionsIter::new(options) {
+            let option = unsafe { &mut *(option as *mut LV2_Options_Option) };
+            let (value_type, size, value) = if option.context != LV2_OPTIONS_INSTANCE {
+                status |= LV2_OPTIONS_ERR_BAD_SUBJECT;
+                continue;
+            } else if option.key == uris.param_sample_rate {
+                (uris.atom_float, mem::size_of::<f32>(), &amp.sample_rate as *const f32 as *const raw::c_void)
+            } else if option.key == uris.bufsz_max_block_length && amp.max_block_length > 0 {
+                (uris.atom_int, mem::size_of::<i32>(), &amp.max_block_length as *const i32 as *const raw::c_void)
+            } else if option.key == uris.bufsz_nominal_block_length && amp.nominal_block_length > 0 {
+                (uris.atom_int, mem::size_of::<i32>(), &amp.nominal_block_length as *const i32 as *const raw::c_void)
+            } else {
+                status |= LV2_OPTIONS_ERR_BAD_KEY;
+                continue;
+            };
+            option.type_ = value_type;
+            option.size = size as u32;
+            option.value = value;
+        }
+        status
+    })
+}
+
+// Hosts call this outside run, so the engine may reallocate.
+extern "C" fn options_set(instance: LV2_Handle, options: *const LV2_Options_Option) -> LV2_Options_Status {
+    guard(LV2_OPTIONS_ERR_UNKNOWN, || {
+        let amp = unsafe { &mut *(instance as *mut Amp) };
+        set_options(amp, options)
+    })
+}
+
+static OPTIONS_INTERFACE: LV2_Options_Interface = LV2_Options_Interface {
+    get: options_get,
+    set: options_set,
+};
+
+extern "C" fn cleanup(instance: LV2_Handle) {
+    guard((), || {
+        unsafe {
+            let amp: Box<Amp> = Box::from_raw(instance as *mut Amp);
+            amp.logger.flush();
+            drop(amp);
+        }
+    })
+}
+
+extern "C" fn extension_data(uri: *const raw::c_char) -> *mut raw::c_void {
+    guard(ptr::null_mut(), || {
+        let uri = unsafe { ffi::CStr::from_ptr(uri) };
+        let state_uri = unsafe { ffi::CStr::from_ptr(LV2_STATE_interface as *const raw::c_char) };
+        let worker_uri = unsafe { ffi::CStr::from_ptr(LV2_WORKER_interface as *const raw::c_char) };
+        let options_uri = unsafe { ffi::CStr::from_ptr(LV2_OPTIONS_interface as *const raw::c_char) };
+        if uri == state_uri {
+            &STATE_INTERFACE as *const LV2_State_Interface as *mut raw::c_void
+        } else if uri == worker_uri {
+            &WORKER_INTERFACE as *const LV2_Worker_Interface as *mut raw::c_void
+        } else if uri == options_uri {
+            &OPTIONS_INTERFACE as *const LV2_Options_Interface as *mut raw::c_void
+        } else {
+            std::ptr::null_mut()
+        }
+    })
 }
 
 #[no_mangle]
-pub extern fn lv2_descriptor(index: u32) -> *const LV2_Descriptor {
-    println!("SynthZ lv2_descriptor");
-    match index {
-        0 => return &LV2DESCRIPTOR,
-        _ => return std::ptr::null_mut()
-    }
+pub extern "C" fn lv2_descriptor(index: u32) -> *const LV2_Descriptor {
+    guard(ptr::null(), || {
+        match index {
+            0 => &LV2DESCRIPTOR,
+            1 => &MONO_DESCRIPTOR,
+            2 => &POLY_DESCRIPTOR,
+            3 => &filter_plugin::DESCRIPTOR,
+            _ => ptr::null()
+        }
+    })
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use testing::{allocations, option, Host, State, NO_MORE_OPTIONS};
+
+    fn amp<'a>(handle: LV2_Handle) -> &'a mut Amp {
+        assert!(!handle.is_null());
+        unsafe { &mut *(handle as *mut Amp) }
+    }
+
+    #[test]
+    fn instantiating_needs_a_urid_map() {
+        let none = [ptr::null()];
+        assert!(instantiate(&LV2DESCRIPTOR, 48000.0, ptr::null(), none.as_ptr()).is_null());
+        assert!(instantiate(&LV2DESCRIPTOR, 48000.0, ptr::null(), ptr::null()).is_null());
+
+        // Other features do not make up for it.
+        let options = [NO_MORE_OPTIONS];
+        let feature = LV2_Feature {
+            URI: LV2_OPTIONS_options as *const raw::c_char,
+            data: options.as_ptr() as *mut raw::c_void,
+        };
+        let features = [&feature as *const LV2_Feature, ptr::null()];
+        assert!(instantiate(&LV2DESCRIPTOR, 48000.0, ptr::null(), features.as_ptr()).is_null());
+
+        let host = Host::new();
+        for descriptor in &[LV2DESCRIPTOR, MONO_DESCRIPTOR, POLY_DESCRIPTOR] {
+            let handle = host.instantiate(descriptor, &[NO_MORE_OPTIONS]);
+            assert!(!handle.is_null());
+            cleanup(handle);
+        }
+    }
+
+    #[test]
+    fn block_length_options_size_the_engine() {
+        let host = Host::new();
+        let int = host.urid_for_const(LV2_ATOM_Int);
+        let max_key = host.urid_for_const(LV2_BUF_SIZE_maxBlockLength);
+        let nominal_key = host.urid_for_const(LV2_BUF_SIZE_nominalBlockLength);
+        let (max, nominal) = (1024i32, 512i32);
+        let max_option = || option(max_key, int, 4, &max as *const i32 as *const raw::c_void);
+        let nominal_option = || option(nominal_key, int, 4, &nominal as *const i32 as *const raw::c_void);
+
+        // The bound is preferred, as run may be given that much.
+        let handle = host.instantiate(&LV2DESCRIPTOR, &[nominal_option(), max_option(), NO_MORE_OPTIONS]);
+        assert_eq!(amp(handle).synth.max_block(), 1024);
+        let mut got = [option(max_key, 0, 0, ptr::null()), option(nominal_key, 0, 0, ptr::null()), NO_MORE_OPTIONS];
+        assert_eq!(options_get(handle, got.as_mut_ptr()), LV2_OPTIONS_SUCCESS);
+        for (option, value) in got.iter().zip(&[max, nominal]) {
+            assert_eq!((option.type_, option.size), (int, 4));
+            assert_eq!(unsafe { *(option.value as *const i32) }, *value);
+        }
+        cleanup(handle);
+
+        let handle = host.instantiate(&LV2DESCRIPTOR, &[nominal_option(), NO_MORE_OPTIONS]);
+        assert_eq!(amp(handle).synth.max_block(), 512);
+        // Lengths the host never gave are not made up.
+        let mut got = [option(max_key, 0, 0, ptr::null()), NO_MORE_OPTIONS];
+        assert_eq!(options_get(handle, got.as_mut_ptr()), LV2_OPTIONS_ERR_BAD_KEY);
+        assert!(got[0].value.is_null());
+
+        // Set later, as by options_set.
+        assert_eq!(options_set(handle, [max_option(), NO_MORE_OPTIONS].as_ptr()), LV2_OPTIONS_SUCCESS);
+        assert_eq!(amp(handle).synth.max_block(), 1024);
+        cleanup(handle);
+
+        let handle = host.instantiate(&LV2DESCRIPTOR, &[NO_MORE_OPTIONS]);
+        assert_eq!(amp(handle).synth.max_block(), DEFAULT_BLOCK_LENGTH);
+        cleanup(handle);
+    }
+
+    #[test]
+    fn bad_options_are_refused() {
+        let host = Host::new();
+        let handle = host.instantiate(&LV2DESCRIPTOR, &[NO_MORE_OPTIONS]);
+        let int = host.urid_for_const(LV2_ATOM_Int);
+        let max_key = host.urid_for_const(LV2_BUF_SIZE_maxBlockLength);
+        let rate_key = host.urid_for_const(LV2_PARAMETERS_sampleRate);
+        let unknown = host.urid("http://example.org/unknown");
+        let (zero, length, text) = (0i32, 256i32, b"256\0");
+        let length_ptr = &length as *const i32 as *const raw::c_void;
+
+        let set = |options: &[LV2_Options_Option]| options_set(handle, options.as_ptr());
+        assert_eq!(set(&[option(unknown, int, 4, length_ptr), NO_MORE_OPTIONS]), LV2_OPTIONS_ERR_BAD_KEY);
+        // Wrongly typed, sized or ranged values.
+        let string = host.urid_for_const(LV2_ATOM_String);
+        assert_eq!(set(&[option(max_key, string, 4, text.as_ptr() as *const raw::c_void), NO_MORE_OPTIONS]),
+                   LV2_OPTIONS_ERR_BAD_VALUE);
+        assert_eq!(set(&[option(max_key, int, 2, length_ptr), NO_MORE_OPTIONS]), LV2_OPTIONS_ERR_BAD_VALUE);
+        assert_eq!(set(&[option(max_key, int, 4, &zero as *const i32 as *const raw::c_void), NO_MORE_OPTIONS]),
+                   LV2_OPTIONS_ERR_BAD_VALUE);
+        assert_eq!(set(&[option(rate_key, int, 4, ptr::null()), NO_MORE_OPTIONS]), LV2_OPTIONS_ERR_BAD_VALUE);
+        assert_eq!(amp(handle).synth.max_block(), DEFAULT_BLOCK_LENGTH);
+        assert_eq!(amp(handle).sample_rate, 48000.0);
+
+        // Options about something other than the instance.
+        let port = LV2_Options_Option { context: LV2_OPTIONS_PORT, ..option(max_key, int, 4, length_ptr) };
+        assert_eq!(set(&[port, NO_MORE_OPTIONS]), LV2_OPTIONS_ERR_BAD_SUBJECT);
+
+        // Good options still apply alongside bad ones, and the errors are combined.
+        assert_eq!(set(&[option(unknown, int, 4, length_ptr), option(max_key, int, 4, length_ptr),
+                         option(max_key, int, 2, length_ptr), NO_MORE_OPTIONS]),
+                   LV2_OPTIONS_ERR_BAD_KEY | LV2_OPTIONS_ERR_BAD_VALUE);
+        assert_eq!(amp(handle).synth.max_block(), 256);
+
+        let mut got = [option(unknown, 0, 0, ptr::null()), option(rate_key, 0, 0, ptr::null()), NO_MORE_OPTIONS];
+        assert_eq!(options_get(handle, got.as_mut_ptr()), LV2_OPTIONS_ERR_BAD_KEY);
+        assert!(got[0].value.is_null());
+        assert_eq!(unsafe { *(got[1].value as *const f32) }, 48000.0);
+        cleanup(handle);
+    }
+
+    // Words of an atom buffer, as the forge writes bytes.
+    fn bytes(words: &mut [u64]) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, words.len() * 8) }
+    }
+
+    // Reads back an object the forge wrote into words.
+    fn object_in(words: &mut [u64]) -> AtomObjectIter {
+        let atom = words.as_ptr() as *const LV2_Atom;
+        AtomObjectIter::new(unsafe { atom.add(1) } as *const LV2_Atom_Object_Body, unsafe { (*atom).size } as usize)
+    }
+
+    #[test]
+    fn patch_sets_need_a_known_property_and_a_value_of_its_type() {
+        let host = Host::new();
+        let handle = host.instantiate(&LV2DESCRIPTOR, &[NO_MORE_OPTIONS]);
+        let (uris, forge_uris) = (&amp(handle).sampler_uris, amp(handle).forge_uris);
+        let unknown = host.urid("http://example.org/unknown");
+        let set = |write: &dyn Fn(&mut AtomForge), patch: &mut Option<synth::worker::PatchPath>| {
+            let mut words = [0u64; 16];
+            {
+                let mut forge = AtomForge::new(bytes(&mut words), forge_uris);
+                let obj = forge.object(0, uris.patch_set).unwrap();
+                write(&mut forge);
+                forge.pop(obj);
+            }
+            extract_patch_set(object_in(&mut words), uris, patch)
+        };
+        let mut patch = None;
+
+        let expected = Some(synth::SynthProperty::SetParameter(1, params::ParamValue::Float(0.25)));
+        assert_eq!(set(&|f| {
+            f.key(uris.patch_property).unwrap();
+            f.urid(uris.synthz_params[1]).unwrap();
+            f.key(uris.patch_value).unwrap();
+            f.float(0.25).unwrap();
+        }, &mut patch), expected);
+        // In either order, with any numeric type, and alongside keys that mean nothing here.
+        assert_eq!(set(&|f| {
+            f.key(unknown).unwrap();
+            f.int(7).unwrap();
+            f.key(uris.patch_value).unwrap();
+            f.double(0.25).unwrap();
+            f.key(uris.patch_property).unwrap();
+            f.urid(uris.synthz_params[1]).unwrap();
+        }, &mut patch), expected);
+
+        // Properties that are not parameters, or not URIDs, and values that are not numbers.
+        assert_eq!(set(&|f| {
+            f.key(uris.patch_property).unwrap();
+            f.urid(unknown).unwrap();
+            f.key(uris.patch_value).unwrap();
+            f.float(0.25).unwrap();
+        }, &mut patch), None);
+        assert_eq!(set(&|f| {
+            f.key(uris.patch_property).unwrap();
+            f.int(uris.synthz_params[1] as i32).unwrap();
+            f.key(uris.patch_value).unwrap();
+            f.float(0.25).unwrap();
+        }, &mut patch), None);
+        assert_eq!(set(&|f| {
+            f.key(uris.patch_property).unwrap();
+            f.urid(uris.synthz_params[1]).unwrap();
+            f.key(uris.patch_value).unwrap();
+            f.string("0.25").unwrap();
+        }, &mut patch), None);
+        assert_eq!(set(&|f| {
+            f.key(uris.patch_property).unwrap();
+            f.urid(uris.synthz_params[1]).unwrap();
+        }, &mut patch), None);
+        assert!(patch.is_none());
+
+        // A patch is taken only as a path, and sets no parameter.
+        assert_eq!(set(&|f| {
+            f.key(uris.patch_property).unwrap();
+            f.urid(uris.synthz_patch).unwrap();
+            f.key(uris.patch_value).unwrap();
+            f.string("/tmp/patch.txt").unwrap();
+        }, &mut patch), None);
+        assert!(patch.is_none());
+        assert_eq!(set(&|f| {
+            f.key(uris.patch_property).unwrap();
+            f.urid(uris.synthz_patch).unwrap();
+            f.key(uris.patch_value).unwrap();
+            f.path("/tmp/patch.txt").unwrap();
+        }, &mut patch), None);
+        assert_eq!(patch.unwrap().get(), Some(&b"/tmp/patch.txt"[..]));
+        cleanup(handle);
+    }
+
+    #[test]
+    fn patch_gets_ask_for_one_parameter_or_all() {
+        let host = Host::new();
+        let handle = host.instantiate(&LV2DESCRIPTOR, &[NO_MORE_OPTIONS]);
+        let (uris, forge_uris) = (&amp(handle).sampler_uris, amp(handle).forge_uris);
+        let unknown = host.urid("http://example.org/unknown");
+        let get = |write: &dyn Fn(&mut AtomForge)| {
+            let mut words = [0u64; 8];
+            {
+                let mut forge = AtomForge::new(bytes(&mut words), forge_uris);
+                let obj = forge.object(0, uris.patch_get).unwrap();
+                write(&mut forge);
+                forge.pop(obj);
+            }
+            extract_patch_get(object_in(&mut words), uris)
+        };
+
+        assert_eq!(get(&|_| ()), Some(synth::SynthProperty::GetParameter(None)));
+        assert_eq!(get(&|f| {
+            f.key(unknown).unwrap();
+            f.float(1.0).unwrap();
+        }), Some(synth::SynthProperty::GetParameter(None)));
+        assert_eq!(get(&|f| {
+            f.key(uris.patch_property).unwrap();
+            f.urid(uris.synthz_params[1]).unwrap();
+        }), Some(synth::SynthProperty::GetParameter(Some(1))));
+
+        // Properties that are not parameters, or not URIDs, are not taken as asking for everything.
+        assert_eq!(get(&|f| {
+            f.key(uris.patch_property).unwrap();
+            f.urid(unknown).unwrap();
+        }), None);
+        assert_eq!(get(&|f| {
+            f.key(uris.patch_property).unwrap();
+            f.int(uris.synthz_params[1] as i32).unwrap();
+        }), None);
+        cleanup(handle);
+    }
+
+    // Writes the notifications into a sequence in words, and reads back each event's object type
+    // and properties. URID values are read as numbers.
+    fn notifications(amp: &mut Amp, words: &mut [u64]) -> Vec<(LV2_URID, Vec<(LV2_URID, f64)>)> {
+        {
+            let mut forge = AtomForge::new(bytes(words), amp.forge_uris);
+            let seq = forge.sequence_head(0).unwrap();
+            write_notifications(&mut forge, amp);
+            forge.pop(seq);
+        }
+        let uris = &amp.sampler_uris;
+        AtomSequenceIter::new(words.as_ptr() as *const LV2_Atom_Sequence, uris.atom_beat_time).map(|event| {
+            assert_eq!(event.data_type, uris.atom_object);
+            let iter = AtomObjectIter::new(event.data as *const LV2_Atom_Object_Body, event.size);
+            let otype = iter.otype;
+            let props = iter.map(|p| (p.key, extract_number(&p, uris).or_else(|| extract_urid(&p, uris).map(f64::from))
+                                                  .unwrap()))
+                            .collect();
+            (otype, props)
+        }).collect()
+    }
+
+    #[test]
+    fn notifications_report_parameters_and_status_once() {
+        let host = Host::new();
+        let handle = host.instantiate(&LV2DESCRIPTOR, &[NO_MORE_OPTIONS]);
+        let amp = amp(handle);
+        let (patch_set, status) = (amp.sampler_uris.patch_set, amp.sampler_uris.synthz_status);
+        let (property, value) = (amp.sampler_uris.patch_property, amp.sampler_uris.patch_value);
+        let (voices, peak) = (amp.sampler_uris.synthz_voices, amp.sampler_uris.synthz_peak);
+        let param = f64::from(amp.sampler_uris.synthz_params[1]);
+
+        amp.synth.set_param(1, params::ParamValue::Float(0.5));
+        amp.peak = 0.25;
+        amp.peak_frames = amp.peak_period;
+        let mut words = [0u64; 32];
+        assert_eq!(notifications(amp, &mut words), vec![
+            (patch_set, vec![(property, param), (value, 0.5)]),
+            (status, vec![(voices, 0.0), (peak, 0.25)]),
+        ]);
+        assert_eq!((amp.peak, amp.peak_frames), (0.0, 0));
+
+        // Nothing has changed since, and the peak period has not come round again.
+        amp.peak_frames = amp.peak_period - 1;
+        assert_eq!(notifications(amp, &mut words), vec![]);
+        cleanup(handle);
+    }
+
+    #[test]
+    fn notifications_that_do_not_fit_wait() {
+        let host = Host::new();
+        let handle = host.instantiate(&LV2DESCRIPTOR, &[NO_MORE_OPTIONS]);
+        let amp = amp(handle);
+        let (patch_set, status) = (amp.sampler_uris.patch_set, amp.sampler_uris.synthz_status);
+        amp.synth.set_param(1, params::ParamValue::Float(0.5));
+        amp.peak = 0.25;
+        amp.peak_frames = amp.peak_period;
+
+        // Room for the sequence header alone. The parameter is started and rolled back.
+        let mut words = [u64::MAX; 7];
+        assert_eq!(notifications(amp, &mut words), vec![]);
+        assert_eq!(unsafe { (*(words.as_ptr() as *const LV2_Atom)).size }, 8);
+
+        // Room for the parameter but not the status, which is rolled back and kept for later.
+        let mut words = [u64::MAX; 14];
+        let written: Vec<_> = notifications(amp, &mut words).into_iter().map(|(otype, _)| otype).collect();
+        assert_eq!(written, vec![patch_set]);
+        assert_eq!(unsafe { (*(words.as_ptr() as *const LV2_Atom)).size }, 8 + 72);
+        assert_eq!(amp.peak, 0.25);
+
+        let mut words = [0u64; 32];
+        let written: Vec<_> = notifications(amp, &mut words).into_iter().map(|(otype, _)| otype).collect();
+        assert_eq!(written, vec![status]);
+        cleanup(handle);
+    }
+
+    #[test]
+    fn state_keeps_the_voicing_and_a_preset_over_the_ports() {
+        const BLOCK: usize = 64;
+        let host = Host::new();
+        let handle = host.instantiate(&LV2DESCRIPTOR, &[NO_MORE_OPTIONS]);
+        let midi = host.urid_for_const(LV2_MIDI_MidiEvent);
+        let mut buffers: Vec<[f32; BLOCK]> = SYNTH_PORTS.iter().map(|p| [p.default; BLOCK]).collect();
+        for (port, buffer) in buffers.iter_mut().enumerate() {
+            connect_port(handle, port as u32, buffer.as_mut_ptr() as *mut raw::c_void);
+        }
+        let mut control = [0u64; 8];
+        connect_port(handle, Port::Control as u32, control.as_mut_ptr() as *mut raw::c_void);
+        connect_port(handle, Port::Notify as u32, ptr::null_mut());
+
+        // The pad preset by program change, then the pan port moved away from it.
+        {
+            let mut forge = AtomForge::new(bytes(&mut control), amp(handle).forge_uris);
+            let seq = forge.sequence_head(0).unwrap();
+            forge.frame_time(0).unwrap();
+            forge.bytes(midi, &[LV2_MIDI_MSG_PGM_CHANGE, 1]).unwrap();
+            forge.pop(seq);
+        }
+        run(handle, BLOCK as u32);
+        control[0] = 8;
+        buffers[Port::Pan as usize][0] = -0.5;
+        run(handle, BLOCK as u32);
+        // Pan and spread move together, so the spread is the port's too.
+        let mut expected = presets::PRESETS[1].controls;
+        expected.pan = -0.5;
+        expected.spread = 0.0;
+        assert_eq!(*amp(handle).synth.controls(), expected);
+        amp(handle).synth.set_voicing(synth::Voicing::Poly(3)).unwrap();
+
+        let mut state = State::default();
+        assert_eq!(state.save(handle, &STATE_INTERFACE), LV2_STATE_SUCCESS);
+        cleanup(handle);
+
+        // Restored into a new instance, whose ports the host sets as they were saved.
+        let handle = host.instantiate(&LV2DESCRIPTOR, &[NO_MORE_OPTIONS]);
+        for (port, buffer) in buffers.iter_mut().enumerate() {
+            connect_port(handle, port as u32, buffer.as_mut_ptr() as *mut raw::c_void);
+        }
+        control[0] = 8;
+        connect_port(handle, Port::Control as u32, control.as_mut_ptr() as *mut raw::c_void);
+        connect_port(handle, Port::Notify as u32, ptr::null_mut());
+        assert_eq!(state.restore(handle, &STATE_INTERFACE), LV2_STATE_SUCCESS);
+        run(handle, BLOCK as u32);
+        assert_eq!(amp(handle).synth.voicing(), synth::Voicing::Poly(3));
+        assert_eq!(amp(handle).synth.preset(), Some(1));
+        assert_eq!(*amp(handle).synth.controls(), expected);
+
+        // The ports still move what they are moved away from.
+        buffers[Port::Attack as usize][0] = 0.5;
+        run(handle, BLOCK as u32);
+        assert_eq!(amp(handle).synth.controls().attack, 0.5);
+        assert_eq!(amp(handle).synth.controls().waveform, presets::PRESETS[1].controls.waveform);
+
+        // Sessions saved before these keys keep what the instance had.
+        let handle2 = host.instantiate(&LV2DESCRIPTOR, &[NO_MORE_OPTIONS]);
+        assert_eq!(State::default().restore(handle2, &STATE_INTERFACE), LV2_STATE_SUCCESS);
+        assert_eq!(amp(handle2).synth.voicing(), synth::Voicing::Single);
+        assert_eq!(amp(handle2).synth.preset(), None);
+        assert_eq!(*amp(handle2).synth.controls(), presets::PRESETS[0].controls);
+        cleanup(handle2);
+        cleanup(handle);
+    }
+
+    #[test]
+    fn running_does_not_allocate() {
+        let host = Host::new();
+        run_without_allocating(&host, host.instantiate_with_worker(&POLY_DESCRIPTOR, &[NO_MORE_OPTIONS]), true);
+        // Filters are then designed on the audio thread, and patch loads refused.
+        run_without_allocating(&host, host.instantiate(&POLY_DESCRIPTOR, &[NO_MORE_OPTIONS]), false);
+    }
+
+    // Runs blocks full of events and control moves, checking run allocates nothing.
+    fn run_without_allocating(host: &Host, handle: LV2_Handle, worker: bool) {
+        const BLOCK: usize = 256;
+        let forge_uris = amp(handle).forge_uris;
+        let param = amp(handle).sampler_uris.synthz_params[0];
+        let patch = amp(handle).sampler_uris.synthz_patch;
+        let midi = host.urid_for_const(LV2_MIDI_MidiEvent);
+        let position = host.urid_for_const(LV2_TIME_Position);
+        let (bpm, speed, bar) = (host.urid_for_const(LV2_TIME_beatsPerMinute),
+                                 host.urid_for_const(LV2_TIME_speed), host.urid_for_const(LV2_TIME_bar));
+        let (get, set) = (host.urid_for_const(LV2_PATCH_Get), host.urid_for_const(LV2_PATCH_Set));
+        let (property, value) = (host.urid_for_const(LV2_PATCH_property), host.urid_for_const(LV2_PATCH_value));
+
+        // Every port but the atom ones gets a buffer, with control ports at their defaults.
+        let mut buffers: Vec<[f32; BLOCK]> = SYNTH_PORTS.iter().map(|p| [p.default; BLOCK]).collect();
+        for (port, buffer) in buffers.iter_mut().enumerate() {
+            connect_port(handle, port as u32, buffer.as_mut_ptr() as *mut raw::c_void);
+        }
+        let mut control = [0u64; 64];
+        let mut notify = [0u64; 64];
+        connect_port(handle, Port::Control as u32, control.as_mut_ptr() as *mut raw::c_void);
+        connect_port(handle, Port::Notify as u32, notify.as_mut_ptr() as *mut raw::c_void);
+        activate(handle);
+
+        for block in 0..8u8 {
+            {
+                let mut forge = AtomForge::new(bytes(&mut control), forge_uris);
+                let seq = forge.sequence_head(0).unwrap();
+                forge.frame_time(0).unwrap();
+                forge.bytes(midi, &[LV2_MIDI_MSG_NOTE_ON, 60 + block, 100]).unwrap();
+                forge.frame_time(10).unwrap();
+                forge.bytes(midi, &[LV2_MIDI_MSG_PGM_CHANGE, block % 2]).unwrap();
+                forge.frame_time(20).unwrap();
+                let obj = forge.object(0, position).unwrap();
+                forge.key(bpm).unwrap();
+                forge.float(120.0 + f32::from(block)).unwrap();
+                forge.key(speed).unwrap();
+                forge.float(1.0).unwrap();
+                forge.key(bar).unwrap();
+                forge.long(i64::from(block)).unwrap();
+                forge.pop(obj);
+                forge.frame_time(30).unwrap();
+                let obj = forge.object(0, set).unwrap();
+                forge.key(property).unwrap();
+                forge.urid(param).unwrap();
+                forge.key(value).unwrap();
+                forge.float(0.5).unwrap();
+                forge.pop(obj);
+                forge.frame_time(35).unwrap();
+                let obj = forge.object(0, set).unwrap();
+                forge.key(property).unwrap();
+                forge.urid(patch).unwrap();
+                forge.key(value).unwrap();
+                forge.path("/nonexistent/patch.txt").unwrap();
+                forge.pop(obj);
+                forge.frame_time(40).unwrap();
+                let obj = forge.object(0, get).unwrap();
+                forge.pop(obj);
+                forge.frame_time(200).unwrap();
+                forge.bytes(midi, &[LV2_MIDI_MSG_NOTE_OFF, 60 + block, 0]).unwrap();
+                forge.pop(seq);
+            }
+            // The host says how much room the notify port has.
+            notify[0] = mem::size_of_val(&notify) as u64;
+            // Moving a control sends its change, and the filter cutoff asks for a design.
+            buffers[Port::FilterFreq as usize][0] = 0.2 + f32::from(block) * 0.05;
+            buffers[Port::Attack as usize][0] = 0.1 * f32::from(block % 3);
+
+            assert_eq!(allocations(|| run(handle, BLOCK as u32)), 0, "block {}", block);
+            // The Get was answered, and the filter design handed to the worker.
+            assert!(unsafe { (*(notify.as_ptr() as *const LV2_Atom)).size } > 8);
+            if worker {
+                assert!(host.scheduled() > 0);
+                host.work(handle, &WORKER_INTERFACE);
+            }
+        }
+        deactivate(handle);
+        cleanup(handle);
+    }
+
+    // Run with UPDATE_TTL set to rewrite the shipped files after changing the tables.
+    #[test]
+    fn shipped_ttl_matches() {
+        let resources = Path::new(env!("CARGO_MANIFEST_DIR")).join("resources");
+        let mut files: Vec<(&str, String)> = PLUGINS.iter().map(|p| (p.ttl, p.to_ttl())).collect();
+        files.extend(PLUGINS.iter().filter(|p| !p.presets.is_empty()).map(|p| (p.presets_ttl, p.presets_to_ttl())));
+        files.push(("manifest.ttl", ports::manifest_ttl(&PLUGINS, "libsynthz.so")));
+
+        for (name, generated) in files {
+            let path = resources.join(name);
+            if env::var_os("UPDATE_TTL").is_some() {
+                fs::write(&path, &generated).unwrap();
+            }
+            let shipped = fs::read_to_string(&path).unwrap();
+            assert!(shipped == generated, "resources/{} is out of date, run the tests with UPDATE_TTL=1", name);
+        }
+    }
+
+    #[test]
+    fn beat_times_follow_tempo_changes() {
+        let mut clock = EventClock::new(120.0, 48000.0, 64000);
+        assert_eq!(clock.frames(EventTime::Beats(0.5)), 12000);
+        clock.set_bpm(EventTime::Beats(1.0), 60.0);
+        assert_eq!(clock.frames(EventTime::Beats(1.5)), 48000);
+        // Times past either end of the block are clamped into it.
+        assert_eq!(clock.frames(EventTime::Beats(3.0)), 63999);
+        assert_eq!(clock.frames(EventTime::Frames(-5)), 0);
+    }
+
+    #[test]
+    fn descriptors_match_plugins() {
+        for (i, plugin) in PLUGINS.iter().enumerate() {
+            let descriptor = unsafe { &*lv2_descriptor(i as u32) };
+            let uri = unsafe { ffi::CStr::from_ptr(descriptor.URI) };
+            assert_eq!(uri.to_str().unwrap(), plugin.uri());
+        }
+        assert!(lv2_descriptor(PLUGINS.len() as u32).is_null());
+    }
+
+    #[test]
+    fn port_indices_match_table() {
+        let ports = [
+            (Port::Control, "control"), (Port::OutputLeft, "out"), (Port::Waveform, "waveform"),
+            (Port::Attack, "attack"), (Port::Decay, "decay"), (Port::Sustain, "sustain"),
+            (Port::Release, "release"), (Port::SecWaveform, "sec_waveform"),
+            (Port::SecFreqMul, "sec_freq_mul"), (Port::SecDepth, "sec_depth"),
+            (Port::FilterFreq, "filter_freq"), (Port::FilterOn, "filter_on"), (Port::Notify, "notify"),
+            (Port::OutputRight, "out_r"), (Port::Pan, "pan"), (Port::Spread, "spread"), (Port::Glide, "glide"),
+            (Port::InputLeft, "in_l"), (Port::InputRight, "in_r"), (Port::InputLevel, "input_level"),
+            (Port::InputFm, "input_fm"), (Port::CvFm, "cv_fm"), (Port::CvGate, "cv_gate"),
+            (Port::CvPan, "cv_pan"), (Port::CvEnvelope, "cv_env"), (Port::CvOscillator, "cv_osc"),
+        ];
+        assert_eq!(ports.len(), SYNTH_PORTS.len());
+        for &(port, symbol) in ports.iter() {
+            assert_eq!(SYNTH_PORTS[port as usize].symbol, symbol);
+        }
+    }
+}
@@ -1,27 +1,37 @@
 
 use std::mem;
-use std::collections::BTreeMap;
 use lv2_raw::atom::*;
 use lv2_raw::urid::LV2_URID as LV2_URID;
+use lv2_raw::urid::LV2_URID_Map;
+use lv2::urid::urid_for_const;
 
 pub struct AtomSequenceIter {
-    pub seq: *const LV2_Atom_Sequence,
     pub next: *const LV2_Atom_Event,
     pub total: usize,
+    pub beats: bool,
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+pub enum EventTime {
+    Frames(i64),
+    Beats(f64),
 }
 
 pub struct SequenceData {
     pub data_type: LV2_URID,
-    pub time_frames: i64, // LV2_Atom_Event_Time,
+    pub time: EventTime,
     pub data: *const u8,
     pub size: usize
 }
 
 impl AtomSequenceIter {
-    pub fn new(seq: *const LV2_Atom_Sequence) -> AtomSequenceIter {
+    // beat_time is the mapped atom:beatTime URID. Sequences with any other unit are in frames.
+    pub fn new(seq: *const LV2_Atom_Sequence, beat_time: LV2_URID) -> AtomSequenceIter {
         unsafe {
             AtomSequenceIter {
-                seq: seq,
+                beats: (*seq).body.unit == beat_time,
                 next: seq.offset(1) as *const LV2_Atom_Event,
                 total: (seq as usize)
                 .checked_add((*seq).atom.size as usize)
@@ -31,12 +41,6 @@ impl AtomSequenceIter {
             }
         }
     }
-
-    pub fn get_time_unit_urid(&self) -> LV2_URID {
-        unsafe {
-            (*self.seq).body.unit as LV2_URID
-        }
-    }
 }
 
 pub fn pad_size(size: u32) -> usize {
@@ -53,33 +57,460 @@ impl Iterator for AtomSequenceIter {
             None
         } else {
             unsafe {
-                let seqData = SequenceData {
+                let time = if self.beats {
+                    EventTime::Beats((*self.next).time.beats)
+                } else {
+                    EventTime::Frames((*self.next).time.frames)
+                };
+                let seq_data = SequenceData {
                     data_type: (*self.next).body.atom_type,
-                    time_frames: (*self.next).time_frames,
+                    time,
                     data: self.next.offset(1) as *const u8,
                     size: (*self.next).body.size as usize,
                 };
                 let next_offset: usize = mem::size_of::<LV2_Atom_Event>() + pad_size((*self.next).body.size);
                 self.next = ((self.next as usize).checked_add(next_offset as usize).unwrap()) as *const LV2_Atom_Event;
-                Some(seqData)
+                Some(seq_data)
             }
         }
     }
 }
 
-pub struct AtomObject {
+pub struct AtomObjectIter {
     pub otype: LV2_URID,
-    pub items: Vec<AtomProperty>,
+    pub next: usize,
+    pub total: usize,
 }
 
-pub enum AtomItem {
-    AtomFloat(f32),
-    AtomLong(i64),
+pub struct PropertyData {
+    pub key: LV2_URID,
+    pub data_type: LV2_URID,
+    pub data: *const u8,
+    pub size: usize
 }
 
-pub struct AtomProperty {
-    pub key: LV2_URID,
-    pub context: u32,
-    pub item: AtomItem,
+impl AtomObjectIter {
+    // size is the size of the object atom, including the object body header.
+    pub fn new(obj: *const LV2_Atom_Object_Body, size: usize) -> AtomObjectIter {
+        unsafe {
+            AtomObjectIter {
+                otype: (*obj).otype,
+                next: (obj as usize).checked_add(mem::size_of::<LV2_Atom_Object_Body>()).unwrap(),
+                total: (obj as usize).checked_add(size).unwrap(),
+            }
+        }
+    }
+}
+
+impl Iterator for AtomObjectIter {
+    type Item = PropertyData;
+
+    fn next(&mut self) -> Option<PropertyData> {
+        let header = mem::size_of::<LV2_Atom_Property_Body>();
+        if self.next + header > self.total {
+            return None;
+        }
+        unsafe {
+            let pbody = self.next as *const LV2_Atom_Property_Body;
+            let size = (*pbody).value.size as usize;
+            if self.next + header + size > self.total {
+                return None;
+            }
+            let prop = PropertyData {
+                key: (*pbody).key,
+                data_type: (*pbody).value.atom_type,
+                data: pbody.offset(1) as *const u8,
+                size,
+            };
+            self.next += header + pad_size((*pbody).value.size);
+            Some(prop)
+        }
+    }
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+pub struct ForgeUris {
+    pub atom_bool: LV2_URID,
+    pub atom_double: LV2_URID,
+    pub atom_float: LV2_URID,
+    pub atom_int: LV2_URID,
+    pub atom_long: LV2_URID,
+    pub atom_object: LV2_URID,
+    pub atom_path: LV2_URID,
+    pub atom_sequence: LV2_URID,
+    pub atom_string: LV2_URID,
+    pub atom_urid: LV2_URID,
+}
+
+impl ForgeUris {
+    pub fn new(map: *const LV2_URID_Map) -> ForgeUris {
+        ForgeUris {
+            atom_bool: urid_for_const(map, LV2_ATOM_Bool),
+            atom_double: urid_for_const(map, LV2_ATOM_Double),
+            atom_float: urid_for_const(map, LV2_ATOM_Float),
+            atom_int: urid_for_const(map, LV2_ATOM_Int),
+            atom_long: urid_for_const(map, LV2_ATOM_Long),
+            atom_object: urid_for_const(map, LV2_ATOM_Object),
+            atom_path: urid_for_const(map, LV2_ATOM_Path),
+            atom_sequence: urid_for_const(map, LV2_ATOM_Sequence),
+            atom_string: urid_for_const(map, LV2_ATOM_String),
+            atom_urid: urid_for_const(map, LV2_ATOM_URID),
+        }
+    }
+}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum ForgeError {
+    // The buffer has no room for the write.
+    Overflow,
+    // Too many containers are open at once.
+    TooDeep,
+}
+
+pub type ForgeResult = Result<(), ForgeError>;
+
+const FORGE_MAX_DEPTH: usize = 8;
+
+// An open container. Must be passed back to AtomForge::pop once its contents are written.
+#[must_use]
+pub struct ForgeFrame {
+    depth: usize,
+}
+
+// A position in the buffer that a failed write can be rolled back to. Frames open when the mark
+// was taken must still be open when rolling back.
+#[derive(Clone)]
+#[derive(Copy)]
+pub struct ForgeMark {
+    offset: usize,
+    depth: usize,
+}
+
+// Writes atoms into a host-provided buffer. Containers (sequences and objects) are pushed as
+// frames, and every write grows the size of all open frames. Each atom is bounds checked as a
+// whole before anything is written, so a failed write leaves the buffer as it was. Use mark and
+// rollback to discard a partly written container.
+pub struct AtomForge<'a> {
+    buf: &'a mut [u8],
+    offset: usize,
+    frames: [usize; FORGE_MAX_DEPTH],
+    depth: usize,
+    uris: ForgeUris,
+}
+
+impl <'a> AtomForge<'a> {
+    pub fn new(buf: &'a mut [u8], uris: ForgeUris) -> AtomForge<'a> {
+        AtomForge {
+            buf,
+            offset: 0,
+            frames: [0; FORGE_MAX_DEPTH],
+            depth: 0,
+            uris,
+        }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.offset
+    }
+
+    pub fn mark(&self) -> ForgeMark {
+        ForgeMark { offset: self.offset, depth: self.depth }
+    }
+
+    // Discards everything written since the mark, closing any frames opened after it.
+    pub fn rollback(&mut self, mark: ForgeMark) {
+        let written = (self.offset - mark.offset) as u32;
+        for i in 0..mark.depth {
+            let size = self.read_u32(self.frames[i]);
+            self.write_u32(self.frames[i], size - written);
+        }
+        self.offset = mark.offset;
+        self.depth = mark.depth;
+    }
+
+    fn read_u32(&self, at: usize) -> u32 {
+        let mut b = [0u8; 4];
+        b.copy_from_slice(&self.buf[at..at + 4]);
+        u32::from_ne_bytes(b)
+    }
+
+    fn write_u32(&mut self, at: usize, v: u32) {
+        self.buf[at..at + 4].copy_from_slice(&v.to_ne_bytes());
+    }
+
+    fn reserve(&self, size: usize) -> ForgeResult {
+        if size > self.remaining() {
+            Err(ForgeError::Overflow)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Appends bytes without checking for space, and grows every open frame.
+    fn raw(&mut self, data: &[u8]) {
+        self.buf[self.offset..self.offset + data.len()].copy_from_slice(data);
+        self.offset += data.len();
+        for i in 0..self.depth {
+            let size = self.read_u32(self.frames[i]);
+            self.write_u32(self.frames[i], size + data.len() as u32);
+        }
+    }
+
+    fn pad(&mut self, size: usize) {
+        let zeros = [0u8; 8];
+        let padding = pad_size(size as u32) - size;
+        self.raw(&zeros[..padding]);
+    }
+
+    fn atom_header(&mut self, size: u32, atom_type: LV2_URID) {
+        self.raw(&size.to_ne_bytes());
+        self.raw(&atom_type.to_ne_bytes());
+    }
+
+    // Writes a complete atom with the given body, padded out to 64 bits.
+    fn atom(&mut self, atom_type: LV2_URID, body: &[&[u8]]) -> ForgeResult {
+        let size: usize = body.iter().map(|b| b.len()).sum();
+        self.reserve(mem::size_of::<LV2_Atom>() + pad_size(size as u32))?;
+        self.atom_header(size as u32, atom_type);
+        for b in body {
+            self.raw(b);
+        }
+        self.pad(size);
+        Ok(())
+    }
+
+    // Opens a container atom. The header size starts at zero and grows as the body is written.
+    fn push(&mut self, atom_type: LV2_URID, body: &[u8]) -> Result<ForgeFrame, ForgeError> {
+        if self.depth == FORGE_MAX_DEPTH {
+            return Err(ForgeError::TooDeep);
+        }
+        self.reserve(mem::size_of::<LV2_Atom>() + body.len())?;
+        let start = self.offset;
+        self.atom_header(0, atom_type);
+        self.frames[self.depth] = start;
+        self.depth += 1;
+        self.raw(body);
+        Ok(ForgeFrame { depth: self.depth })
+    }
+
+    pub fn pop(&mut self, frame: ForgeFrame) {
+        assert_eq!(frame.depth, self.depth);
+        self.depth -= 1;
+    }
+
+    pub fn sequence_head(&mut self, unit: LV2_URID) -> Result<ForgeFrame, ForgeError> {
+        let mut body = [0u8; 8];
+        body[0..4].copy_from_slice(&unit.to_ne_bytes());
+        let seq_type = self.uris.atom_sequence;
+        self.push(seq_type, &body)
+    }
+
+    pub fn object(&mut self, id: LV2_URID, otype: LV2_URID) -> Result<ForgeFrame, ForgeError> {
+        let mut body = [0u8; 8];
+        body[0..4].copy_from_slice(&id.to_ne_bytes());
+        body[4..8].copy_from_slice(&otype.to_ne_bytes());
+        let object_type = self.uris.atom_object;
+        self.push(object_type, &body)
+    }
+
+    // Event header in a frame time sequence. Must be followed by exactly one atom.
+    pub fn frame_time(&mut self, frames: i64) -> ForgeResult {
+        self.reserve(mem::size_of::<i64>())?;
+        self.raw(&frames.to_ne_bytes());
+        Ok(())
+    }
+
+    // Property header in an object. Must be followed by exactly one atom.
+    pub fn key(&mut self, key: LV2_URID) -> ForgeResult {
+        self.reserve(2 * mem::size_of::<u32>())?;
+        self.raw(&key.to_ne_bytes());
+        self.raw(&0u32.to_ne_bytes());
+        Ok(())
+    }
+
+    pub fn int(&mut self, value: i32) -> ForgeResult {
+        let int_type = self.uris.atom_int;
+        self.atom(int_type, &[&value.to_ne_bytes()])
+    }
+
+    pub fn float(&mut self, value: f32) -> ForgeResult {
+        let float_type = self.uris.atom_float;
+        self.atom(float_type, &[&value.to_ne_bytes()])
+    }
+
+    pub fn urid(&mut self, value: LV2_URID) -> ForgeResult {
+        let urid_type = self.uris.atom_urid;
+        self.atom(urid_type, &[&value.to_ne_bytes()])
+    }
 }
 
+// Writers for the rest of the atom types, which nothing sends yet.
+#[cfg_attr(not(test), allow(dead_code))]
+impl <'a> AtomForge<'a> {
+    // Event header in a beat time sequence. Must be followed by exactly one atom.
+    pub fn beat_time(&mut self, beats: f64) -> ForgeResult {
+        self.reserve(mem::size_of::<f64>())?;
+        self.raw(&beats.to_ne_bytes());
+        Ok(())
+    }
+
+    pub fn bool(&mut self, value: bool) -> ForgeResult {
+        let bool_type = self.uris.atom_bool;
+        self.atom(bool_type, &[&(value as i32).to_ne_bytes()])
+    }
+
+    pub fn long(&mut self, value: i64) -> ForgeResult {
+        let long_type = self.uris.atom_long;
+        self.atom(long_type, &[&value.to_ne_bytes()])
+    }
+
+    pub fn double(&mut self, value: f64) -> ForgeResult {
+        let double_type = self.uris.atom_double;
+        self.atom(double_type, &[&value.to_ne_bytes()])
+    }
+
+    // Strings and paths are written with a terminating null, which is counted in the atom size.
+    pub fn string(&mut self, value: &str) -> ForgeResult {
+        let string_type = self.uris.atom_string;
+        self.atom(string_type, &[value.as_bytes(), &[0]])
+    }
+
+    pub fn path(&mut self, value: &str) -> ForgeResult {
+        let path_type = self.uris.atom_path;
+        self.atom(path_type, &[value.as_bytes(), &[0]])
+    }
+
+    // An atom of a type the forge has no writer for, such as a MIDI message.
+    pub fn bytes(&mut self, atom_type: LV2_URID, body: &[u8]) -> ForgeResult {
+        self.atom(atom_type, &[body])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URIS: ForgeUris = ForgeUris {
+        atom_bool: 1,
+        atom_double: 2,
+        atom_float: 3,
+        atom_int: 4,
+        atom_long: 5,
+        atom_object: 6,
+        atom_path: 7,
+        atom_sequence: 8,
+        atom_string: 9,
+        atom_urid: 10,
+    };
+    const BEAT_TIME: LV2_URID = 11;
+
+    // Words of a buffer, which atoms are read from and written to as bytes.
+    fn bytes(words: &mut [u64]) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, words.len() * 8) }
+    }
+
+    fn u32_at(buf: &[u8], at: usize) -> u32 {
+        let mut b = [0u8; 4];
+        b.copy_from_slice(&buf[at..at + 4]);
+        u32::from_ne_bytes(b)
+    }
+
+    #[test]
+    fn writes_past_the_end_change_nothing() {
+        let mut words = [u64::MAX; 3];
+        let buf = bytes(&mut words);
+        let mut forge = AtomForge::new(buf, URIS);
+        assert_eq!(forge.long(7), Ok(()));
+        assert_eq!(forge.remaining(), 8);
+        assert_eq!(forge.int(1), Err(ForgeError::Overflow));
+        assert_eq!(forge.string("a longer string"), Err(ForgeError::Overflow));
+        assert_eq!(forge.key(2), Ok(()));
+        assert_eq!(forge.remaining(), 0);
+        assert_eq!(forge.frame_time(0), Err(ForgeError::Overflow));
+        assert_eq!(forge.beat_time(0.0), Err(ForgeError::Overflow));
+        assert_eq!(forge.object(0, 1).err(), Some(ForgeError::Overflow));
+    }
+
+    #[test]
+    fn atoms_are_padded_to_eight_bytes() {
+        let mut words = [u64::MAX; 8];
+        let buf = bytes(&mut words);
+        {
+            let mut forge = AtomForge::new(buf, URIS);
+            forge.float(0.5).unwrap();
+            assert_eq!(forge.remaining(), 48);
+            forge.path("/a/b").unwrap();
+            assert_eq!(forge.remaining(), 32);
+            forge.bool(true).unwrap();
+            forge.double(0.25).unwrap();
+        }
+        // Float: size 4, then four bytes of padding.
+        assert_eq!((u32_at(buf, 0), u32_at(buf, 4)), (4, URIS.atom_float));
+        assert_eq!(&buf[8..12], &0.5f32.to_ne_bytes());
+        assert_eq!(&buf[12..16], &[0; 4]);
+        // Path: the null is counted in the size, and padding follows it.
+        assert_eq!((u32_at(buf, 16), u32_at(buf, 20)), (5, URIS.atom_path));
+        assert_eq!(&buf[24..32], b"/a/b\0\0\0\0");
+        assert_eq!((u32_at(buf, 32), u32_at(buf, 36)), (4, URIS.atom_bool));
+        assert_eq!((u32_at(buf, 48), u32_at(buf, 52)), (8, URIS.atom_double));
+        assert_eq!(&buf[56..64], &0.25f64.to_ne_bytes());
+    }
+
+    #[test]
+    fn rollback_discards_a_partly_written_event() {
+        let mut words = [0u64; 10];
+        let buf = bytes(&mut words);
+        let mut forge = AtomForge::new(buf, URIS);
+        let seq = forge.sequence_head(0).unwrap();
+        forge.frame_time(0).unwrap();
+        forge.urid(3).unwrap();
+        let mark = forge.mark();
+        let remaining = forge.remaining();
+
+        forge.frame_time(1).unwrap();
+        // Rolling back closes the object, so it is not popped.
+        let _object = forge.object(0, 2).unwrap();
+        forge.key(4).unwrap();
+        assert_eq!(forge.long(1), Err(ForgeError::Overflow));
+        forge.rollback(mark);
+        assert_eq!(forge.remaining(), remaining);
+        forge.pop(seq);
+        assert_eq!(u32_at(forge.buf, 0), 8 + 8 + 16);
+    }
+
+    #[test]
+    fn frames_hold_their_contents_once_popped() {
+        let mut words = [0u64; 16];
+        let buf = bytes(&mut words);
+        {
+            let mut forge = AtomForge::new(buf, URIS);
+            let seq = forge.sequence_head(0).unwrap();
+            forge.frame_time(5).unwrap();
+            let obj = forge.object(0, 2).unwrap();
+            forge.key(21).unwrap();
+            forge.int(-3).unwrap();
+            forge.key(22).unwrap();
+            forge.string("hi").unwrap();
+            forge.pop(obj);
+            forge.pop(seq);
+        }
+        // Object body, then two properties of a key and a 16 byte atom.
+        let object = 8 + 2 * (8 + 16);
+        assert_eq!(u32_at(buf, 24), object);
+        // Sequence body, then one event of a time and the object atom.
+        assert_eq!(u32_at(buf, 0), 8 + 8 + 8 + object);
+
+        let seq = buf.as_ptr() as *const LV2_Atom_Sequence;
+        let events: Vec<SequenceData> = AtomSequenceIter::new(seq, BEAT_TIME).collect();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(events[0].time, EventTime::Frames(5)));
+        assert_eq!((events[0].data_type, events[0].size), (URIS.atom_object, object as usize));
+        let props: Vec<PropertyData> = AtomObjectIter::new(events[0].data as *const LV2_Atom_Object_Body, events[0].size)
+            .collect();
+        assert_eq!(props.iter().map(|p| (p.key, p.data_type, p.size)).collect::<Vec<_>>(),
+                   vec![(21, URIS.atom_int, 4), (22, URIS.atom_string, 3)]);
+    }
+}
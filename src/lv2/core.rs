@@ -1,6 +1,8 @@
-
 use std::ffi;
+use std::fmt;
+use std::marker::PhantomData;
 use std::os::raw;
+use std::panic;
 use lv2_raw::core;
 
 pub struct LV2_Feature_Iter {
@@ -19,13 +21,16 @@ impl Iterator for LV2_Feature_Iter {
     type Item = *const core::LV2_Feature;
 
     fn next(&mut self) -> Option<*const core::LV2_Feature> {
+        if self.next.is_null() {
+            return None;
+        }
         unsafe {
-            let retVal = *self.next;
-            if retVal as usize == 0 {
+            let ret_val = *self.next;
+            if ret_val.is_null() {
                 None
             } else {
                 self.next = self.next.offset(1);
-                Some(retVal)
+                Some(ret_val)
             }
         }
     }
@@ -33,11 +38,83 @@ impl Iterator for LV2_Feature_Iter {
 }
 
 pub trait FeatureExtractor {
-    fn matches(&self, item: &ffi::CStr) -> bool;
+    fn uri(&self) -> &ffi::CStr;
+    fn required(&self) -> bool;
+    fn stored(&self) -> bool;
     fn store(&mut self, data: *const raw::c_void);
+
+    fn matches(&self, item: &ffi::CStr) -> bool {
+        item == self.uri()
+    }
+}
+
+// A host feature whose data is a pointer to T.
+pub struct Feature<T> {
+    uri: &'static ffi::CStr,
+    required: bool,
+    data: Option<*const T>,
+    phantom: PhantomData<T>,
+}
+
+impl <T> Feature<T> {
+    fn new(uri: *const u8, required: bool) -> Feature<T> {
+        Feature {
+            uri: unsafe { ffi::CStr::from_ptr(uri as *const raw::c_char) },
+            required,
+            data: None,
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn required(uri: *const u8) -> Feature<T> {
+        Feature::new(uri, true)
+    }
+
+    pub fn optional(uri: *const u8) -> Feature<T> {
+        Feature::new(uri, false)
+    }
+
+    pub fn get(&self) -> Option<*const T> {
+        self.data
+    }
 }
 
-pub fn extract_features(features: *const *const core::LV2_Feature, mut extractors: Vec<&mut FeatureExtractor>) {
+impl <T> FeatureExtractor for Feature<T> {
+    fn uri(&self) -> &ffi::CStr {
+        self.uri
+    }
+
+    fn required(&self) -> bool {
+        self.required
+    }
+
+    fn stored(&self) -> bool {
+        self.data.is_some()
+    }
+
+    fn store(&mut self, data: *const raw::c_void) {
+        if !data.is_null() {
+            self.data = Some(data as *const T);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FeatureError {
+    // A required feature was not provided by the host.
+    Missing(ffi::CString),
+}
+
+impl fmt::Display for FeatureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FeatureError::Missing(ref uri) => write!(f, "missing required feature {}", uri.to_string_lossy()),
+        }
+    }
+}
+
+pub fn extract_features(features: *const *const core::LV2_Feature,
+                        mut extractors: Vec<&mut dyn FeatureExtractor>) -> Result<(), FeatureError> {
 
     let iter = LV2_Feature_Iter::new(features);
 
@@ -51,5 +128,14 @@ pub fn extract_features(features: *const *const core::LV2_Feature, mut extractor
             }
         }
     }
+
+    match extractors.iter().find(|e| e.required() && !e.stored()) {
+        Some(e) => Err(FeatureError::Missing(e.uri().to_owned())),
+        None => Ok(())
+    }
 }
 
+// Runs an FFI callback, returning the default instead of unwinding into the host if it panics.
+pub fn guard<R, F: FnOnce() -> R>(default: R, f: F) -> R {
+    panic::catch_unwind(panic::AssertUnwindSafe(f)).unwrap_or(default)
+}
@@ -0,0 +1,247 @@
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::fmt::Write;
+use std::os::raw;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use lv2_raw::log::*;
+use lv2_raw::urid::{LV2_URID, LV2_URID_Map};
+use lv2::urid::urid_for_const;
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(PartialOrd)]
+pub enum LogLevel {
+    Trace = 0,
+    Note = 1,
+    Warning = 2,
+    Error = 3,
+}
+
+// Longest message kept from the realtime thread, including the terminating null.
+const MESSAGE_SIZE: usize = 256;
+// Messages held between flushes. Any more are dropped.
+const RING_SIZE: usize = 64;
+
+struct LogMessage {
+    level: LogLevel,
+    len: usize,
+    text: [u8; MESSAGE_SIZE],
+}
+
+impl LogMessage {
+    fn new() -> LogMessage {
+        LogMessage { level: LogLevel::Trace, len: 0, text: [0; MESSAGE_SIZE] }
+    }
+}
+
+// Formats into the message buffer, truncating rather than allocating.
+impl fmt::Write for LogMessage {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // Leave room for the null, and cut only between characters.
+        let mut n = s.len().min(MESSAGE_SIZE - 1 - self.len);
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+        self.text[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        // Stop at the first cut, so nothing later is written after a gap.
+        if n < s.len() { Err(fmt::Error) } else { Ok(()) }
+    }
+}
+
+// Single producer, single consumer queue of preallocated messages. The realtime thread pushes
+// and whichever non-realtime thread flushes the log pops.
+struct LogRing {
+    messages: Vec<UnsafeCell<LogMessage>>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+unsafe impl Sync for LogRing {}
+
+impl LogRing {
+    fn new() -> LogRing {
+        LogRing {
+            messages: (0..RING_SIZE).map(|_| UnsafeCell::new(LogMessage::new())).collect(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    fn push(&self, level: LogLevel, args: fmt::Arguments) {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) == RING_SIZE {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        // The slot between head and tail is owned by the producer until tail is published.
+        let message = unsafe { &mut *self.messages[tail % RING_SIZE].get() };
+        message.level = level;
+        message.len = 0;
+        let _ = message.write_fmt(args);
+        message.text[message.len] = 0;
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Relaxed) == self.tail.load(Ordering::Acquire)
+    }
+
+    fn pop<F>(&self, f: F) -> bool where F: FnOnce(&LogMessage) {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head == tail {
+            return false;
+        }
+        f(unsafe { &*self.messages[head % RING_SIZE].get() });
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        true
+    }
+}
+
+// Routes diagnostics to the host log, or to stderr if the host has no log feature. Messages from
+// the audio thread go through rt, which only queues them; flush writes them out and must be
+// called from a non-realtime context.
+pub struct Logger {
+    log: Option<*const LV2_Log_Log>,
+    level: LogLevel,
+    uris: [LV2_URID; 4],
+    ring: LogRing,
+    flush_requested: AtomicBool,
+}
+
+unsafe impl Send for Logger {}
+unsafe impl Sync for Logger {}
+
+impl Logger {
+    // Without a URID map the host log cannot be used, as message types are URIDs.
+    pub fn new(log: Option<*const LV2_Log_Log>, map: Option<*const LV2_URID_Map>) -> Logger {
+        let (log, uris) = match map {
+            Some(map) => (log, [
+                urid_for_const(map, LV2_LOG_Trace),
+                urid_for_const(map, LV2_LOG_Note),
+                urid_for_const(map, LV2_LOG_Warning),
+                urid_for_const(map, LV2_LOG_Error),
+            ]),
+            None => (None, [0; 4]),
+        };
+        Logger {
+            log,
+            level: if cfg!(debug_assertions) { LogLevel::Trace } else { LogLevel::Note },
+            uris,
+            ring: LogRing::new(),
+            flush_requested: AtomicBool::new(false),
+        }
+    }
+
+    fn write(&self, level: LogLevel, text: &[u8]) {
+        match self.log {
+            Some(log) => unsafe {
+                ((*log).printf)((*log).handle,
+                                self.uris[level as usize],
+                                b"SynthZ: %s\n\0".as_ptr() as *const raw::c_char,
+                                text.as_ptr() as *const raw::c_char);
+            },
+            None => {
+                eprintln!("SynthZ: {}", String::from_utf8_lossy(&text[..text.len() - 1]));
+            }
+        }
+    }
+
+    // Logs immediately. Not realtime safe.
+    pub fn log(&self, level: LogLevel, args: fmt::Arguments) {
+        if level < self.level {
+            return;
+        }
+        let mut text = fmt::format(args).into_bytes();
+        text.push(0);
+        self.write(level, &text);
+    }
+
+    // Queues a message from the audio thread without allocating or blocking.
+    pub fn rt(&self, level: LogLevel, args: fmt::Arguments) {
+        if level >= self.level {
+            self.ring.push(level, args);
+        }
+    }
+
+    // Whether queued messages are waiting for a flush that nobody has asked for yet. Returns true
+    // only once until the next flush, so the audio thread can schedule a single flush.
+    pub fn needs_flush(&self) -> bool {
+        !self.ring.is_empty() && !self.flush_requested.swap(true, Ordering::AcqRel)
+    }
+
+    // Writes out messages queued by rt. Not realtime safe.
+    pub fn flush(&self) {
+        self.flush_requested.store(false, Ordering::Release);
+        while self.ring.pop(|m| self.write(m.level, &m.text[..m.len + 1])) {}
+        let dropped = self.ring.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            self.log(LogLevel::Warning, format_args!("{} messages dropped from the audio thread", dropped));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn popped(ring: &LogRing) -> Option<(LogLevel, String)> {
+        let mut message = None;
+        ring.pop(|m| {
+            assert_eq!(m.text[m.len], 0);
+            message = Some((m.level, String::from_utf8(m.text[..m.len].to_vec()).unwrap()));
+        });
+        message
+    }
+
+    #[test]
+    fn messages_pop_in_order() {
+        let ring = LogRing::new();
+        assert!(ring.is_empty());
+        ring.push(LogLevel::Note, format_args!("first {}", 1));
+        ring.push(LogLevel::Error, format_args!("second"));
+        assert!(!ring.is_empty());
+        assert_eq!(popped(&ring), Some((LogLevel::Note, String::from("first 1"))));
+        assert_eq!(popped(&ring), Some((LogLevel::Error, String::from("second"))));
+        assert_eq!(popped(&ring), None);
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn full_ring_drops_new_messages() {
+        let ring = LogRing::new();
+        for i in 0..RING_SIZE + 3 {
+            ring.push(LogLevel::Note, format_args!("{}", i));
+        }
+        assert_eq!(ring.dropped.load(Ordering::Relaxed), 3);
+        for i in 0..RING_SIZE {
+            assert_eq!(popped(&ring).unwrap().1, i.to_string());
+        }
+        assert_eq!(popped(&ring), None);
+
+        // Slots freed by popping are used again.
+        ring.push(LogLevel::Note, format_args!("again"));
+        assert_eq!(popped(&ring).unwrap().1, "again");
+    }
+
+    #[test]
+    fn long_messages_are_cut_between_characters() {
+        let ring = LogRing::new();
+        // Two byte characters, so the limit falls inside one.
+        let long = "é".repeat(MESSAGE_SIZE);
+        ring.push(LogLevel::Note, format_args!("{}{}", long, "tail"));
+        let (_, text) = popped(&ring).unwrap();
+        assert_eq!(text, "é".repeat((MESSAGE_SIZE - 1) / 2));
+
+        ring.push(LogLevel::Note, format_args!("a{}", long));
+        let (_, text) = popped(&ring).unwrap();
+        assert_eq!(text.len(), MESSAGE_SIZE - 1);
+        assert!(text.starts_with('a'));
+    }
+}
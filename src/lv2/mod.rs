@@ -3,3 +3,7 @@ pub mod core;
 pub mod atom;
 pub mod urid;
 pub mod midi;
+pub mod options;
+pub mod state;
+pub mod log;
+pub mod worker;
@@ -0,0 +1,55 @@
+use std::mem;
+use std::ptr;
+use lv2_raw::options::*;
+use lv2::atom::ForgeUris;
+
+// Walks an option array up to its terminating entry.
+pub struct OptionsIter {
+    next: *const LV2_Options_Option,
+}
+
+impl OptionsIter {
+    pub fn new(options: *const LV2_Options_Option) -> OptionsIter {
+        OptionsIter { next: options }
+    }
+}
+
+impl Iterator for OptionsIter {
+    type Item = *const LV2_Options_Option;
+
+    fn next(&mut self) -> Option<*const LV2_Options_Option> {
+        if self.next.is_null() {
+            return None;
+        }
+        unsafe {
+            let option = self.next;
+            if (*option).key == 0 && (*option).value.is_null() {
+                None
+            } else {
+                self.next = self.next.offset(1);
+                Some(option)
+            }
+        }
+    }
+}
+
+// Reads a numeric option of any atom number type, or None if the type or size is wrong.
+pub fn option_number(option: &LV2_Options_Option, uris: &ForgeUris) -> Option<f64> {
+    if option.value.is_null() {
+        return None;
+    }
+    let size = option.size as usize;
+    unsafe {
+        if option.type_ == uris.atom_int && size == mem::size_of::<i32>() {
+            Some(f64::from(ptr::read_unaligned(option.value as *const i32)))
+        } else if option.type_ == uris.atom_long && size == mem::size_of::<i64>() {
+            Some(ptr::read_unaligned(option.value as *const i64) as f64)
+        } else if option.type_ == uris.atom_float && size == mem::size_of::<f32>() {
+            Some(f64::from(ptr::read_unaligned(option.value as *const f32)))
+        } else if option.type_ == uris.atom_double && size == mem::size_of::<f64>() {
+            Some(ptr::read_unaligned(option.value as *const f64))
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use std::mem;
+use std::os::raw;
+use std::slice;
+use lv2_raw::state::*;
+use lv2_raw::urid::LV2_URID;
+
+pub struct StateStore {
+    store: LV2_State_Store_Function,
+    handle: LV2_State_Handle,
+}
+
+impl StateStore {
+    pub fn new(store: LV2_State_Store_Function, handle: LV2_State_Handle) -> StateStore {
+        StateStore { store, handle }
+    }
+
+    pub fn store_float(&self, key: LV2_URID, float_type: LV2_URID, value: f32) -> LV2_State_Status {
+        (self.store)(self.handle,
+                     key,
+                     &value as *const f32 as *const raw::c_void,
+                     mem::size_of::<f32>(),
+                     float_type,
+                     LV2_STATE_IS_POD | LV2_STATE_IS_PORTABLE)
+    }
+
+    pub fn store_int(&self, key: LV2_URID, int_type: LV2_URID, value: i32) -> LV2_State_Status {
+        (self.store)(self.handle,
+                     key,
+                     &value as *const i32 as *const raw::c_void,
+                     mem::size_of::<i32>(),
+                     int_type,
+                     LV2_STATE_IS_POD | LV2_STATE_IS_PORTABLE)
+    }
+
+    // Stores the text with its terminating null, as an atom:String body is.
+    pub fn store_string(&self, key: LV2_URID, string_type: LV2_URID, value: &str) -> LV2_State_Status {
+        let mut body = Vec::with_capacity(value.len() + 1);
+        body.extend_from_slice(value.as_bytes());
+        body.push(0);
+        (self.store)(self.handle,
+                     key,
+                     body.as_ptr() as *const raw::c_void,
+                     body.len(),
+                     string_type,
+                     LV2_STATE_IS_POD | LV2_STATE_IS_PORTABLE)
+    }
+}
+
+pub struct StateValue {
+    pub data: *const u8,
+    pub size: usize,
+    pub value_type: LV2_URID,
+}
+
+pub struct StateRetrieve {
+    retrieve: LV2_State_Retrieve_Function,
+    handle: LV2_State_Handle,
+}
+
+impl StateRetrieve {
+    pub fn new(retrieve: LV2_State_Retrieve_Function, handle: LV2_State_Handle) -> StateRetrieve {
+        StateRetrieve { retrieve, handle }
+    }
+
+    pub fn retrieve(&self, key: LV2_URID) -> Option<StateValue> {
+        let mut size: usize = 0;
+        let mut value_type: u32 = 0;
+        let mut flags: u32 = 0;
+        let data = (self.retrieve)(self.handle, key, &mut size, &mut value_type, &mut flags);
+        if data.is_null() {
+            None
+        } else {
+            Some(StateValue { data: data as *const u8, size, value_type })
+        }
+    }
+
+    pub fn retrieve_float(&self, key: LV2_URID, float_type: LV2_URID) -> Option<f32> {
+        self.retrieve(key)
+            .filter(|v| v.value_type == float_type && v.size == mem::size_of::<f32>())
+            .map(|v| unsafe { *(v.data as *const f32) })
+    }
+
+    pub fn retrieve_int(&self, key: LV2_URID, int_type: LV2_URID) -> Option<i32> {
+        self.retrieve(key)
+            .filter(|v| v.value_type == int_type && v.size == mem::size_of::<i32>())
+            .map(|v| unsafe { *(v.data as *const i32) })
+    }
+
+    pub fn retrieve_string(&self, key: LV2_URID, string_type: LV2_URID) -> Option<String> {
+        self.retrieve(key)
+            .filter(|v| v.value_type == string_type && v.size > 0)
+            .map(|v| {
+                let body = unsafe { slice::from_raw_parts(v.data, v.size) };
+                let text = body.split(|b| *b == 0).next().unwrap_or(&[]);
+                String::from_utf8_lossy(text).into_owned()
+            })
+    }
+}
@@ -0,0 +1,55 @@
+use std::mem;
+use std::ptr;
+use std::os::raw;
+use lv2_raw::worker::*;
+
+// Values are passed between threads by copying their bytes through the host, so ownership moves
+// with the message: exactly one side may read it back with read_message.
+
+pub struct WorkerSchedule {
+    schedule: *const LV2_Worker_Schedule,
+}
+
+impl WorkerSchedule {
+    pub fn new(schedule: *const LV2_Worker_Schedule) -> WorkerSchedule {
+        WorkerSchedule { schedule }
+    }
+
+    // Sends a value to the worker thread. If the host cannot take it, it is handed back.
+    pub fn schedule<T: Send>(&self, value: T) -> Result<(), T> {
+        let value = mem::ManuallyDrop::new(value);
+        let status = unsafe {
+            ((*self.schedule).schedule_work)((*self.schedule).handle,
+                                             mem::size_of::<T>() as u32,
+                                             &*value as *const T as *const raw::c_void)
+        };
+        if status == LV2_WORKER_SUCCESS {
+            Ok(())
+        } else {
+            Err(mem::ManuallyDrop::into_inner(value))
+        }
+    }
+}
+
+// Sends a value from the worker thread back to the audio thread.
+pub fn respond<T: Send>(respond: LV2_Worker_Respond_Function,
+                        handle: LV2_Worker_Respond_Handle,
+                        value: T) -> Result<(), T> {
+    let value = mem::ManuallyDrop::new(value);
+    let status = respond(handle, mem::size_of::<T>() as u32, &*value as *const T as *const raw::c_void);
+    if status == LV2_WORKER_SUCCESS {
+        Ok(())
+    } else {
+        Err(mem::ManuallyDrop::into_inner(value))
+    }
+}
+
+// Takes ownership of a value sent by schedule or respond. The host makes no promises about
+// alignment, so the value is read unaligned.
+pub unsafe fn read_message<T: Send>(size: u32, data: *const raw::c_void) -> Option<T> {
+    if size as usize == mem::size_of::<T>() && !data.is_null() {
+        Some(ptr::read_unaligned(data as *const T))
+    } else {
+        None
+    }
+}
@@ -1,5 +1,7 @@
 
 pub const LV2_ATOM_Blank: *const u8 = b"http://lv2plug.in/ns/ext/atom#Blank\0" as *const u8;
+pub const LV2_ATOM_Bool: *const u8 = b"http://lv2plug.in/ns/ext/atom#Bool\0" as *const u8;
+pub const LV2_ATOM_Double: *const u8 = b"http://lv2plug.in/ns/ext/atom#Double\0" as *const u8;
 pub const LV2_ATOM_Float: *const u8 = b"http://lv2plug.in/ns/ext/atom#Float\0" as *const u8;
 pub const LV2_ATOM_Literal: *const u8 = b"http://lv2plug.in/ns/ext/atom#Literal\0" as *const u8;
 pub const LV2_ATOM_Int: *const u8 = b"http://lv2plug.in/ns/ext/atom#Int\0" as *const u8;
@@ -8,7 +10,10 @@ pub const LV2_ATOM_Object: *const u8 = b"http://lv2plug.in/ns/ext/atom#Object\0"
 pub const LV2_ATOM_Path: *const u8 = b"http://lv2plug.in/ns/ext/atom#Path\0" as *const u8;
 pub const LV2_ATOM_Property: *const u8 = b"http://lv2plug.in/ns/ext/atom#Property\0" as *const u8;
 pub const LV2_ATOM_Resource: *const u8 = b"http://lv2plug.in/ns/ext/atom#Resource\0" as *const u8;
+pub const LV2_ATOM_beatTime: *const u8 = b"http://lv2plug.in/ns/ext/atom#beatTime\0" as *const u8;
+pub const LV2_ATOM_frameTime: *const u8 = b"http://lv2plug.in/ns/ext/atom#frameTime\0" as *const u8;
 pub const LV2_ATOM_Sequence: *const u8 = b"http://lv2plug.in/ns/ext/atom#Sequence\0" as *const u8;
+pub const LV2_ATOM_String: *const u8 = b"http://lv2plug.in/ns/ext/atom#String\0" as *const u8;
 pub const LV2_ATOM_URID: *const u8 = b"http://lv2plug.in/ns/ext/atom#URID\0" as *const u8;
 pub const LV2_ATOM_eventTransfer: *const u8 = b"http://lv2plug.in/ns/ext/atom#eventTransfer\0" as *const u8;
 
@@ -18,15 +23,17 @@ pub struct LV2_Atom {
     pub atom_type: u32,
 }
 
-// #[repr(C)]
-// pub union LV2_Atom_Event_Time {
-//     frames: i64,
-//     beats: f64,
-// }
+#[repr(C)]
+#[derive(Clone)]
+#[derive(Copy)]
+pub union LV2_Atom_Event_Time {
+    pub frames: i64,
+    pub beats: f64,
+}
 
 #[repr(C)]
 pub struct LV2_Atom_Event {
-    pub time_frames: i64, // LV2_Atom_Event_Time,
+    pub time: LV2_Atom_Event_Time,
     pub body: LV2_Atom,
 }
 
@@ -0,0 +1,4 @@
+pub const LV2_BUF_SIZE_boundedBlockLength: *const u8 = b"http://lv2plug.in/ns/ext/buf-size#boundedBlockLength\0" as *const u8;
+pub const LV2_BUF_SIZE_maxBlockLength: *const u8 = b"http://lv2plug.in/ns/ext/buf-size#maxBlockLength\0" as *const u8;
+pub const LV2_BUF_SIZE_minBlockLength: *const u8 = b"http://lv2plug.in/ns/ext/buf-size#minBlockLength\0" as *const u8;
+pub const LV2_BUF_SIZE_nominalBlockLength: *const u8 = b"http://lv2plug.in/ns/ext/buf-size#nominalBlockLength\0" as *const u8;
@@ -12,12 +12,12 @@ pub struct LV2_Feature {
 #[repr(C)]
 pub struct LV2_Descriptor {
     pub URI: *const raw::c_char,
-    pub instantiate: extern fn (*const LV2_Descriptor, f64, *const raw::c_char, *const *const LV2_Feature) -> LV2_Handle,
-    pub connect_port: extern fn (LV2_Handle, u32, *mut raw::c_void),
-    pub activate: extern fn (LV2_Handle),
-    pub run:extern fn (LV2_Handle, u32),
-    pub deactivate:extern fn (LV2_Handle),
-    pub cleanup:extern fn (LV2_Handle),
-    pub extension_data:extern fn (*const raw::c_char) -> *mut raw::c_void,
+    pub instantiate: extern "C" fn(*const LV2_Descriptor, f64, *const raw::c_char, *const *const LV2_Feature) -> LV2_Handle,
+    pub connect_port: extern "C" fn(LV2_Handle, u32, *mut raw::c_void),
+    pub activate: extern "C" fn(LV2_Handle),
+    pub run:extern "C" fn(LV2_Handle, u32),
+    pub deactivate:extern "C" fn(LV2_Handle),
+    pub cleanup:extern "C" fn(LV2_Handle),
+    pub extension_data:extern "C" fn(*const raw::c_char) -> *mut raw::c_void,
 }
 
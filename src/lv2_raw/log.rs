@@ -0,0 +1,19 @@
+use std::os::raw as raw;
+use lv2_raw::urid::LV2_URID;
+
+pub const LV2_LOG_log: *const u8 = b"http://lv2plug.in/ns/ext/log#log\0" as *const u8;
+pub const LV2_LOG_Entry: *const u8 = b"http://lv2plug.in/ns/ext/log#Entry\0" as *const u8;
+pub const LV2_LOG_Error: *const u8 = b"http://lv2plug.in/ns/ext/log#Error\0" as *const u8;
+pub const LV2_LOG_Note: *const u8 = b"http://lv2plug.in/ns/ext/log#Note\0" as *const u8;
+pub const LV2_LOG_Trace: *const u8 = b"http://lv2plug.in/ns/ext/log#Trace\0" as *const u8;
+pub const LV2_LOG_Warning: *const u8 = b"http://lv2plug.in/ns/ext/log#Warning\0" as *const u8;
+
+pub type LV2_Log_Handle = *mut raw::c_void;
+
+#[repr(C)]
+pub struct LV2_Log_Log {
+    pub handle: LV2_Log_Handle,
+    pub printf: unsafe extern "C" fn(LV2_Log_Handle, LV2_URID, *const raw::c_char, ...) -> raw::c_int,
+    // Takes a va_list, which we never construct.
+    pub vprintf: *const raw::c_void,
+}
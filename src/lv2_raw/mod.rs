@@ -1,5 +1,15 @@
+// Bindings keep the names used by the LV2 C headers, and cover more than the plugin uses.
+#![allow(dead_code, non_camel_case_types, non_snake_case, non_upper_case_globals)]
 
 pub mod core;
 pub mod urid;
 pub mod atom;
+pub mod buf_size;
+pub mod log;
 pub mod midi;
+pub mod options;
+pub mod parameters;
+pub mod patch;
+pub mod state;
+pub mod time;
+pub mod worker;
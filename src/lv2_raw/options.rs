@@ -0,0 +1,38 @@
+use std::os::raw as raw;
+use lv2_raw::core::LV2_Handle;
+use lv2_raw::urid::LV2_URID;
+
+pub const LV2_OPTIONS_interface: *const u8 = b"http://lv2plug.in/ns/ext/options#interface\0" as *const u8;
+pub const LV2_OPTIONS_options: *const u8 = b"http://lv2plug.in/ns/ext/options#options\0" as *const u8;
+
+pub type LV2_Options_Context = u32;
+
+pub const LV2_OPTIONS_INSTANCE: LV2_Options_Context = 0;  // Applies to the instance itself
+pub const LV2_OPTIONS_RESOURCE: LV2_Options_Context = 1;  // Applies to some named resource
+pub const LV2_OPTIONS_BLANK: LV2_Options_Context    = 2;  // Applies to some blank node
+pub const LV2_OPTIONS_PORT: LV2_Options_Context     = 3;  // Applies to a port on the instance
+
+pub type LV2_Options_Status = u32;
+
+pub const LV2_OPTIONS_SUCCESS: LV2_Options_Status         = 0;  // Completed successfully
+pub const LV2_OPTIONS_ERR_UNKNOWN: LV2_Options_Status     = 1;  // Unknown error
+pub const LV2_OPTIONS_ERR_BAD_SUBJECT: LV2_Options_Status = 1 << 1;  // Invalid/unsupported subject
+pub const LV2_OPTIONS_ERR_BAD_KEY: LV2_Options_Status     = 1 << 2;  // Invalid/unsupported key
+pub const LV2_OPTIONS_ERR_BAD_VALUE: LV2_Options_Status   = 1 << 3;  // Invalid/unsupported value
+
+// Arrays of options are terminated by an option with a zero key and null value.
+#[repr(C)]
+pub struct LV2_Options_Option {
+    pub context: LV2_Options_Context,
+    pub subject: u32,
+    pub key: LV2_URID,
+    pub size: u32,
+    pub type_: LV2_URID,
+    pub value: *const raw::c_void,
+}
+
+#[repr(C)]
+pub struct LV2_Options_Interface {
+    pub get: extern "C" fn(LV2_Handle, *mut LV2_Options_Option) -> LV2_Options_Status,
+    pub set: extern "C" fn(LV2_Handle, *const LV2_Options_Option) -> LV2_Options_Status,
+}
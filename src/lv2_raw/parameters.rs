@@ -0,0 +1 @@
+pub const LV2_PARAMETERS_sampleRate: *const u8 = b"http://lv2plug.in/ns/ext/parameters#sampleRate\0" as *const u8;
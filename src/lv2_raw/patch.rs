@@ -0,0 +1,4 @@
+pub const LV2_PATCH_Get: *const u8 = b"http://lv2plug.in/ns/ext/patch#Get\0" as *const u8;
+pub const LV2_PATCH_Set: *const u8 = b"http://lv2plug.in/ns/ext/patch#Set\0" as *const u8;
+pub const LV2_PATCH_property: *const u8 = b"http://lv2plug.in/ns/ext/patch#property\0" as *const u8;
+pub const LV2_PATCH_value: *const u8 = b"http://lv2plug.in/ns/ext/patch#value\0" as *const u8;
@@ -0,0 +1,32 @@
+use std::os::raw as raw;
+use lv2_raw::core::*;
+
+pub const LV2_STATE_interface: *const u8 = b"http://lv2plug.in/ns/ext/state#interface\0" as *const u8;
+
+pub type LV2_State_Handle = *mut raw::c_void;
+
+pub type LV2_State_Flags = u32;
+
+pub const LV2_STATE_IS_POD: LV2_State_Flags      = 1;
+pub const LV2_STATE_IS_PORTABLE: LV2_State_Flags = 1 << 1;
+pub const LV2_STATE_IS_NATIVE: LV2_State_Flags   = 1 << 2;
+
+pub type LV2_State_Status = u32;
+
+pub const LV2_STATE_SUCCESS: LV2_State_Status         = 0;  // Completed successfully
+pub const LV2_STATE_ERR_UNKNOWN: LV2_State_Status     = 1;  // Unknown error
+pub const LV2_STATE_ERR_BAD_TYPE: LV2_State_Status    = 2;  // Failed due to unsupported type
+pub const LV2_STATE_ERR_BAD_FLAGS: LV2_State_Status   = 3;  // Failed due to unsupported flags
+pub const LV2_STATE_ERR_NO_FEATURE: LV2_State_Status  = 4;  // Failed due to missing features
+pub const LV2_STATE_ERR_NO_PROPERTY: LV2_State_Status = 5;  // Failed due to missing property
+pub const LV2_STATE_ERR_NO_SPACE: LV2_State_Status    = 6;  // Failed due to insufficient space
+
+pub type LV2_State_Store_Function = extern "C" fn(LV2_State_Handle, u32, *const raw::c_void, usize, u32, u32) -> LV2_State_Status;
+
+pub type LV2_State_Retrieve_Function = extern "C" fn(LV2_State_Handle, u32, *mut usize, *mut u32, *mut u32) -> *const raw::c_void;
+
+#[repr(C)]
+pub struct LV2_State_Interface {
+    pub save: extern "C" fn(LV2_Handle, LV2_State_Store_Function, LV2_State_Handle, u32, *const *const LV2_Feature) -> LV2_State_Status,
+    pub restore: extern "C" fn(LV2_Handle, LV2_State_Retrieve_Function, LV2_State_Handle, u32, *const *const LV2_Feature) -> LV2_State_Status,
+}
@@ -0,0 +1,9 @@
+pub const LV2_TIME_Position: *const u8 = b"http://lv2plug.in/ns/ext/time#Position\0" as *const u8;
+pub const LV2_TIME_bar: *const u8 = b"http://lv2plug.in/ns/ext/time#bar\0" as *const u8;
+pub const LV2_TIME_barBeat: *const u8 = b"http://lv2plug.in/ns/ext/time#barBeat\0" as *const u8;
+pub const LV2_TIME_beatUnit: *const u8 = b"http://lv2plug.in/ns/ext/time#beatUnit\0" as *const u8;
+pub const LV2_TIME_beatsPerBar: *const u8 = b"http://lv2plug.in/ns/ext/time#beatsPerBar\0" as *const u8;
+pub const LV2_TIME_beatsPerMinute: *const u8 = b"http://lv2plug.in/ns/ext/time#beatsPerMinute\0" as *const u8;
+pub const LV2_TIME_frame: *const u8 = b"http://lv2plug.in/ns/ext/time#frame\0" as *const u8;
+pub const LV2_TIME_framesPerSecond: *const u8 = b"http://lv2plug.in/ns/ext/time#framesPerSecond\0" as *const u8;
+pub const LV2_TIME_speed: *const u8 = b"http://lv2plug.in/ns/ext/time#speed\0" as *const u8;
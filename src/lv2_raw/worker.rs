@@ -0,0 +1,30 @@
+use std::os::raw as raw;
+use lv2_raw::core::LV2_Handle;
+
+pub const LV2_WORKER_interface: *const u8 = b"http://lv2plug.in/ns/ext/worker#interface\0" as *const u8;
+pub const LV2_WORKER_schedule: *const u8 = b"http://lv2plug.in/ns/ext/worker#schedule\0" as *const u8;
+
+pub type LV2_Worker_Status = u32;
+
+pub const LV2_WORKER_SUCCESS: LV2_Worker_Status      = 0;  // Completed successfully
+pub const LV2_WORKER_ERR_UNKNOWN: LV2_Worker_Status  = 1;  // Unknown error
+pub const LV2_WORKER_ERR_NO_SPACE: LV2_Worker_Status = 2;  // Failed due to lack of space
+
+pub type LV2_Worker_Respond_Handle = *mut raw::c_void;
+
+pub type LV2_Worker_Respond_Function = extern "C" fn(LV2_Worker_Respond_Handle, u32, *const raw::c_void) -> LV2_Worker_Status;
+
+#[repr(C)]
+pub struct LV2_Worker_Interface {
+    pub work: extern "C" fn(LV2_Handle, LV2_Worker_Respond_Function, LV2_Worker_Respond_Handle, u32, *const raw::c_void) -> LV2_Worker_Status,
+    pub work_response: extern "C" fn(LV2_Handle, u32, *const raw::c_void) -> LV2_Worker_Status,
+    pub end_run: extern "C" fn(LV2_Handle) -> LV2_Worker_Status,
+}
+
+pub type LV2_Worker_Schedule_Handle = *mut raw::c_void;
+
+#[repr(C)]
+pub struct LV2_Worker_Schedule {
+    pub handle: LV2_Worker_Schedule_Handle,
+    pub schedule_work: extern "C" fn(LV2_Worker_Schedule_Handle, u32, *const raw::c_void) -> LV2_Worker_Status,
+}
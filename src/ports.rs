@@ -0,0 +1,384 @@
+// The TTL is only written by the tests, which regenerate the shipped files.
+#![cfg_attr(not(test), allow(dead_code))]
+
+use std::ffi;
+use std::fmt::Write;
+use std::os::raw;
+
+use synth::params::ParamInfo;
+use synth::presets::Preset;
+
+// Plugin and port metadata. The plugin TTL and manifest are generated from these tables, and
+// connect_port checks ports against them, so indices, symbols and ranges live in one place.
+
+pub const PREFIXES: [(&str, &str); 18] = [
+    ("atom", "http://lv2plug.in/ns/ext/atom#"),
+    ("bufsz", "http://lv2plug.in/ns/ext/buf-size#"),
+    ("doap", "http://usefulinc.com/ns/doap#"),
+    ("log", "http://lv2plug.in/ns/ext/log#"),
+    ("lv2", "http://lv2plug.in/ns/lv2core#"),
+    ("midi", "http://lv2plug.in/ns/ext/midi#"),
+    ("opts", "http://lv2plug.in/ns/ext/options#"),
+    ("param", "http://lv2plug.in/ns/ext/parameters#"),
+    ("patch", "http://lv2plug.in/ns/ext/patch#"),
+    ("pg", "http://lv2plug.in/ns/ext/port-groups#"),
+    ("pset", "http://lv2plug.in/ns/ext/presets#"),
+    ("rdf", "http://www.w3.org/1999/02/22-rdf-syntax-ns#"),
+    ("rdfs", "http://www.w3.org/2000/01/rdf-schema#"),
+    ("state", "http://lv2plug.in/ns/ext/state#"),
+    ("time", "http://lv2plug.in/ns/ext/time#"),
+    ("units", "http://lv2plug.in/ns/extensions/units#"),
+    ("urid", "http://lv2plug.in/ns/ext/urid#"),
+    ("work", "http://lv2plug.in/ns/ext/worker#"),
+];
+
+// Written with the prefixes above, so "lv2:toggled" rather than a full URI.
+pub type Term = &'static str;
+
+#[derive(Clone)]
+#[derive(Copy)]
+pub enum PortKind {
+    // Sequence of events, of the given types.
+    AtomInput(&'static [Term]),
+    AtomOutput(&'static [Term]),
+    AudioInput,
+    AudioOutput,
+    ControlInput,
+    CvInput,
+    CvOutput,
+}
+
+#[derive(Clone)]
+#[derive(Copy)]
+pub struct ScalePoint {
+    pub label: &'static str,
+    pub value: f32,
+}
+
+#[derive(Clone)]
+#[derive(Copy)]
+pub struct PortInfo {
+    pub symbol: &'static str,
+    pub name: &'static str,
+    pub kind: PortKind,
+    pub designation: Option<Term>,
+    // Only used by control ports.
+    pub default: f32,
+    pub minimum: f32,
+    pub maximum: f32,
+    pub unit: Option<Term>,
+    pub properties: &'static [Term],
+    pub scale_points: &'static [ScalePoint],
+}
+
+impl PortInfo {
+    const fn new(symbol: &'static str, name: &'static str, kind: PortKind) -> PortInfo {
+        PortInfo {
+            symbol,
+            name,
+            kind,
+            designation: None,
+            default: 0.0,
+            minimum: 0.0,
+            maximum: 1.0,
+            unit: None,
+            properties: &[],
+            scale_points: &[],
+        }
+    }
+
+    pub const fn atom_input(symbol: &'static str, name: &'static str, supports: &'static [Term]) -> PortInfo {
+        PortInfo::new(symbol, name, PortKind::AtomInput(supports))
+    }
+
+    pub const fn atom_output(symbol: &'static str, name: &'static str, supports: &'static [Term]) -> PortInfo {
+        PortInfo::new(symbol, name, PortKind::AtomOutput(supports))
+    }
+
+    pub const fn audio_input(symbol: &'static str, name: &'static str) -> PortInfo {
+        PortInfo::new(symbol, name, PortKind::AudioInput)
+    }
+
+    pub const fn audio_output(symbol: &'static str, name: &'static str) -> PortInfo {
+        PortInfo::new(symbol, name, PortKind::AudioOutput)
+    }
+
+    pub const fn cv_input(symbol: &'static str, name: &'static str) -> PortInfo {
+        PortInfo::new(symbol, name, PortKind::CvInput)
+    }
+
+    pub const fn cv_output(symbol: &'static str, name: &'static str) -> PortInfo {
+        PortInfo::new(symbol, name, PortKind::CvOutput)
+    }
+
+    pub const fn control(symbol: &'static str, name: &'static str, default: f32, minimum: f32, maximum: f32) -> PortInfo {
+        PortInfo { default, minimum, maximum, ..PortInfo::new(symbol, name, PortKind::ControlInput) }
+    }
+
+    pub const fn designation(self, designation: Term) -> PortInfo {
+        PortInfo { designation: Some(designation), ..self }
+    }
+
+    pub const fn unit(self, unit: Term) -> PortInfo {
+        PortInfo { unit: Some(unit), ..self }
+    }
+
+    pub const fn properties(self, properties: &'static [Term]) -> PortInfo {
+        PortInfo { properties, ..self }
+    }
+
+    pub const fn scale_points(self, scale_points: &'static [ScalePoint]) -> PortInfo {
+        PortInfo { scale_points, ..self }
+    }
+
+    fn statements(&self, index: usize) -> Vec<(Term, Vec<String>)> {
+        let (types, buffer, supports) = match self.kind {
+            PortKind::AtomInput(s) => (["lv2:InputPort", "atom:AtomPort"], true, s),
+            PortKind::AtomOutput(s) => (["lv2:OutputPort", "atom:AtomPort"], true, s),
+            PortKind::AudioInput => (["lv2:AudioPort", "lv2:InputPort"], false, &[][..]),
+            PortKind::AudioOutput => (["lv2:AudioPort", "lv2:OutputPort"], false, &[][..]),
+            PortKind::ControlInput => (["lv2:InputPort", "lv2:ControlPort"], false, &[][..]),
+            PortKind::CvInput => (["lv2:CVPort", "lv2:InputPort"], false, &[][..]),
+            PortKind::CvOutput => (["lv2:CVPort", "lv2:OutputPort"], false, &[][..]),
+        };
+        let mut statements = vec![("a", types.iter().map(|t| t.to_string()).collect())];
+        if buffer {
+            statements.push(("atom:bufferType", vec![String::from("atom:Sequence")]));
+        }
+        if !supports.is_empty() {
+            statements.push(("atom:supports", supports.iter().map(|t| t.to_string()).collect()));
+        }
+        if let Some(d) = self.designation {
+            statements.push(("lv2:designation", vec![d.to_string()]));
+        }
+        statements.push(("lv2:index", vec![index.to_string()]));
+        statements.push(("lv2:symbol", vec![quote(self.symbol)]));
+        statements.push(("lv2:name", vec![quote(self.name)]));
+        if let PortKind::ControlInput = self.kind {
+            statements.push(("lv2:default", vec![number(self.default)]));
+            statements.push(("lv2:minimum", vec![number(self.minimum)]));
+            statements.push(("lv2:maximum", vec![number(self.maximum)]));
+        }
+        if let Some(u) = self.unit {
+            statements.push(("units:unit", vec![u.to_string()]));
+        }
+        if !self.properties.is_empty() {
+            statements.push(("lv2:portProperty", self.properties.iter().map(|t| t.to_string()).collect()));
+        }
+        if !self.scale_points.is_empty() {
+            let points: Vec<String> = self.scale_points.iter().map(|p| {
+                let mut point = String::from("[\n");
+                write_statements(&mut point, "\t\t\t", &[
+                    ("rdfs:label", vec![quote(p.label)]),
+                    ("rdf:value", vec![number(p.value)]),
+                ]);
+                point.push_str("\n\t\t]");
+                point
+            }).collect();
+            statements.push(("lv2:scalePoint", vec![points.join(" , ")]));
+        }
+        statements
+    }
+}
+
+// A parameter naming a file, set with patch:Set of an atom:Path.
+pub struct PathParameter {
+    pub symbol: &'static str,
+    pub name: &'static str,
+}
+
+pub struct PluginInfo {
+    pub uri: *const u8,
+    pub name: &'static str,
+    pub class: Term,
+    // File in resources holding the generated TTL.
+    pub ttl: &'static str,
+    pub required_features: &'static [Term],
+    pub optional_features: &'static [Term],
+    pub extension_data: &'static [Term],
+    pub supported_options: &'static [Term],
+    // Parameters are named under parameter_base, so variants can share them.
+    pub parameter_base: *const u8,
+    pub parameters: &'static [ParamInfo],
+    pub path_parameters: &'static [PathParameter],
+    pub ports: &'static [PortInfo],
+    pub presets: &'static [Preset],
+    // File in resources holding the presets, if there are any.
+    pub presets_ttl: &'static str,
+}
+
+fn c_str(uri: *const u8) -> &'static str {
+    unsafe { ffi::CStr::from_ptr(uri as *const raw::c_char) }.to_str().unwrap()
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s)
+}
+
+// Always written with a decimal point, so Turtle reads it as a decimal.
+fn number(v: f32) -> String {
+    format!("{:?}", v)
+}
+
+// Writes "predicate object" pairs joined by ";", with multiple objects joined by ",".
+fn write_statements(out: &mut String, indent: &str, statements: &[(Term, Vec<String>)]) {
+    for (i, &(predicate, ref objects)) in statements.iter().enumerate() {
+        if i > 0 {
+            out.push_str(" ;\n");
+        }
+        let _ = write!(out, "{}{} {}", indent, predicate, objects.join(&format!(" ,\n{}\t", indent)));
+    }
+}
+
+// Prefixes the body with declarations of the prefixes it uses.
+fn with_prefixes(body: &str) -> String {
+    let mut out = String::new();
+    for &(prefix, uri) in PREFIXES.iter().filter(|&&(p, _)| body.contains(&format!("{}:", p))) {
+        let _ = writeln!(out, "@prefix {:<6} <{}> .", format!("{}:", prefix), uri);
+    }
+    out.push('\n');
+    out.push_str(body);
+    out
+}
+
+impl PluginInfo {
+    pub fn uri(&self) -> &'static str {
+        c_str(self.uri)
+    }
+
+    pub fn parameter_uri(&self, symbol: &str) -> String {
+        format!("{}#{}", c_str(self.parameter_base), symbol)
+    }
+
+    fn statements(&self) -> Vec<(Term, Vec<String>)> {
+        let terms = |ts: &[Term]| ts.iter().map(|t| t.to_string()).collect::<Vec<String>>();
+        let mut statements = vec![
+            ("a", vec![String::from("lv2:Plugin"), self.class.to_string()]),
+            ("lv2:project", vec![String::from("<http://lv2plug.in/ns/lv2>")]),
+            ("doap:name", vec![quote(self.name)]),
+            ("doap:license", vec![String::from("<http://opensource.org/licenses/isc>")]),
+        ];
+        let lists = [
+            ("lv2:requiredFeature", self.required_features),
+            ("lv2:optionalFeature", self.optional_features),
+            ("lv2:extensionData", self.extension_data),
+            ("opts:supportedOption", self.supported_options),
+        ];
+        for &(predicate, items) in lists.iter().filter(|&&(_, items)| !items.is_empty()) {
+            statements.push((predicate, terms(items)));
+        }
+        let symbols = self.parameters.iter().map(|p| p.symbol)
+            .chain(self.path_parameters.iter().map(|p| p.symbol))
+            .map(|symbol| format!("<{}>", self.parameter_uri(symbol)))
+            .collect::<Vec<String>>();
+        if !symbols.is_empty() {
+            statements.push(("patch:writable", symbols));
+        }
+        let ports = self.ports.iter().enumerate().map(|(i, port)| {
+            let mut out = String::from("[\n");
+            write_statements(&mut out, "\t\t", &port.statements(i));
+            out.push_str("\n\t]");
+            out
+        }).collect::<Vec<String>>();
+        // Ports are listed "[ ... ] , [ ... ]" rather than one per line.
+        statements.push(("lv2:port", vec![ports.join(" , ")]));
+        statements
+    }
+
+    // The plugin's TTL file, as shipped in resources.
+    pub fn to_ttl(&self) -> String {
+        let mut body = String::new();
+        for param in self.parameters {
+            let mut statements = vec![
+                ("a", vec![String::from("lv2:Parameter")]),
+                ("rdfs:label", vec![quote(param.name)]),
+                ("rdfs:range", vec![String::from("atom:Float")]),
+                ("lv2:default", vec![number(param.default)]),
+                ("lv2:minimum", vec![number(param.minimum)]),
+                ("lv2:maximum", vec![number(param.maximum)]),
+            ];
+            if let Some(u) = param.unit {
+                statements.push(("units:unit", vec![u.to_string()]));
+            }
+            let _ = writeln!(body, "<{}>", self.parameter_uri(param.symbol));
+            write_statements(&mut body, "\t", &statements);
+            body.push_str(" .\n\n");
+        }
+        for param in self.path_parameters {
+            let _ = writeln!(body, "<{}>", self.parameter_uri(param.symbol));
+            write_statements(&mut body, "\t", &[
+                ("a", vec![String::from("lv2:Parameter")]),
+                ("rdfs:label", vec![quote(param.name)]),
+                ("rdfs:range", vec![String::from("atom:Path")]),
+            ]);
+            body.push_str(" .\n\n");
+        }
+        let _ = writeln!(body, "<{}>", self.uri());
+        write_statements(&mut body, "\t", &self.statements());
+        body.push_str(" .\n");
+        with_prefixes(&body)
+    }
+
+    pub fn preset_uri(&self, preset: &Preset) -> String {
+        format!("{}/presets#{}", self.uri(), preset.symbol)
+    }
+
+    // The plugin's presets file, setting each control port the plugin has.
+    pub fn presets_to_ttl(&self) -> String {
+        let mut body = String::new();
+        for (i, preset) in self.presets.iter().enumerate() {
+            if i > 0 {
+                body.push('\n');
+            }
+            let values = preset.controls.values();
+            let ports = self.ports.iter()
+                .filter_map(|port| values.iter().find(|&&(symbol, _)| symbol == port.symbol))
+                .map(|&(symbol, value)| {
+                    let mut out = String::from("[\n");
+                    write_statements(&mut out, "\t\t", &[
+                        ("lv2:symbol", vec![quote(symbol)]),
+                        ("pset:value", vec![number(value)]),
+                    ]);
+                    out.push_str("\n\t]");
+                    out
+                })
+                .collect::<Vec<String>>();
+            let _ = writeln!(body, "<{}>", self.preset_uri(preset));
+            write_statements(&mut body, "\t", &[
+                ("a", vec![String::from("pset:Preset")]),
+                ("lv2:appliesTo", vec![format!("<{}>", self.uri())]),
+                ("rdfs:label", vec![quote(preset.name)]),
+                ("lv2:port", vec![ports.join(" , ")]),
+            ]);
+            body.push_str(" .\n");
+        }
+        with_prefixes(&body)
+    }
+}
+
+// The bundle manifest, listing every plugin in the binary and their presets.
+pub fn manifest_ttl(plugins: &[&PluginInfo], binary: &str) -> String {
+    let mut body = String::new();
+    for plugin in plugins {
+        let _ = writeln!(body, "\n<{}>", plugin.uri());
+        write_statements(&mut body, "\t", &[
+            ("a", vec![String::from("lv2:Plugin")]),
+            ("lv2:binary", vec![format!("<{}>", binary)]),
+            ("rdfs:seeAlso", vec![format!("<{}>", plugin.ttl)]),
+        ]);
+        body.push_str(" .\n");
+    }
+    for plugin in plugins {
+        for preset in plugin.presets {
+            let _ = writeln!(body, "\n<{}>", plugin.preset_uri(preset));
+            write_statements(&mut body, "\t", &[
+                ("a", vec![String::from("pset:Preset")]),
+                ("lv2:appliesTo", vec![format!("<{}>", plugin.uri())]),
+                ("rdfs:seeAlso", vec![format!("<{}>", plugin.presets_ttl)]),
+            ]);
+            body.push_str(" .\n");
+        }
+    }
+    // Entries are separated by blank lines, so drop the first.
+    with_prefixes(&body[1..])
+}
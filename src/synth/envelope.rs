@@ -14,8 +14,8 @@ pub struct Envelope {
 }
 
 impl Envelope {
-    pub fn new(rate: f32) -> module::MisoModule<Envelope> {
-        module::MisoModule::new(Envelope {
+    pub fn new(rate: f32) -> module::MimoModule<Envelope> {
+        module::MimoModule::new(Envelope {
             rate,
             t: 0.0,
             n_trig_1: true,
@@ -25,7 +25,8 @@ impl Envelope {
         })
     }
 
-    fn envelope(&mut self, ar: f32, dr: f32, s: f32, rr: f32, trig: f32, sig_n: f32) -> f32 {
+    // Gain for the signal at the next sample.
+    fn envelope(&mut self, ar: f32, dr: f32, s: f32, rr: f32, trig: f32) -> f32 {
         let a = ar * self.rate * 10.0;
         let d = dr * self.rate * 10.0;
         let r = rr * self.rate * 10.0;
@@ -55,12 +56,24 @@ impl Envelope {
         self.n_trig_1 = trig > 0.5;
         self.cur = env;
 
-        sig_n * ((2.0 as f32).powf(env) - 1.0)
+        (2.0 as f32).powf(env) - 1.0
     }
 
+    // Whether the release has finished, and the envelope waits for the next trigger.
+    fn ended(&self) -> bool {
+        !self.n_trig_1 && self.cur <= 0.0
+    }
+}
+
+enum Outputs {
+    Output = 0,
+    Level = 1,
+    End = 2,
 }
 
-impl module::MisoWorker for Envelope {
+// Output is the signal shaped by the envelope, level the gain it is shaped by, and end a gate
+// which is high from the end of the release until the next trigger.
+impl module::MimoWorker for Envelope {
     fn get_data(&self) -> Vec<module::DataIn> {
         vec![
             module::DataIn::new(String::from("attack"), 0.1),
@@ -72,7 +85,14 @@ impl module::MisoWorker for Envelope {
         ]
     }
 
-    fn extract(&mut self, vals: &[f32]) -> f32 {
-        self.envelope(vals[0], vals[1], vals[2], vals[3], vals[4], vals[5])
+    fn get_outputs(&self) -> &'static [&'static str] {
+        &["output", "level", "end"]
+    }
+
+    fn extract(&mut self, vals: &[f32], outs: &mut [f32]) {
+        let level = self.envelope(vals[0], vals[1], vals[2], vals[3], vals[4]);
+        outs[Outputs::Output as usize] = vals[5] * level;
+        outs[Outputs::Level as usize] = level;
+        outs[Outputs::End as usize] = if self.ended() { 1.0 } else { 0.0 };
     }
 }
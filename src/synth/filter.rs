@@ -1,8 +1,8 @@
 
 use std::f32;
-use std::cmp;
 
 #[derive(Debug)]
+#[derive(Clone)]
 pub struct BiQuadCoeffs {
     a1: f32,
     a2: f32,
@@ -23,27 +23,36 @@ impl BiQuadCoeffs {
     }
 }
 
+// Direct form I, so the delays hold past inputs and outputs rather than a state scaled by the
+// coefficients, and stay meaningful when the coefficients change.
+#[derive(Clone)]
 pub struct BiQuad {
     coeffs: BiQuadCoeffs,
-    wn1: f32,
-    wn2: f32
+    xn1: f32,
+    xn2: f32,
+    yn1: f32,
+    yn2: f32
 }
 
 impl BiQuad {
     pub fn new(coeffs: BiQuadCoeffs) -> BiQuad {
         BiQuad {
             coeffs,
-            wn1: 0.0,
-            wn2: 0.0
+            xn1: 0.0,
+            xn2: 0.0,
+            yn1: 0.0,
+            yn2: 0.0
         }
     }
 
     pub fn filter(&mut self, x: f32) -> f32 {
-        let wn = x - self.coeffs.a1 * self.wn1 - self.coeffs.a2 * self.wn2;
-        let y = self.coeffs.b0 * wn + self.coeffs.b1 * self.wn1 + self.coeffs.b2 * self.wn2;
+        let c = &self.coeffs;
+        let y = c.b0 * x + c.b1 * self.xn1 + c.b2 * self.xn2 - c.a1 * self.yn1 - c.a2 * self.yn2;
         // shift delays
-        self.wn2 = self.wn1;
-        self.wn1 = wn;
+        self.xn2 = self.xn1;
+        self.xn1 = x;
+        self.yn2 = self.yn1;
+        self.yn1 = y;
         y
     }
 }
@@ -77,6 +86,7 @@ pub fn lpf(freq: f32, rate: f32) -> BiQuadCoeffs {
     BiQuadCoeffs::new(1.0 - eat, 0.0, 0.0, -eat, 0.0)
 }
 
+#[derive(Clone)]
 pub struct Filter {
     quads: Vec<BiQuad>
 }
@@ -95,19 +105,35 @@ impl Filter {
     pub fn filter(&mut self, x: f32) -> f32 {
         self.quads.iter_mut().fold(x, |yn, quad| quad.filter(yn))
     }
+
+    // Changes the coefficients of each quad in place, keeping the delays.
+    pub fn set_cfg<I: IntoIterator<Item = BiQuadCoeffs>>(&mut self, quads: I) {
+        for (quad, coeffs) in self.quads.iter_mut().zip(quads) {
+            quad.coeffs = coeffs;
+        }
+    }
+
+    // Takes over the delays of a filter of the same order, so swapping it in carries on from
+    // where the old one was rather than from silence.
+    pub fn continue_from(&mut self, other: &Filter) {
+        for (quad, old) in self.quads.iter_mut().zip(other.quads.iter()) {
+            quad.xn1 = old.xn1;
+            quad.xn2 = old.xn2;
+            quad.yn1 = old.yn1;
+            quad.yn2 = old.yn2;
+        }
+    }
 }
 
-fn analog_coeffs(n: u32) -> Vec<AnalogBiQuadCoeffs> {
-    let mut coeffs = Vec::<AnalogBiQuadCoeffs>::with_capacity(((n + 1) / 2) as usize);
-    for k in 0..n / 2 {
+// Computed as they are taken, so a filter can be redesigned without allocating.
+fn analog_coeffs(n: u32) -> impl Iterator<Item = AnalogBiQuadCoeffs> {
+    let pairs = (0..n / 2).map(move |k| {
         let frac = (2.0 * (k + 1) as f32 + n as f32 - 1.0) * f32::consts::PI / (2.0 * n as f32);
         let mv = -2.0 * f32::cos(frac);
-        coeffs.push(AnalogBiQuadCoeffs::new(0.0, 0.0, 1.0 / f32::sqrt(2.0), 1.0, mv, 1.0));
-    }
-    if n % 2 > 0 {
-        coeffs.push(AnalogBiQuadCoeffs::new(0.0, 0.0, 1.0 / f32::sqrt(2.0), 0.0, 1.0, 1.0));
-    }
-    coeffs
+        AnalogBiQuadCoeffs::new(0.0, 0.0, 1.0 / f32::sqrt(2.0), 1.0, mv, 1.0)
+    });
+    let single = (0..n % 2).map(|_| AnalogBiQuadCoeffs::new(0.0, 0.0, 1.0 / f32::sqrt(2.0), 0.0, 1.0, 1.0));
+    pairs.chain(single)
 }
 
 fn digitise_biquad(c: &AnalogBiQuadCoeffs, warp: f32) -> BiQuadCoeffs {
@@ -122,18 +148,16 @@ fn digitise_biquad(c: &AnalogBiQuadCoeffs, warp: f32) -> BiQuadCoeffs {
 }
 
 pub fn butterworth_lpf(order: u32, cutoff: f32, sampling_freq: f32) -> Vec<BiQuadCoeffs> {
+    butterworth_lpf_iter(order, cutoff, sampling_freq).collect()
+}
+
+pub fn butterworth_lpf_iter(order: u32, cutoff: f32, sampling_freq: f32) -> impl Iterator<Item = BiQuadCoeffs> {
     // denominator. Numerator = cutoff.
     let coeffs = analog_coeffs(order);
 
-    println!("Synth Coeffs: {:?}", coeffs);
-
     let warp = 1.0 / f32::tan(cutoff / (2.0 * sampling_freq));
-    println!("Synth cutoff {:?} freq: {:?} warp {:?}", cutoff, sampling_freq, warp);
-
-    let biquads = coeffs.iter().map(|c| digitise_biquad(c, warp)).collect();
-    println!("Synth Biquads: {:?}", biquads);
 
-    biquads
+    coeffs.map(move |c| digitise_biquad(&c, warp))
 }
 
 // TODO Add Filter and Filter ADSR
@@ -147,3 +171,29 @@ pub fn butterworth_lpf(order: u32, cutoff: f32, sampling_freq: f32) -> Vec<BiQua
 
 // TODO Also add reverb
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lowpass(cutoff: f32) -> Filter {
+        Filter::from_cfg(butterworth_lpf(4, 2.0 * f32::consts::PI * cutoff, 48000.0))
+    }
+
+    #[test]
+    fn swapped_filters_carry_on() {
+        let mut old = lowpass(1000.0);
+        for _ in 0..48000 {
+            old.filter(1.0);
+        }
+        let level = old.filter(1.0);
+        assert!(level > 0.1);
+
+        // Low pass filters settle to the same level for a steady input whatever the cutoff, so
+        // one carrying on stays near it, while a fresh one starts again from silence.
+        let mut carried = lowpass(2000.0);
+        carried.continue_from(&old);
+        assert!((carried.filter(1.0) - level).abs() < level * 1e-3);
+        assert!(lowpass(2000.0).filter(1.0) < level * 0.1);
+    }
+}
@@ -1,14 +1,27 @@
 
 use std::f32;
-use synth::module::Module;
+use std::mem;
 
+use std::sync::Arc;
+
+use lv2::log::{LogLevel, Logger};
 use lv2::midi;
-use lv2_raw::midi as raw_midi;
 
-mod filter;
+pub mod filter;
 mod module;
 mod oscillator;
 mod envelope;
+mod panner;
+mod voice;
+pub mod params;
+pub mod patch;
+pub mod presets;
+pub mod registry;
+pub mod transport;
+pub mod worker;
+
+pub use self::module::{Mix, PatchPoint};
+pub use self::voice::Voicing;
 
 pub struct SynthEvent {
     time_frames: i64,
@@ -26,32 +39,110 @@ impl SynthEvent {
 
 pub enum SynthEventBody {
     MidiData(midi::MidiEvent),
-    SynthProperties(Vec<SynthProperty>),
+    SynthProperty(SynthProperty),
 }
 
 #[derive(Debug)]
+#[derive(PartialEq)]
 pub enum SynthProperty {
     Frame(i64),
     Speed(f32),
+    Bpm(f32),
+    Bar(i64),
+    BarBeat(f32),
+    BeatsPerBar(f32),
+    BeatUnit(i32),
     Waveform(f32),
     Secondary(f32, f32, f32),
     Envelope(f32, f32, f32, f32),
     FilterFreq(f32),
-    FilterOn(bool)
+    FilterOn(bool),
+    Pan(f32, f32),
+    Glide(f32),
+    // Level of the audio input in the output, and its depth as FM for the oscillator.
+    Input(f32, f32),
+    SetParameter(usize, params::ParamValue),
+    GetParameter(Option<usize>),
+}
+
+// Jobs which can wait for the worker at any one time.
+const JOB_QUEUE_SIZE: usize = 16;
+
+// Notes which can be held at once.
+const MAX_HELD_NOTES: usize = 128;
+
+// Events which can wait for their block. Any more are dropped, as making room would allocate.
+const EVENT_QUEUE_SIZE: usize = 256;
+
+// Maps the 0-1 filter control to a cutoff between 20Hz and 20kHz.
+pub fn filter_cutoff(control: f32, rate: f32) -> f32 {
+    (20.0 * (1000.0 as f32).powf(control)).min(rate * 0.45)
+}
+
+// A rack connector reached by name, found again whenever the voices are rebuilt.
+struct Patched {
+    name: (String, String),
+    point: Option<PatchPoint>,
 }
 
 pub struct ToneIterator {
     rate: f32,
-    rack: module::Rack,
+    voicing: Voicing,
+    patch: patch::Patch,
+    voices: Vec<voice::Voice>,
+    // Values last fed to every voice's buffer, to feed again to new voices.
+    buffer_values: Vec<f32>,
+    // Notes held down in the order they were pressed, for last note priority.
+    held: Vec<u8>,
+    // Events waiting for the block they fall in, in time order, with times counted from the start
+    // of the next block.
+    events: Vec<SynthEvent>,
+    // Frame of the block the event being applied falls at.
+    event_frame: usize,
+    // Notes started so far, to find the oldest voice.
+    notes_started: u64,
+    // Glide time in seconds.
+    glide: f32,
+    // Longest block feed accepts, and the output buffers preallocated to that length.
+    max_block: usize,
+    out_left: Vec<f32>,
+    out_right: Vec<f32>,
+    // Audio input for the next block, if the host gave any.
+    in_left: Vec<f32>,
+    in_right: Vec<f32>,
+    in_mid: Vec<f32>,
+    // Beat phase for each sample of the block.
+    beat_phase: Vec<f32>,
+    has_input: bool,
+    input_level: f32,
+    // Rack inputs fed by the host, with how they join their cables, and outputs tapped for it,
+    // mixed across voices, after each feed.
+    inputs: Vec<(Patched, module::Mix)>,
+    taps: Vec<(Patched, Vec<f32>)>,
+    params: params::Params,
+    transport: transport::Transport,
+    // One filter per channel.
+    filter: Option<Box<[filter::Filter; 2]>>,
+    filter_on: bool,
+    filter_freq: f32,
+    // Control values being played, whether from the control ports or a preset, and the preset
+    // last chosen by program change, for saving.
+    controls: presets::Controls,
+    preset: Option<u8>,
+    // Control value and rate of the filter the worker is designing, if it is designing one.
+    filter_designing: Option<(f32, f32)>,
+    // Without a worker, filters are designed here in place of the one playing, which is then
+    // kept for the next design rather than freed in the audio thread.
+    spare_filter: Option<Box<[filter::Filter; 2]>>,
+    has_worker: bool,
+    jobs: Vec<worker::Job>,
+    logger: Arc<Logger>,
 }
 
+// The engine's modules, which come first in every rack.
 enum Modules {
     Buffer,
-    DepthAttenuverter,
-    VelocityAttenuverter,
-    Oscillator,
-    FmOscillator,
-    Envelope,
+    AudioIn,
 }
 
 enum DataItems {
@@ -67,171 +158,1061 @@ enum DataItems {
     NoteFreq = 9,
     NoteVelocity = 10,
     NoteTrigger = 11,
-    Output = 12,
-    Len = 13
+    OutputLeft = 12,
+    TransportBpm = 13,
+    TransportBeatPhase = 14,
+    OutputRight = 15,
+    Pan = 16,
+    Spread = 17,
+    InputFm = 18,
+    Len = 19
+}
+
+// Names of the buffer items, in DataItems order.
+const BUFFER_ITEMS: [&str; DataItems::Len as usize] = [
+    "envelope_attack",
+    "envelope_decay",
+    "envelope_sustain",
+    "envelope_release",
+    "filter_frequency",
+    "waveform_type",
+    "sec_waveform_type",
+    "sec_waveform_depth",
+    "sec_waveform_freq",
+    "note_freq",
+    "note_velocity",
+    "note_trigger",
+    "output_left",
+    "transport_bpm",
+    "transport_beat_phase",
+    "output_right",
+    "pan",
+    "spread",
+    "input_fm",
+];
+
+fn buffer_default(item: usize) -> f32 {
+    if item == DataItems::TransportBpm as usize {
+        120.0
+    } else {
+        0.0
+    }
+}
+
+// Outputs of the audio input module.
+enum AudioIn {
+    Left = 0,
+    Right = 1,
+    Mid = 2,
+}
+
+const AUDIO_IN_ITEMS: [&str; 3] = ["left", "right", "mid"];
+
+fn build_rack(rate: f32, max_block: usize, patch: &patch::Patch) -> Result<module::Rack, module::RackError> {
+    let mut modules: Vec<module::ModuleInfo> = registry::ENGINE_MODULES.iter()
+        .map(|t| module::ModuleInfo::new(t.name, t.name, t.build(rate)))
+        .collect();
+    modules.extend(patch.build_modules(rate)?);
+    let mut rack = module::Rack::new(modules, patch.cables.clone())?;
+    rack.reserve(max_block);
+    Ok(rack)
+}
+
+fn build_voices(rate: f32, max_block: usize, count: usize, patch: &patch::Patch) -> Result<Vec<voice::Voice>, module::RackError> {
+    (0..count).map(|_| build_rack(rate, max_block, patch).map(|rack| voice::Voice::new(rack, max_block))).collect()
+}
+
+// Finds a connector in a rack, if the rack has it.
+fn resolve(rack: &module::Rack, connector: &(String, String), direction: module::Direction) -> Option<PatchPoint> {
+    let (ref module, ref name) = *connector;
+    rack.patch_point(module, name, direction)
 }
 
 impl ToneIterator {
-    pub fn new(rate: f32) -> ToneIterator {
-        let mut buffer_items = vec![
-            module::DataIn::new(String::from("envelope_attack"), 0.0),
-            module::DataIn::new(String::from("envelope_decay"), 0.0),
-            module::DataIn::new(String::from("envelope_sustain"), 0.0),
-            module::DataIn::new(String::from("envelope_release"), 0.0),
-            module::DataIn::new(String::from("filter_frequency"), 0.0),
-            module::DataIn::new(String::from("waveform_type"), 0.0),
-            module::DataIn::new(String::from("sec_waveform_type"), 0.0),
-            module::DataIn::new(String::from("sec_waveform_depth"), 0.0),
-            module::DataIn::new(String::from("sec_waveform_freq"), 0.0),
-            module::DataIn::new(String::from("note_freq"), 0.0),
-            module::DataIn::new(String::from("note_velocity"), 0.0),
-            module::DataIn::new(String::from("note_trigger"), 0.0),
-            module::DataIn::new(String::from("output"), 0.0)
-        ];
-        let mut modules = vec![
-            module::ModuleInfo::new("buffer", Box::new(module::BufferModule::new(buffer_items))),
-            module::ModuleInfo::new("depth_attenuverter", Box::new(module::Attenuverter::new())),
-            module::ModuleInfo::new("velocity_attenuverter", Box::new(module::Attenuverter::new())),
-            module::ModuleInfo::new("primary_osc", Box::new(oscillator::Oscillator::new(rate))),
-            module::ModuleInfo::new("secondary_osc", Box::new(oscillator::Oscillator::new(rate))),
-            module::ModuleInfo::new("envelope", Box::new(envelope::Envelope::new(rate))),
-        ];
-        let buffer_env_attack = module::ConnectorInfo::new("buffer", "envelope_attack");
-        let buffer_env_decay = module::ConnectorInfo::new("buffer", "envelope_decay");
-        let buffer_env_sustain = module::ConnectorInfo::new("buffer", "envelope_sustain");
-        let buffer_env_release = module::ConnectorInfo::new("buffer", "envelope_release");
-        let buffer_filter_freq = module::ConnectorInfo::new("buffer", "filter_frequency");
-        let buffer_waveform_type = module::ConnectorInfo::new("buffer", "waveform_type");
-        let buffer_sec_waveform_type = module::ConnectorInfo::new("buffer", "sec_waveform_type");
-        let buffer_sec_waveform_depth = module::ConnectorInfo::new("buffer", "sec_waveform_depth");
-        let buffer_sec_waveform_freq = module::ConnectorInfo::new("buffer", "sec_waveform_freq");
-        let buffer_note_freq = module::ConnectorInfo::new("buffer", "note_freq");
-        let buffer_note_velocity = module::ConnectorInfo::new("buffer", "note_velocity");
-        let buffer_note_trigger = module::ConnectorInfo::new("buffer", "note_trigger");
-        let buffer_output = module::ConnectorInfo::new("buffer", "output");
-
-        let depth_attenuverter_attenuation = module::ConnectorInfo::new("depth_attenuverter", "attenuation");
-        let depth_attenuverter_signal = module::ConnectorInfo::new("depth_attenuverter", "signal");
-        let depth_attenuverter_out = module::ConnectorInfo::new("depth_attenuverter", "output");
-
-        let velocity_attenuverter_attenuation = module::ConnectorInfo::new("velocity_attenuverter", "attenuation");
-        let velocity_attenuverter_signal = module::ConnectorInfo::new("velocity_attenuverter", "signal");
-        let velocity_attenuverter_out = module::ConnectorInfo::new("velocity_attenuverter", "output");
-
-        let osc_freq_in = module::ConnectorInfo::new("primary_osc", "freq_in");
-        let osc_duty_cycle_in = module::ConnectorInfo::new("primary_osc", "duty_cycle_in");
-        let osc_fm_in = module::ConnectorInfo::new("primary_osc", "fm_in");
-        let osc_primary = module::ConnectorInfo::new("primary_osc", "primary");
-        let osc_out = module::ConnectorInfo::new("primary_osc", "output");
-
-        let fm_osc_freq_in = module::ConnectorInfo::new("secondary_osc", "freq_in");
-        let fm_osc_duty_cycle_in = module::ConnectorInfo::new("secondary_osc", "duty_cycle_in");
-        let fm_osc_fm_in = module::ConnectorInfo::new("secondary_osc", "fm_in");
-        let fm_osc_primary = module::ConnectorInfo::new("secondary_osc", "primary");
-        let fm_osc_out = module::ConnectorInfo::new("secondary_osc", "output");
-
-        let envelope_attack = module::ConnectorInfo::new("envelope", "attack");
-        let envelope_decay = module::ConnectorInfo::new("envelope", "decay");
-        let envelope_sustain = module::ConnectorInfo::new("envelope", "sustain");
-        let envelope_release = module::ConnectorInfo::new("envelope", "release");
-        let envelope_signal = module::ConnectorInfo::new("envelope", "signal");
-        let envelope_trigger = module::ConnectorInfo::new("envelope", "trigger");
-        let envelope_out = module::ConnectorInfo::new("envelope", "output");
-
-        let mut connections = vec![
-            module::ConnectionInfo::new(buffer_env_attack, envelope_attack),
-            module::ConnectionInfo::new(buffer_env_decay, envelope_decay),
-            module::ConnectionInfo::new(buffer_env_sustain, envelope_sustain),
-            module::ConnectionInfo::new(buffer_env_release, envelope_release),
-
-            module::ConnectionInfo::new(buffer_note_trigger, envelope_trigger),
-            // TODO Attach the filter
-            module::ConnectionInfo::new(buffer_waveform_type, osc_primary),
-            module::ConnectionInfo::new(buffer_note_freq, osc_freq_in),
-
-            module::ConnectionInfo::new(buffer_sec_waveform_depth, depth_attenuverter_attenuation),
-            module::ConnectionInfo::new(buffer_sec_waveform_freq, fm_osc_freq_in),
-            module::ConnectionInfo::new(buffer_sec_waveform_type, fm_osc_primary),
-            module::ConnectionInfo::new(fm_osc_out, depth_attenuverter_signal),
-
-            module::ConnectionInfo::new(depth_attenuverter_out, osc_fm_in),
-
-            module::ConnectionInfo::new(buffer_note_velocity, velocity_attenuverter_attenuation),
-            module::ConnectionInfo::new(osc_out, velocity_attenuverter_signal),
-
-            module::ConnectionInfo::new(velocity_attenuverter_out, envelope_signal),
-
-            module::ConnectionInfo::new(envelope_out, buffer_output)
-        ];
-        let mut ti = ToneIterator {
+    pub fn new(rate: f32, max_block: usize, voicing: Voicing, logger: Arc<Logger>) -> ToneIterator {
+        let patch = patch::Patch::factory();
+        ToneIterator {
             rate,
-            rack: module::Rack::new(modules, connections)
-        };
+            voicing,
+            voices: build_voices(rate, max_block, voicing.voice_count(), &patch).expect("factory patch builds"),
+            patch,
+            buffer_values: (0..DataItems::Len as usize).map(buffer_default).collect(),
+            held: Vec::with_capacity(MAX_HELD_NOTES),
+            events: Vec::with_capacity(EVENT_QUEUE_SIZE),
+            event_frame: 0,
+            notes_started: 0,
+            glide: 0.0,
+            max_block,
+            out_left: Vec::with_capacity(max_block),
+            out_right: Vec::with_capacity(max_block),
+            in_left: Vec::with_capacity(max_block),
+            in_right: Vec::with_capacity(max_block),
+            in_mid: Vec::with_capacity(max_block),
+            beat_phase: Vec::with_capacity(max_block),
+            has_input: false,
+            input_level: 0.0,
+            inputs: Vec::new(),
+            taps: Vec::new(),
+            params: params::Params::new(),
+            transport: transport::Transport::new(),
+            filter: None,
+            filter_on: false,
+            // Not a valid control value, so the first one designs a filter.
+            filter_freq: -1.0,
+            filter_designing: None,
+            controls: presets::PRESETS[0].controls,
+            preset: None,
+            spare_filter: None,
+            has_worker: true,
+            jobs: Vec::with_capacity(JOB_QUEUE_SIZE),
+            logger,
+        }
+    }
 
-        ti
+    pub fn param(&self, param: usize) -> &params::ParamValue {
+        self.params.get(param)
     }
 
-    fn get_buffer<'a>(&'a mut self) -> &'a mut module::Module {
-        self.rack.get(0)
+    // Sets a parameter from outside the event stream, and reports the new value back.
+    pub fn set_param(&mut self, param: usize, value: params::ParamValue) {
+        self.params.set(param, value);
+        self.params.request(param);
     }
 
-    pub fn add_data(&mut self, events: Vec<SynthEvent>) {
-        let buffer = self.get_buffer();
-        for data in events.as_slice() { match &data.body {
-            &SynthEventBody::SynthProperties(ref p) => {
-                for prop in p {
-                    match prop {
-                        &SynthProperty::Frame(f) => {}
-                        &SynthProperty::Speed(spd) => {}
-                        &SynthProperty::Waveform(wave) => {
-                            buffer.feed(DataItems::WaveformType as usize, vec![wave]);
-                        }
-                        &SynthProperty::FilterFreq(freq) => {}
-                        &SynthProperty::FilterOn(ison) => {}
-                        &SynthProperty::Secondary(wave, depth, multiplier) => {
-                            buffer.feed(DataItems::SecWaveformType as usize, vec![wave]);
-                            buffer.feed(DataItems::SecWaveformDepth as usize, vec![depth]);
-                            buffer.feed(DataItems::SecWaveformFreq as usize, vec![multiplier]);
-                        }
-                        &SynthProperty::Envelope(a, d, s, r) => {
-                            buffer.feed(DataItems::EnvelopeAttack as usize, vec![a]);
-                            buffer.feed(DataItems::EnvelopeDecay as usize, vec![d]);
-                            buffer.feed(DataItems::EnvelopeSustain as usize, vec![s]);
-                            buffer.feed(DataItems::EnvelopeRelease as usize, vec![r]);
+    // Whether the parameter should be reported to the host. Clears the request.
+    pub fn take_param_report(&mut self, param: usize) -> bool {
+        self.params.take_requested(param)
+    }
+
+    // Asks again for a report that could not be made.
+    pub fn retry_param_report(&mut self, param: usize) {
+        self.params.request(param);
+    }
+
+    pub fn rate(&self) -> f32 {
+        self.rate
+    }
+
+    // Rebuilds the rack for a new sample rate. Sounding notes are cut off. Not realtime safe.
+    pub fn set_rate(&mut self, rate: f32) {
+        if rate == self.rate {
+            return;
+        }
+        self.rate = rate;
+        // The patch built at the old rate, so builds at this one.
+        self.voices = build_voices(rate, self.max_block, self.voicing.voice_count(), &self.patch).expect("patch builds");
+        self.held.clear();
+        self.replay_buffer();
+        // The filter was designed for the old rate, so ask for a new one.
+        if self.filter_freq >= 0.0 {
+            self.design_filter(self.filter_freq);
+        }
+    }
+
+    pub fn max_block(&self) -> usize {
+        self.max_block
+    }
+
+    // Not realtime safe.
+    pub fn set_max_block(&mut self, max_block: usize) {
+        self.max_block = max_block;
+        self.out_left.reserve(max_block);
+        self.out_right.reserve(max_block);
+        self.in_left.reserve(max_block);
+        self.in_right.reserve(max_block);
+        self.in_mid.reserve(max_block);
+        self.beat_phase.reserve(max_block);
+        for &mut (_, ref mut tapped) in &mut self.taps {
+            tapped.reserve(max_block);
+        }
+        for voice in &mut self.voices {
+            voice.reserve(max_block);
+        }
+    }
+
+    pub fn transport(&self) -> &transport::Transport {
+        &self.transport
+    }
+
+    // Voices playing a note.
+    pub fn voices(&self) -> u32 {
+        self.voices.iter().filter(|v| v.note.is_some()).count() as u32
+    }
+
+    // Changes a value in the buffer of every voice, from the frame of the event being applied.
+    fn feed_voices(&mut self, item: DataItems, value: f32) {
+        let item = item as usize;
+        self.buffer_values[item] = value;
+        for voice in &mut self.voices {
+            voice.rack.get(Modules::Buffer as usize).feed_at(item, self.event_frame, value);
+        }
+    }
+
+    // Brings new voices up to date with what has been fed to the old ones.
+    fn replay_buffer(&mut self) {
+        for voice in &mut self.voices {
+            let buffer = voice.rack.get(Modules::Buffer as usize);
+            for (item, value) in self.buffer_values.iter().enumerate() {
+                buffer.feed(item, &[*value]);
+            }
+        }
+    }
+
+    // Makes tempo and beat position available to modules through the buffer. The block's events
+    // may move the transport part way through, so the beat phase is filled in up to each of them.
+    fn start_transport(&mut self) {
+        self.beat_phase.clear();
+        let bpm = self.transport.bpm;
+        self.feed_voices(DataItems::TransportBpm, bpm);
+    }
+
+    // Advances the transport to frame of the block, filling in the beat phase up to it.
+    fn advance_transport(&mut self, frame: usize) {
+        let start = self.beat_phase.len();
+        if frame <= start {
+            return;
+        }
+        let beats_per_frame = self.transport.beats_per_frame(self.rate);
+        let phase = self.transport.beat_phase();
+        self.beat_phase.extend((0..frame - start).map(|i| (phase + i as f32 * beats_per_frame).rem_euclid(1.0)));
+        self.transport.advance(frame - start, self.rate);
+    }
+
+    fn finish_transport(&mut self, samples: usize) {
+        self.advance_transport(samples);
+        for voice in &mut self.voices {
+            voice.rack.get(0).feed(DataItems::TransportBeatPhase as usize, &self.beat_phase);
+        }
+    }
+
+    // Maps a note number to the 0-1 pitch scale used by the oscillators.
+    fn note_pitch(&self, note_num: u8) -> f32 {
+        let tuning = self.params.get_float(params::Param::Tuning);
+        // Offset in semitones from A440
+        let detune = 12.0 * (tuning / 440.0).log2();
+        (note_num as f32 + detune) / 127.0
+    }
+
+    // The voice to play a new note on: one already playing it, else the free voice that has been
+    // free longest, else the oldest.
+    fn allocate_voice(&self, note_num: u8) -> usize {
+        let playing = self.voices.iter().position(|v| v.note == Some(note_num));
+        let free = self.voices.iter().enumerate()
+            .filter(|&(_, v)| v.note.is_none())
+            .min_by_key(|&(_, v)| v.started)
+            .map(|(i, _)| i);
+        let oldest = self.voices.iter().enumerate()
+            .min_by_key(|&(_, v)| v.started)
+            .map(|(i, _)| i);
+        playing.or(free).or(oldest).unwrap_or(0)
+    }
+
+    fn note_on(&mut self, note_num: u8, velocity: u8) {
+        let pitch = self.note_pitch(note_num);
+        let velocity = (velocity as f32) / 255.0 + 0.5;
+        self.notes_started += 1;
+
+        let (index, legato) = match self.voicing {
+            Voicing::Single => (0, false),
+            Voicing::MonoLead => {
+                self.held.retain(|&n| n != note_num);
+                if self.held.len() < MAX_HELD_NOTES {
+                    self.held.push(note_num);
+                }
+                (0, self.voices[0].note.is_some())
+            }
+            Voicing::Poly(_) => (self.allocate_voice(note_num), false),
+        };
+
+        let glide = self.glide > 0.0;
+        let frame = self.event_frame;
+        let voice = &mut self.voices[index];
+        voice.note = Some(note_num);
+        voice.started = self.notes_started;
+        if legato && glide {
+            // Fed while gliding, by feed.
+            voice.glide_to(pitch, frame);
+        } else {
+            voice.set_pitch(pitch);
+            voice.rack.get(0).feed_at(DataItems::NoteFreq as usize, frame, pitch);
+        }
+        if !legato {
+            let buffer = voice.rack.get(0);
+            buffer.feed_at(DataItems::NoteTrigger as usize, frame, 1.0);
+            buffer.feed_at(DataItems::NoteVelocity as usize, frame, velocity);
+        }
+    }
+
+    fn note_off(&mut self, note_num: u8) {
+        let frame = self.event_frame;
+        match self.voicing {
+            Voicing::Single => {
+                self.voices[0].note = None;
+                self.voices[0].rack.get(0).feed_at(DataItems::NoteTrigger as usize, frame, 0.0);
+            }
+            Voicing::MonoLead => {
+                self.held.retain(|&n| n != note_num);
+                if self.voices[0].note != Some(note_num) {
+                    return;
+                }
+                match self.held.last().cloned() {
+                    Some(previous) => {
+                        let pitch = self.note_pitch(previous);
+                        let glide = self.glide > 0.0;
+                        let voice = &mut self.voices[0];
+                        voice.note = Some(previous);
+                        if glide {
+                            voice.glide_to(pitch, frame);
+                        } else {
+                            voice.set_pitch(pitch);
+                            voice.rack.get(0).feed_at(DataItems::NoteFreq as usize, frame, pitch);
                         }
                     }
+                    None => {
+                        self.voices[0].note = None;
+                        self.voices[0].rack.get(0).feed_at(DataItems::NoteTrigger as usize, frame, 0.0);
+                    }
+                }
+            }
+            Voicing::Poly(_) => {
+                for voice in self.voices.iter_mut().filter(|v| v.note == Some(note_num)) {
+                    voice.note = None;
+                    voice.rack.get(0).feed_at(DataItems::NoteTrigger as usize, frame, 0.0);
+                }
+            }
+        }
+    }
+
+    // Next job for the worker. The response must be passed back to apply.
+    pub fn next_job(&mut self) -> Option<worker::Job> {
+        self.jobs.pop()
+    }
+
+    // Puts back a job the worker could not take, to be the next one asked for.
+    pub fn retry_job(&mut self, job: worker::Job) {
+        self.jobs.push(job);
+    }
+
+    // Swaps in the result of a job. Returns whatever it replaced, which should be dropped outside
+    // the audio thread.
+    pub fn apply(&mut self, response: worker::Response) -> Option<worker::Response> {
+        match response {
+            worker::Response::Filter(mut f) => {
+                // Carried on from the old filter, so the swap does not click.
+                if let Some(ref old) = self.filter {
+                    for (new, old) in f.iter_mut().zip(old.iter()) {
+                        new.continue_from(old);
+                    }
+                }
+                // Moves made while it was designed are caught up with in one more design.
+                if self.filter_designing.take() != Some((self.filter_freq, self.rate)) {
+                    let freq = self.filter_freq;
+                    self.design_filter(freq);
+                }
+                self.filter.replace(f).map(worker::Response::Filter)
+            }
+            worker::Response::Patch(mut loaded) => {
+                if loaded.rate != self.rate || loaded.max_block < self.max_block || loaded.voices.len() != self.voices.len() {
+                    self.logger.rt(LogLevel::Warning, format_args!("Patch was built for other settings, ignored"));
+                    return Some(worker::Response::Patch(loaded));
+                }
+                self.play(&mut loaded);
+                self.logger.rt(LogLevel::Note, format_args!("Patch loaded"));
+                Some(worker::Response::Patch(loaded))
+            }
+        }
+    }
+
+    // Swaps in a patch built for the current settings, leaving the old one in loaded. Sounding
+    // notes are cut off.
+    fn play(&mut self, loaded: &mut worker::LoadedPatch) {
+        mem::swap(&mut self.patch, &mut loaded.patch);
+        mem::swap(&mut self.voices, &mut loaded.voices);
+        for &mut (ref mut input, _) in &mut self.inputs {
+            input.point = resolve(&self.voices[0].rack, &input.name, module::Direction::Input);
+        }
+        for &mut (ref mut tap, _) in &mut self.taps {
+            tap.point = resolve(&self.voices[0].rack, &tap.name, module::Direction::Output);
+            if let Some(point) = tap.point {
+                for voice in &mut self.voices {
+                    voice.rack.connect(point);
+                }
+            }
+        }
+        loaded.played = true;
+        self.held.clear();
+        self.replay_buffer();
+    }
+
+    pub fn voicing(&self) -> Voicing {
+        self.voicing
+    }
+
+    // Rebuilds the voices for another voicing. Sounding notes are cut off. Not realtime safe.
+    pub fn set_voicing(&mut self, voicing: Voicing) -> Result<(), module::RackError> {
+        if voicing == self.voicing {
+            return Ok(());
+        }
+        let mut loaded = worker::LoadedPatch::build(self.patch.clone(), self.rate, self.max_block,
+                                                    voicing.voice_count())?;
+        self.voicing = voicing;
+        self.play(&mut loaded);
+        Ok(())
+    }
+
+    pub fn controls(&self) -> &presets::Controls {
+        &self.controls
+    }
+
+    pub fn preset(&self) -> Option<u8> {
+        self.preset
+    }
+
+    // Plays the controls, as saved with the preset they were chosen by.
+    pub fn restore_controls(&mut self, controls: &presets::Controls, preset: Option<u8>) {
+        controls.changes(None, |prop| self.set_property(&prop));
+        self.preset = preset;
+    }
+
+    // The rack voices are playing, as patch text. Not realtime safe.
+    pub fn patch_text(&self) -> String {
+        patch::Patch::from_rack(&self.voices[0].rack).to_string()
+    }
+
+    // Plays the patch from now on. Sounding notes are cut off. Not realtime safe.
+    pub fn set_patch(&mut self, patch: patch::Patch) -> Result<(), module::RackError> {
+        let mut loaded = worker::LoadedPatch::build(patch, self.rate, self.max_block, self.voices.len())?;
+        self.play(&mut loaded);
+        self.logger.log(LogLevel::Note, format_args!("Patch loaded"));
+        Ok(())
+    }
+
+    // Asks the worker for a filter with the cutoff the control gives at the current rate. One
+    // design is asked for at a time, so a moving control does not flood the worker; moves made
+    // meanwhile are designed for when it is applied. Without a worker it is designed here.
+    fn design_filter(&mut self, freq: f32) {
+        self.filter_freq = freq;
+        if !self.has_worker {
+            if let (Some(filter), Some(spare)) = (self.filter.as_mut(), self.spare_filter.as_mut()) {
+                let cutoff = filter_cutoff(freq, self.rate);
+                for (new, old) in spare.iter_mut().zip(filter.iter()) {
+                    worker::redesign_lowpass(new, cutoff, self.rate);
+                    new.continue_from(old);
                 }
+                mem::swap(filter, spare);
+            }
+        } else if self.filter_designing.is_none() {
+            self.filter_designing = Some((freq, self.rate));
+            let cutoff = filter_cutoff(freq, self.rate);
+            self.jobs.push(worker::Job::DesignFilter { cutoff, rate: self.rate });
+        }
+    }
+
+    // For hosts with no worker. Filters are then designed in place, which needs no allocation,
+    // and patch files cannot be loaded. Not realtime safe.
+    pub fn without_worker(&mut self) {
+        self.has_worker = false;
+        let filter = worker::lowpass(filter_cutoff(0.0, self.rate), self.rate);
+        self.filter = Some(Box::new([filter.clone(), filter.clone()]));
+        self.spare_filter = Some(Box::new([filter.clone(), filter]));
+        if self.filter_freq >= 0.0 {
+            let freq = self.filter_freq;
+            self.design_filter(freq);
+        }
+    }
+
+    // Asks the worker to load a patch file and build voices from it.
+    pub fn load_patch(&mut self, path: worker::PatchPath) {
+        if !self.has_worker {
+            self.logger.rt(LogLevel::Error, format_args!("Loading patch files needs the host's worker"));
+            return;
+        }
+        if path.get().is_none() {
+            self.logger.rt(LogLevel::Error, format_args!("Patch path longer than {} bytes, not loaded",
+                                                        worker::PATCH_PATH_SIZE));
+            return;
+        }
+        self.jobs.retain(|j| !matches!(*j, worker::Job::LoadPatch { .. }));
+        self.jobs.push(worker::Job::LoadPatch {
+            path,
+            rate: self.rate,
+            max_block: self.max_block,
+            voices: self.voices.len(),
+        });
+    }
+
+    fn set_property(&mut self, prop: &SynthProperty) {
+        self.controls.apply(prop);
+        match *prop {
+            SynthProperty::Frame(..) | SynthProperty::Speed(..) | SynthProperty::Bpm(..) | SynthProperty::Bar(..)
+                | SynthProperty::BarBeat(..) | SynthProperty::BeatsPerBar(..) => {
+                // The transport moves from the event's frame on.
+                let frame = self.event_frame;
+                self.advance_transport(frame);
+            }
+            _ => {}
+        }
+        match *prop {
+            SynthProperty::Frame(f) => {
+                self.transport.frame = f;
+            }
+            SynthProperty::Speed(spd) => {
+                self.transport.speed = spd;
+            }
+            SynthProperty::Bpm(bpm) => {
+                self.transport.bpm = bpm;
+                self.feed_voices(DataItems::TransportBpm, bpm);
+            }
+            SynthProperty::Bar(bar) => {
+                self.transport.bar = bar;
+            }
+            SynthProperty::BarBeat(beat) => {
+                self.transport.bar_beat = beat;
+            }
+            SynthProperty::BeatsPerBar(beats) => {
+                self.transport.beats_per_bar = beats;
+            }
+            SynthProperty::BeatUnit(unit) => {
+                self.transport.beat_unit = unit;
+            }
+            SynthProperty::Waveform(wave) => {
+                self.feed_voices(DataItems::WaveformType, wave);
+            }
+            SynthProperty::FilterFreq(freq) => {
+                if freq != self.filter_freq {
+                    self.design_filter(freq);
+                }
+            }
+            SynthProperty::FilterOn(ison) => {
+                self.filter_on = ison;
+            }
+            SynthProperty::Pan(pan, spread) => {
+                self.feed_voices(DataItems::Pan, pan);
+                self.feed_voices(DataItems::Spread, spread);
+            }
+            SynthProperty::Glide(glide) => {
+                self.glide = glide;
+            }
+            SynthProperty::Input(level, fm) => {
+                self.input_level = level;
+                self.feed_voices(DataItems::InputFm, fm);
+            }
+            SynthProperty::Secondary(wave, depth, multiplier) => {
+                self.feed_voices(DataItems::SecWaveformType, wave);
+                self.feed_voices(DataItems::SecWaveformDepth, depth);
+                self.feed_voices(DataItems::SecWaveformFreq, multiplier);
+            }
+            SynthProperty::SetParameter(param, ref value) => {
+                self.params.set(param, value.clone());
+                self.params.request(param);
+            }
+            SynthProperty::GetParameter(Some(param)) => {
+                self.params.request(param);
+            }
+            SynthProperty::GetParameter(None) => {
+                self.params.request_all();
+            }
+            SynthProperty::Envelope(a, d, s, r) => {
+                self.feed_voices(DataItems::EnvelopeAttack, a);
+                self.feed_voices(DataItems::EnvelopeDecay, d);
+                self.feed_voices(DataItems::EnvelopeSustain, s);
+                self.feed_voices(DataItems::EnvelopeRelease, r);
+            }
+        }
+    }
+
+    // Sets the engine to a factory preset, until the controls next change.
+    fn load_preset(&mut self, program: u8) {
+        match presets::PRESETS.get(program as usize) {
+            Some(preset) => {
+                preset.controls.changes(None, |prop| self.set_property(&prop));
+                self.preset = Some(program);
+                self.logger.rt(LogLevel::Note, format_args!("Loaded preset {}", preset.name));
+            }
+            None => {
+                self.logger.rt(LogLevel::Warning, format_args!("No preset for program {}", program));
+            }
+        }
+    }
+
+    // Queues an event to take effect at its frame, counted from the start of the next call to
+    // feed. Events at the same frame take effect in the order they were added.
+    pub fn add_event(&mut self, event: SynthEvent) {
+        if self.events.len() == EVENT_QUEUE_SIZE {
+            self.logger.rt(LogLevel::Warning, format_args!("Event queue full, event at frame {} dropped",
+                                                          event.time_frames));
+            return;
+        }
+        let at = self.events.iter().rposition(|e| e.time_frames <= event.time_frames).map_or(0, |i| i + 1);
+        self.events.insert(at, event);
+    }
+
+    // Applies the events falling in the next block of samples, each from its frame on.
+    fn apply_events(&mut self, samples: usize) {
+        let mut events = mem::take(&mut self.events);
+        let due = events.iter().take_while(|e| e.time_frames < samples as i64).count();
+        for event in &events[..due] {
+            self.event_frame = event.time_frames.max(0) as usize;
+            self.apply_event(event);
+        }
+        self.event_frame = 0;
+        events.drain(..due);
+        for event in &mut events {
+            event.time_frames -= samples as i64;
+        }
+        self.events = events;
+    }
+
+    fn apply_event(&mut self, event: &SynthEvent) {
+        match event.body {
+            SynthEventBody::SynthProperty(ref prop) => {
+                self.set_property(prop);
             },
-            &SynthEventBody::MidiData(ref midi_ev) => {
-                match midi_ev {
-                    &midi::MidiEvent::NoteOn { note_num, velocity } => {
-                        let note = note_num as f32 / 127.0;
-                        // TODO Create vectors with offset of old values, then the new values until
-                        // the next note.
-                        let offset = data.time_frames;
-                        buffer.feed(DataItems::NoteFreq as usize, vec![note]);
-                        buffer.feed(DataItems::NoteTrigger as usize, vec![1.0]);
-                        buffer.feed(DataItems::NoteVelocity as usize, vec![(velocity as f32) / 255.0 + 0.5]);
+            SynthEventBody::MidiData(ref midi_ev) => {
+                match *midi_ev {
+                    midi::MidiEvent::NoteOn { note_num, velocity } => {
+                        self.note_on(note_num, velocity);
+                    },
+                    midi::MidiEvent::NoteOff { note_num, .. } => {
+                        self.note_off(note_num);
                     },
-                    &midi::MidiEvent::NoteOff { note_num, velocity } => {
-                        buffer.feed(DataItems::NoteTrigger as usize, vec![0.0]);
+                    midi::MidiEvent::ProgramChange { num } => {
+                        self.load_preset(num);
                     },
                     _ => {
-                        println!("MIDI {:?} @{}", midi_ev, data.time_frames);
+                        self.logger.rt(LogLevel::Trace, format_args!("MIDI {:?} @{}", midi_ev, self.event_frame));
                     }
                 }
             },
-        } }
+        }
+    }
+
+    // Sets the audio input for the next call to feed, which should be for the same number of
+    // samples. Without it the input is silent.
+    pub fn feed_input(&mut self, left: &[f32], right: &[f32]) {
+        let samples = left.len().min(right.len()).min(self.max_block);
+        self.in_left.clear();
+        self.in_left.extend_from_slice(&left[..samples]);
+        self.in_right.clear();
+        self.in_right.extend_from_slice(&right[..samples]);
+        self.has_input = true;
 
+        self.in_mid.clear();
+        self.in_mid.extend(self.in_left.iter().zip(self.in_right.iter()).map(|(l, r)| (l + r) * 0.5));
+        for voice in &mut self.voices {
+            let audio_in = voice.rack.get(Modules::AudioIn as usize);
+            audio_in.feed(AudioIn::Left as usize, &self.in_left);
+            audio_in.feed(AudioIn::Right as usize, &self.in_right);
+            audio_in.feed(AudioIn::Mid as usize, &self.in_mid);
+        }
+    }
+
+    // Registers a rack input for the host to feed by the returned index, joined with whatever is
+    // cabled to it as mix says. It is found again by name whenever a patch is loaded, and is not
+    // fed if the patch lacks it. Not realtime safe.
+    pub fn patch_input(&mut self, module: &str, input: &str, mix: Mix) -> usize {
+        let name = (String::from(module), String::from(input));
+        let point = resolve(&self.voices[0].rack, &name, module::Direction::Input);
+        self.inputs.push((Patched { name, point }, mix));
+        self.inputs.len() - 1
+    }
+
+    // Feeds a rack input in every voice for the next call to feed.
+    pub fn feed_patch_input(&mut self, input: usize, v: &[f32]) {
+        let (ref patched, mix) = self.inputs[input];
+        if let Some(point) = patched.point {
+            for voice in &mut self.voices {
+                voice.rack.feed_external(point, &v[..v.len().min(self.max_block)], mix);
+            }
+        }
+    }
+
+    // Starts collecting a rack output, returning the index to read it by. Not realtime safe.
+    pub fn tap(&mut self, module: &str, output: &str) -> usize {
+        let name = (String::from(module), String::from(output));
+        let point = resolve(&self.voices[0].rack, &name, module::Direction::Output);
+        if let Some(point) = point {
+            for voice in &mut self.voices {
+                voice.rack.connect(point);
+            }
+        }
+        self.taps.push((Patched { name, point }, Vec::with_capacity(self.max_block)));
+        self.taps.len() - 1
+    }
+
+    // A tapped output from the last call to feed, mixed across voices at the same 1/sqrt(voices)
+    // scale as the audio output. Silent if the patch lacks it.
+    pub fn tapped(&self, tap: usize) -> &[f32] {
+        &self.taps[tap].1
     }
 
-    pub fn feed(&mut self, samples: usize) -> Vec<f32> {
-        self.rack.feed_all(samples);
+    // Renders at most max_block samples into the preallocated left and right buffers.
+    pub fn feed(&mut self, samples: usize) -> (&[f32], &[f32]) {
+        let samples = samples.min(self.max_block);
+        self.start_transport();
+        self.apply_events(samples);
+        let dropped: usize = self.voices.iter_mut().map(|v| v.rack.get(Modules::Buffer as usize).take_dropped()).sum();
+        if dropped > 0 {
+            self.logger.rt(LogLevel::Warning, format_args!("Too many changes in one block, {} dropped", dropped));
+        }
+        self.finish_transport(samples);
+
+        self.out_left.clear();
+        self.out_left.resize(samples, 0.0);
+        self.out_right.clear();
+        self.out_right.resize(samples, 0.0);
+        for &mut (_, ref mut tapped) in &mut self.taps {
+            tapped.clear();
+            tapped.resize(samples, 0.0);
+        }
+
+        // Voices are scaled by 1/sqrt(voices), so chords keep about the headroom of one note.
+        let scale = 1.0 / (self.voices.len() as f32).sqrt();
+        let gain = self.params.get_float(params::Param::Gain) * scale;
+        let coeff = (-1.0 / (self.glide * self.rate)).exp();
+        for voice in &mut self.voices {
+            voice.glide(samples, coeff, DataItems::NoteFreq as usize);
+            voice.rack.feed_all(samples);
+
+            for &mut (ref tap, ref mut tapped) in &mut self.taps {
+                if let Some(output) = tap.point {
+                    let v = voice.rack.output(output, samples);
+                    for (out, v) in tapped.iter_mut().zip(v.iter().cycle()) {
+                        *out += v * scale;
+                    }
+                }
+            }
+
+            if self.has_input {
+                // The buffer repeats what it was last given, so silence the input until the
+                // next feed_input.
+                let audio_in = voice.rack.get(Modules::AudioIn as usize);
+                for item in &[AudioIn::Left as usize, AudioIn::Right as usize, AudioIn::Mid as usize] {
+                    audio_in.feed(*item, &[0.0]);
+                }
+            }
+
+            let left = PatchPoint::new(Modules::Buffer as usize, DataItems::OutputLeft as usize);
+            for (out, v) in self.out_left.iter_mut().zip(voice.rack.output(left, samples).iter().cycle()) {
+                *out += v * gain;
+            }
+            let right = PatchPoint::new(Modules::Buffer as usize, DataItems::OutputRight as usize);
+            for (out, v) in self.out_right.iter_mut().zip(voice.rack.output(right, samples).iter().cycle()) {
+                *out += v * gain;
+            }
+        }
 
-        let buffer = self.get_buffer();
+        if self.has_input {
+            let level = self.input_level;
+            for (out, v) in self.out_left.iter_mut().zip(self.in_left.iter()) {
+                *out += v * level;
+            }
+            for (out, v) in self.out_right.iter_mut().zip(self.in_right.iter()) {
+                *out += v * level;
+            }
+            self.has_input = false;
+        }
 
-        buffer.extract(DataItems::Output as usize, samples)
+        if let (true, Some(f)) = (self.filter_on, self.filter.as_mut()) {
+            let [ref mut fl, ref mut fr] = **f;
+            self.out_left.iter_mut().for_each(|v| *v = fl.filter(*v));
+            self.out_right.iter_mut().for_each(|v| *v = fr.filter(*v));
+        }
+        (&self.out_left, &self.out_right)
     }
 
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::allocations;
+
+    const BLOCK: usize = 256;
+
+    // A synth playing the Init preset changed by props, with any worker jobs done.
+    fn synth(voicing: Voicing, props: Vec<SynthProperty>) -> ToneIterator {
+        let mut synth = ToneIterator::new(48000.0, 64, voicing, Arc::new(Logger::new(None, None)));
+        synth.set_max_block(BLOCK);
+        let mut init = Vec::new();
+        presets::PRESETS[0].controls.changes(None, |prop| init.push(prop));
+        init.extend(props);
+        set_at(&mut synth, 0, init);
+        synth.feed(BLOCK);
+        while let Some(job) = synth.next_job() {
+            synth.apply(job.run().unwrap());
+        }
+        synth
+    }
+
+    fn set_at(synth: &mut ToneIterator, frame: i64, props: Vec<SynthProperty>) {
+        for prop in props {
+            synth.add_event(SynthEvent::new(frame, SynthEventBody::SynthProperty(prop)));
+        }
+    }
+
+    fn note_on(synth: &mut ToneIterator, note_num: u8) {
+        note_on_at(synth, note_num, 0);
+    }
+
+    fn note_on_at(synth: &mut ToneIterator, note_num: u8, frame: i64) {
+        let on = midi::MidiEvent::NoteOn { note_num, velocity: 100 };
+        synth.add_event(SynthEvent::new(frame, SynthEventBody::MidiData(on)));
+    }
+
+    // Feeds blocks of every length up to BLOCK, with audio input, counting the input and the feeds.
+    fn feed_allocations(synth: &mut ToneIterator) -> usize {
+        let input: Vec<f32> = (0..BLOCK).map(|i| (i as f32 * 0.01).sin()).collect();
+        let mut total = 0;
+        for &samples in &[BLOCK, 1, 100, BLOCK, 17, BLOCK] {
+            total += allocations(|| {
+                synth.feed_input(&input[..samples], &input[..samples]);
+                synth.feed(samples);
+            });
+        }
+        total
+    }
+
+    #[test]
+    fn poly_feed_does_not_allocate() {
+        let mut synth = synth(Voicing::Poly(4), vec![
+            SynthProperty::Secondary(0.3, 0.5, 0.6),
+            SynthProperty::Input(0.2, 0.4),
+            SynthProperty::FilterFreq(0.5),
+            SynthProperty::FilterOn(true),
+            SynthProperty::Pan(0.2, 0.6),
+        ]);
+        note_on(&mut synth, 60);
+        note_on(&mut synth, 64);
+        note_on(&mut synth, 67);
+        assert_eq!(feed_allocations(&mut synth), 0);
+    }
+
+    #[test]
+    fn gliding_feed_does_not_allocate() {
+        let mut synth = synth(Voicing::MonoLead, vec![SynthProperty::Glide(0.2)]);
+        note_on(&mut synth, 48);
+        synth.feed(BLOCK);
+        note_on(&mut synth, 72);
+        assert_eq!(feed_allocations(&mut synth), 0);
+    }
+
+    #[test]
+    fn patch_inputs_and_taps_do_not_allocate() {
+        let mut synth = synth(Voicing::Poly(2), vec![]);
+        let pan = synth.patch_input("panner", "pan", Mix::Add);
+        let envelope = synth.tap("envelope", "output");
+        note_on(&mut synth, 60);
+        let sweep: Vec<f32> = (0..BLOCK).map(|i| i as f32 / BLOCK as f32 - 0.5).collect();
+        let mut total = 0;
+        for _ in 0..4 {
+            total += allocations(|| {
+                synth.feed_patch_input(pan, &sweep);
+                synth.feed(BLOCK);
+            });
+        }
+        assert_eq!(total, 0);
+        assert!(synth.tapped(envelope).iter().any(|v| *v != 0.0));
+    }
+
+    #[test]
+    fn events_change_values_at_their_frame() {
+        let mut synth = synth(Voicing::Poly(1), vec![]);
+        let trigger = synth.tap("buffer", "note_trigger");
+        let envelope = synth.tap("envelope", "output");
+        note_on_at(&mut synth, 60, 200);
+        let off = midi::MidiEvent::NoteOff { note_num: 60, velocity: 0 };
+        synth.add_event(SynthEvent::new(300, SynthEventBody::MidiData(off)));
+
+        synth.feed(BLOCK);
+        let tapped = synth.tapped(trigger);
+        assert!(tapped[..200].iter().all(|v| *v == 0.0));
+        assert!(tapped[200..].iter().all(|v| *v == 1.0));
+        assert!(synth.tapped(envelope)[..200].iter().all(|v| *v == 0.0));
+        assert!(synth.tapped(envelope)[200..].iter().any(|v| *v != 0.0));
+
+        // Later events wait for the block they fall in, and values hold between blocks.
+        synth.feed(BLOCK);
+        let tapped = synth.tapped(trigger);
+        assert!(tapped[..300 - BLOCK].iter().all(|v| *v == 1.0));
+        assert!(tapped[300 - BLOCK..].iter().all(|v| *v == 0.0));
+        synth.feed(BLOCK);
+        assert!(synth.tapped(trigger).iter().all(|v| *v == 0.0));
+    }
+
+    #[test]
+    fn position_changes_apply_at_their_frame() {
+        let mut synth = synth(Voicing::Poly(1), vec![]);
+        let phase = synth.tap("buffer", "transport_beat_phase");
+        let bpm = synth.tap("buffer", "transport_bpm");
+        let position = vec![SynthProperty::Speed(1.0), SynthProperty::Bpm(60.0), SynthProperty::BarBeat(0.5)];
+        set_at(&mut synth, 100, position);
+
+        synth.feed(BLOCK);
+        // Stopped until the position, then playing from it at 60 bpm.
+        let beats_per_frame = 60.0 / (60.0 * 48000.0);
+        assert!(synth.tapped(phase)[..100].iter().all(|v| *v == 0.0));
+        for (i, v) in synth.tapped(phase)[100..].iter().enumerate() {
+            assert!((v - (0.5 + i as f32 * beats_per_frame)).abs() < 1e-5);
+        }
+        assert!(synth.tapped(bpm)[..100].iter().all(|v| *v == 120.0));
+        assert!(synth.tapped(bpm)[100..].iter().all(|v| *v == 60.0));
+        let expected = 0.5 + (BLOCK - 100) as f32 * beats_per_frame;
+        assert!((synth.transport().bar_beat - expected).abs() < 1e-5);
+        assert_eq!(synth.transport().frame, (BLOCK - 100) as i64);
+
+        // A bar change later in the block leaves the earlier frames playing on.
+        let bar = vec![SynthProperty::Bar(4), SynthProperty::BarBeat(0.0)];
+        set_at(&mut synth, 50, bar);
+        synth.feed(BLOCK);
+        assert!((synth.tapped(phase)[49] - (expected + 49.0 * beats_per_frame)).abs() < 1e-5);
+        assert_eq!(synth.tapped(phase)[50], 0.0);
+        assert_eq!(synth.transport().bar, 4);
+        assert!((synth.transport().bar_beat - (BLOCK - 50) as f32 * beats_per_frame).abs() < 1e-5);
+    }
+
+    #[test]
+    fn program_changes_load_presets_at_their_frame() {
+        let mut synth = synth(Voicing::Poly(1), vec![]);
+        let waveform = synth.tap("buffer", "waveform_type");
+        let program = |num: u8, frame: i64| {
+            let bytes = [0xc0, num];
+            SynthEvent::new(frame, SynthEventBody::MidiData(midi::MidiEvent::new(bytes.as_ptr(), bytes.len())))
+        };
+
+        synth.add_event(program(1, 100));
+        synth.feed(BLOCK);
+        let pad = &presets::PRESETS[1].controls;
+        assert!(synth.tapped(waveform)[..100].iter().all(|v| *v == presets::PRESETS[0].controls.waveform));
+        assert!(synth.tapped(waveform)[100..].iter().all(|v| *v == pad.waveform));
+        assert_eq!(synth.buffer_values[DataItems::EnvelopeAttack as usize], pad.attack);
+        assert_eq!(synth.buffer_values[DataItems::Spread as usize], pad.spread);
+        assert!(synth.filter_on);
+        assert_eq!(synth.filter_freq, pad.filter_freq);
+
+        // Programs past the bank leave the sound as it was.
+        let values = synth.buffer_values.clone();
+        for &num in &[presets::PRESETS.len() as u8, 127] {
+            synth.add_event(program(num, 0));
+            synth.feed(BLOCK);
+            assert_eq!(synth.buffer_values, values);
+            assert!(synth.filter_on);
+        }
+
+        synth.add_event(program(0, 0));
+        synth.feed(BLOCK);
+        assert_eq!(synth.buffer_values[DataItems::WaveformType as usize], presets::PRESETS[0].controls.waveform);
+        assert!(!synth.filter_on);
+    }
+
+    #[test]
+    fn filter_moves_wait_for_the_design_in_flight() {
+        let mut synth = synth(Voicing::Poly(1), vec![SynthProperty::FilterFreq(0.2)]);
+        assert!(synth.filter.is_some());
+        let design = |synth: &ToneIterator| synth.jobs.iter()
+            .filter_map(|j| match *j {
+                worker::Job::DesignFilter { cutoff, .. } => Some(cutoff),
+                _ => None,
+            })
+            .collect::<Vec<f32>>();
+
+        set_at(&mut synth, 0, vec![SynthProperty::FilterFreq(0.3)]);
+        synth.feed(BLOCK);
+        assert_eq!(design(&synth), vec![filter_cutoff(0.3, 48000.0)]);
+        let job = synth.next_job().unwrap();
+
+        // A control sweeping while the worker designs asks for nothing more.
+        let sweep = |i: usize| 0.4 + i as f32 * 0.01;
+        for i in 0..20 {
+            let freq = sweep(i);
+            set_at(&mut synth, 0, vec![SynthProperty::FilterFreq(freq)]);
+            synth.feed(BLOCK);
+        }
+        assert!(synth.jobs.is_empty());
+
+        // Once it arrives, the latest value is designed for, and then nothing more.
+        synth.apply(job.run().unwrap());
+        assert_eq!(design(&synth), vec![filter_cutoff(sweep(19), 48000.0)]);
+        let job = synth.next_job().unwrap();
+        synth.apply(job.run().unwrap());
+        assert!(synth.jobs.is_empty());
+        assert!(synth.filter_designing.is_none());
+    }
+
+    #[test]
+    fn silent_cv_inputs_leave_the_patch_alone() {
+        let mut plain = synth(Voicing::Poly(1), vec![]);
+        let mut fed = synth(Voicing::Poly(1), vec![]);
+        let inputs = [
+            fed.patch_input("primary_osc", "fm_in", Mix::Add),
+            fed.patch_input("envelope", "trigger", Mix::Max),
+            fed.patch_input("panner", "pan", Mix::Add),
+        ];
+        let plain_left = plain.tap("panner", "left");
+        let fed_left = fed.tap("panner", "left");
+        note_on(&mut plain, 60);
+        note_on(&mut fed, 60);
+        let silence = [0.0; BLOCK];
+        for _ in 0..4 {
+            for &input in &inputs {
+                fed.feed_patch_input(input, &silence);
+            }
+            plain.feed(BLOCK);
+            fed.feed(BLOCK);
+            assert_eq!(plain.tapped(plain_left), fed.tapped(fed_left));
+        }
+        assert!(fed.tapped(fed_left).iter().any(|v| *v != 0.0));
+    }
+
+    #[test]
+    fn asking_for_a_patch_does_not_allocate() {
+        let mut synth = synth(Voicing::Poly(2), vec![]);
+        let path = worker::PatchPath::new(b"/nowhere/test.patch");
+        assert_eq!(allocations(|| synth.load_patch(path)), 0);
+        match synth.next_job() {
+            Some(worker::Job::LoadPatch { path, voices, .. }) => {
+                assert_eq!(path.get(), Some(&b"/nowhere/test.patch"[..]));
+                assert_eq!(voices, 2);
+            }
+            _ => panic!("no patch job"),
+        }
+
+        let long = vec![b'a'; worker::PATCH_PATH_SIZE + 1];
+        synth.load_patch(worker::PatchPath::new(&long));
+        assert!(synth.next_job().is_none());
+    }
+
+    #[test]
+    fn only_played_patches_are_marked() {
+        let mut synth = synth(Voicing::Poly(2), vec![]);
+        for &(rate, played) in &[(44100.0, false), (48000.0, true)] {
+            let loaded = worker::LoadedPatch::build(patch::Patch::factory(), rate, BLOCK, 2).unwrap();
+            match synth.apply(worker::Response::Patch(Box::new(loaded))) {
+                Some(worker::Response::Patch(old)) => assert_eq!(old.played, played),
+                _ => panic!("no patch returned"),
+            }
+        }
+    }
+
+    #[test]
+    fn full_queues_drop_rather_than_grow() {
+        let mut synth = synth(Voicing::Poly(2), vec![]);
+        let mut later: Vec<SynthEvent> = (0..EVENT_QUEUE_SIZE + 10)
+            .map(|i| SynthEvent::new(BLOCK as i64 * 4, SynthEventBody::MidiData(midi::MidiEvent::NoteOn {
+                note_num: (i % 128) as u8,
+                velocity: 100,
+            })))
+            .collect();
+        assert_eq!(allocations(|| later.drain(..).for_each(|e| synth.add_event(e))), 0);
+        assert_eq!(synth.events.len(), EVENT_QUEUE_SIZE);
+
+        // Every control in every voice changes for each of these, far more than a buffer holds.
+        synth.events.clear();
+        for i in 0..EVENT_QUEUE_SIZE {
+            let program = midi::MidiEvent::ProgramChange { num: (i % presets::PRESETS.len()) as u8 };
+            synth.add_event(SynthEvent::new(i as i64 % BLOCK as i64, SynthEventBody::MidiData(program)));
+        }
+        assert_eq!(allocations(|| { synth.feed(BLOCK); }), 0);
+    }
+}
@@ -1,50 +1,159 @@
 
 use std::collections::HashMap;
-use std::iter::Cycle;
+use std::fmt;
+use std::mem;
+use std::slice;
 
-pub trait Module {
-    // TODO Connector should be part of builder pattern / metadata
-    fn connector(&self, name: String) -> usize;
-    fn feed(&mut self, input: usize, v: Vec<f32>);
-    fn extract(&mut self, output: usize, len: usize) -> Vec<f32>;
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub enum Direction {
+    Input,
+    Output,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Direction::Input => write!(f, "input"),
+            Direction::Output => write!(f, "output"),
+        }
+    }
 }
 
+// Modules are built on the worker thread and handed to the audio thread. Blocks are passed as
+// slices into buffers the modules own, so once reserve has been called for the longest block
+// nothing allocates. A slice shorter than the block repeats to fill it, so a single value holds
+// for the whole block.
+pub trait Module: Send {
+    // Index of the named input or output, or None if the module has no such connector.
+    fn connector(&self, name: &str, direction: Direction) -> Option<usize>;
+    // Copies a block into an input for the next process.
+    fn feed(&mut self, input: usize, v: &[f32]);
+    // Changes an input to a value from a frame of the next process on. Modules which cannot
+    // change an input part way through a block take the value for the whole block.
+    fn feed_at(&mut self, input: usize, _frame: usize, value: f32) {
+        self.feed(input, &[value]);
+    }
+    // Runs the module over a block of len samples, from what has been fed since the last run.
+    fn process(&mut self, len: usize);
+    // An output from the last process.
+    fn output(&self, output: usize) -> &[f32];
+    // Makes room for blocks of up to len samples.
+    fn reserve(&mut self, len: usize);
+    // Value an input takes when nothing is fed to it.
+    fn set_default(&mut self, input: usize, value: f32);
+    fn get_default(&self, input: usize) -> f32;
+    // Whether an output is computed from an input, so the input must be fed first.
+    fn depends_on(&self, _output: usize, _input: usize) -> bool {
+        true
+    }
+    // Marks an output as read, by a cable or from outside the rack. Outputs nothing reads may be
+    // left silent rather than computed.
+    fn connect(&mut self, _output: usize) {}
+    // How many changes from feed_at were dropped for want of room since this was last asked.
+    fn take_dropped(&mut self) -> usize {
+        0
+    }
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
 pub struct ConnectorInfo {
     mod_name: String,
     mod_conn: String
 }
 
 impl ConnectorInfo {
-    pub fn new(name: &'static str, conn: &'static str) -> ConnectorInfo {
+    pub fn new(name: &str, conn: &str) -> ConnectorInfo {
         ConnectorInfo {
             mod_name: String::from(name),
             mod_conn: String::from(conn)
         }
     }
+
+    pub fn module(&self) -> &str {
+        &self.mod_name
+    }
+
+    pub fn connector(&self) -> &str {
+        &self.mod_conn
+    }
 }
 
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
 pub struct ConnectionInfo {
     conn_in: ConnectorInfo,
-    conn_out: ConnectorInfo
+    conn_out: ConnectorInfo,
+    feedback: bool
 }
 
 impl ConnectionInfo {
     pub fn new(conn_out: ConnectorInfo, conn_in: ConnectorInfo) -> ConnectionInfo {
         ConnectionInfo {
             conn_in,
-            conn_out
+            conn_out,
+            feedback: false
         }
     }
+
+    // A connection carrying the output from the block before, so it can close a loop.
+    pub fn feedback(conn_out: ConnectorInfo, conn_in: ConnectorInfo) -> ConnectionInfo {
+        ConnectionInfo {
+            conn_in,
+            conn_out,
+            feedback: true
+        }
+    }
+
+    pub fn is_feedback(&self) -> bool {
+        self.feedback
+    }
+
+    pub fn output(&self) -> &ConnectorInfo {
+        &self.conn_out
+    }
+
+    pub fn input(&self) -> &ConnectorInfo {
+        &self.conn_in
+    }
 }
 
 pub struct ModuleInfo {
     name: String,
-    module: Box<Module>
+    // Type name the module was built from.
+    kind: String,
+    module: Box<dyn Module>
 }
 
 impl ModuleInfo {
-    pub fn new(name: &'static str, module: Box<Module>) -> ModuleInfo {
-        ModuleInfo { name: String::from(name), module }
+    pub fn new(name: &str, kind: &str, module: Box<dyn Module>) -> ModuleInfo {
+        ModuleInfo { name: String::from(name), kind: String::from(kind), module }
+    }
+}
+
+// How a signal fed from outside the rack joins what is cabled to the same input.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub enum Mix {
+    // Added to the cabled signal, as for modulation.
+    Add,
+    // The higher of the two, so either of two gates holds the input high.
+    Max,
+}
+
+impl Mix {
+    fn apply(self, cabled: f32, external: f32) -> f32 {
+        match self {
+            Mix::Add => cabled + external,
+            Mix::Max => cabled.max(external),
+        }
     }
 }
 
@@ -52,12 +161,28 @@ struct Connection {
     mod_in: usize,
     input: usize,
     mod_out: usize,
-    output: usize
+    output: usize,
+    // The output from the last block, for a feedback connection. Empty until there is one.
+    delayed: Option<Vec<f32>>,
+    // A block fed from outside the rack for the next feed_all, and how it joins the connection.
+    external: Option<Mix>,
+    fed: Vec<f32>,
+    // What the connection and the external block gave together.
+    mixed: Vec<f32>,
 }
 
 impl Connection {
-    fn new(mod_out: usize, output: usize, mod_in: usize, input: usize) -> Connection {
-        Connection { mod_in, input, mod_out, output }
+    fn new(mod_out: usize, output: usize, mod_in: usize, input: usize, feedback: bool) -> Connection {
+        Connection {
+            mod_in,
+            input,
+            mod_out,
+            output,
+            delayed: if feedback { Some(Vec::new()) } else { None },
+            external: None,
+            fed: Vec::new(),
+            mixed: Vec::new(),
+        }
     }
 }
 
@@ -66,156 +191,568 @@ pub struct Connector {
     offset: usize
 }
 
+// A module's input or output, found by name.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub struct PatchPoint {
+    module: usize,
+    connector: usize,
+}
+
+impl PatchPoint {
+    // A connector of a module at a known place in every rack, such as the engine's.
+    pub fn new(module: usize, connector: usize) -> PatchPoint {
+        PatchPoint { module, connector }
+    }
+}
+
+// Why a rack could not be built.
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum RackError {
+    UnknownModule(String),
+    // More than one module with the same name.
+    DuplicateModule(String),
+    UnknownType { module: String, kind: String },
+    UnknownPort { module: String, port: String, direction: Direction },
+    // More than one connection into the same input.
+    DuplicateInput { module: String, port: String },
+    // A connection from an input, or to an output.
+    DirectionMismatch { module: String, port: String, expected: Direction },
+    // Modules whose connections loop back on themselves without a feedback connection.
+    Cycle(Vec<String>),
+}
+
+impl fmt::Display for RackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RackError::UnknownModule(ref module) => write!(f, "unknown module {}", module),
+            RackError::DuplicateModule(ref module) => write!(f, "module {} already exists", module),
+            RackError::UnknownType { ref module, ref kind } => write!(f, "{} has unknown type {}", module, kind),
+            RackError::UnknownPort { ref module, ref port, direction } =>
+                write!(f, "{} has no {} {}", module, direction, port),
+            RackError::DuplicateInput { ref module, ref port } =>
+                write!(f, "{}.{} is connected more than once", module, port),
+            RackError::DirectionMismatch { ref module, ref port, expected } =>
+                write!(f, "{}.{} is not an {}", module, port, expected),
+            RackError::Cycle(ref modules) =>
+                write!(f, "connections loop through {} with no feedback connection", modules.join(", ")),
+        }
+    }
+}
+
 pub struct Rack {
+    names: Vec<String>,
+    kinds: Vec<String>,
+    // The connections as given, so the rack can be described again.
+    cables: Vec<ConnectionInfo>,
     connections: Vec<Connection>,
-    modules: Vec<Box<Module>>
+    modules: Vec<Box<dyn Module>>,
+    // Which modules have run this block, so each runs once however many inputs it feeds.
+    processed: Vec<bool>,
+}
+
+// Runs a module unless it has already run this block.
+fn run(modules: &mut [Box<dyn Module>], processed: &mut [bool], m: usize, len: usize) {
+    if !processed[m] {
+        modules[m].process(len);
+        processed[m] = true;
+    }
+}
+
+// One module to read from and another to feed.
+fn pair(modules: &mut [Box<dyn Module>], from: usize, to: usize) -> (&dyn Module, &mut dyn Module) {
+    if from < to {
+        let (before, after) = modules.split_at_mut(to);
+        (before[from].as_ref(), after[0].as_mut())
+    } else {
+        let (before, after) = modules.split_at_mut(from);
+        (after[0].as_ref(), before[to].as_mut())
+    }
 }
 
 impl Rack {
-    pub fn new(mut module_info: Vec<ModuleInfo>, connection_info: Vec<ConnectionInfo>) -> Rack {
+    pub fn new(mut module_info: Vec<ModuleInfo>, connection_info: Vec<ConnectionInfo>) -> Result<Rack, RackError> {
         let mut mod_names = HashMap::new();
 
-        module_info.iter().enumerate().for_each(|(i, v)| { mod_names.insert(v.name.clone(), i); });
+        for (i, v) in module_info.iter().enumerate() {
+            if mod_names.insert(v.name.clone(), i).is_some() {
+                return Err(RackError::DuplicateModule(v.name.clone()));
+            }
+        }
+        let names: Vec<String> = module_info.iter().map(|m| m.name.clone()).collect();
+        let kinds = module_info.iter().map(|m| m.kind.clone()).collect();
         let drain_range = 0..module_info.len();
-        let modules: Vec<Box<Module>> = module_info.drain(drain_range).map(|val| val.module).collect();
-
-        let connections: Vec<Connection> = connection_info.iter().map(|c| {
-            let mod_in_offset = mod_names.get(&c.conn_in.mod_name).unwrap();
-            let mod_out_offset = mod_names.get(&c.conn_out.mod_name).unwrap();
-            Connection::new(
-                *mod_out_offset,
-                modules.get(*mod_out_offset).unwrap().connector(c.conn_out.mod_conn.clone()),
-                *mod_in_offset,
-                modules.get(*mod_in_offset).unwrap().connector(c.conn_in.mod_conn.clone())
-            )
-        }).collect();
-
-        Rack {
+        let mut modules: Vec<Box<dyn Module>> = module_info.drain(drain_range).map(|val| val.module).collect();
+
+        // Finds a connector, telling a missing one from one facing the wrong way.
+        let find = |info: &ConnectorInfo, direction: Direction| -> Result<(usize, usize), RackError> {
+            let m = *mod_names.get(&info.mod_name).ok_or_else(|| RackError::UnknownModule(info.mod_name.clone()))?;
+            let other = match direction {
+                Direction::Input => Direction::Output,
+                Direction::Output => Direction::Input,
+            };
+            match (modules[m].connector(&info.mod_conn, direction), modules[m].connector(&info.mod_conn, other)) {
+                (Some(c), _) => Ok((m, c)),
+                (None, Some(_)) => Err(RackError::DirectionMismatch {
+                    module: info.mod_name.clone(),
+                    port: info.mod_conn.clone(),
+                    expected: direction,
+                }),
+                (None, None) => Err(RackError::UnknownPort {
+                    module: info.mod_name.clone(),
+                    port: info.mod_conn.clone(),
+                    direction,
+                }),
+            }
+        };
+
+        let mut connections: Vec<Connection> = Vec::with_capacity(connection_info.len());
+        for c in &connection_info {
+            let (mod_out, output) = find(&c.conn_out, Direction::Output)?;
+            let (mod_in, input) = find(&c.conn_in, Direction::Input)?;
+            if connections.iter().any(|e| e.mod_in == mod_in && e.input == input) {
+                return Err(RackError::DuplicateInput { module: c.conn_in.mod_name.clone(), port: c.conn_in.mod_conn.clone() });
+            }
+            connections.push(Connection::new(mod_out, output, mod_in, input, c.feedback));
+        }
+        for c in &connections {
+            modules[c.mod_out].connect(c.output);
+        }
+        let connections = Rack::evaluation_order(connections, &modules)
+            .map_err(|looped| RackError::Cycle(looped.iter().map(|&m| names[m].clone()).collect()))?;
+
+        Ok(Rack {
+            names,
+            kinds,
+            cables: connection_info,
+            processed: vec![false; modules.len()],
             modules,
-            connections
+            connections,
+        })
+    }
+
+    // Orders connections so each module runs only once everything it depends on has been fed.
+    // Feedback connections depend on nothing, as they carry the last block, and a module cannot
+    // otherwise feed itself. Declaration order is kept where the graph allows. Gives the modules
+    // left in a loop if there is one.
+    fn evaluation_order(connections: Vec<Connection>, modules: &[Box<dyn Module>]) -> Result<Vec<Connection>, Vec<usize>> {
+        let waits_for = |c: &Connection, before: &Connection| {
+            c.delayed.is_none() && before.mod_in == c.mod_out &&
+                (c.mod_in == c.mod_out || modules[c.mod_out].depends_on(c.output, before.input))
+        };
+        let mut pending: Vec<Option<Connection>> = connections.into_iter().map(Some).collect();
+        let mut ordered = Vec::with_capacity(pending.len());
+        while ordered.len() < pending.len() {
+            let ready = (0..pending.len()).find(|&i| match pending[i] {
+                Some(ref c) => !pending.iter().flatten().any(|before| waits_for(c, before)),
+                None => false,
+            });
+            match ready {
+                Some(i) => ordered.push(pending[i].take().unwrap()),
+                None => return Err(Rack::looped(pending, &waits_for)),
+            }
         }
+        Ok(ordered)
     }
 
-    pub fn get<'a>(&'a mut self, m: usize) -> &'a mut Module {
+    // Modules in the loops among connections which could not be ordered. Connections nothing
+    // else waits for are only downstream of a loop, so are dropped until none are left.
+    fn looped<F>(mut pending: Vec<Option<Connection>>, waits_for: &F) -> Vec<usize>
+        where F: Fn(&Connection, &Connection) -> bool {
+        loop {
+            let unneeded = (0..pending.len()).find(|&i| match pending[i] {
+                Some(ref c) => !pending.iter().flatten().any(|after| waits_for(after, c)),
+                None => false,
+            });
+            match unneeded {
+                Some(i) => pending[i] = None,
+                None => break,
+            }
+        }
+        let mut looped: Vec<usize> = pending.iter().flatten().map(|c| c.mod_out).collect();
+        looped.sort_unstable();
+        looped.dedup();
+        looped
+    }
+
+    pub fn get(&mut self, m: usize) -> &mut dyn Module {
         self.modules[m].as_mut()
     }
 
-    pub fn feed_all(&mut self, len: usize) {
-        for c in &self.connections {
-            let out = {
-                let mut mod_out = self.modules[c.mod_out].as_mut();
-                mod_out.extract(c.output, len)
-            };
+    // Each module's name, type name and module, in rack order.
+    pub fn modules<'a>(&'a self) -> impl Iterator<Item = (&'a str, &'a str, &'a dyn Module)> + 'a {
+        self.names.iter().zip(self.kinds.iter()).zip(self.modules.iter())
+            .map(|((name, kind), module)| (name.as_str(), kind.as_str(), module.as_ref()))
+    }
+
+    pub fn cables(&self) -> &[ConnectionInfo] {
+        &self.cables
+    }
+
+    // The named connector of the named module, or None if there is no such connector.
+    pub fn patch_point(&self, module: &str, connector: &str, direction: Direction) -> Option<PatchPoint> {
+        let m = self.names.iter().position(|n| n == module)?;
+        self.modules[m].connector(connector, direction).map(|c| PatchPoint { module: m, connector: c })
+    }
+
+    // Makes room for blocks of up to len samples in every module. Not realtime safe.
+    pub fn reserve(&mut self, len: usize) {
+        for module in &mut self.modules {
+            module.reserve(len);
+        }
+        for c in &mut self.connections {
+            if let Some(ref mut delayed) = c.delayed {
+                delayed.reserve(len);
+            }
+            c.fed.reserve(len);
+            c.mixed.reserve(len);
+        }
+    }
 
-            let mut mod_in = self.modules[c.mod_in].as_mut();
-            mod_in.feed(c.input, out)
+    // Feeds an input for the next feed_all, joined with whatever is connected to it. An input
+    // with nothing connected takes the block as it is.
+    pub fn feed_external(&mut self, input: PatchPoint, v: &[f32], mix: Mix) {
+        if v.is_empty() {
+            return;
         }
+        match self.connections.iter_mut().find(|c| c.mod_in == input.module && c.input == input.connector) {
+            Some(c) => {
+                c.fed.clear();
+                c.fed.extend_from_slice(v);
+                c.external = Some(mix);
+            }
+            None => self.modules[input.module].feed(input.connector, v),
+        }
+    }
+
+    // Runs every connection in evaluation order, so each module is run once with all of its
+    // inputs for the block. Feedback connections carry what their output gave the block before.
+    pub fn feed_all(&mut self, len: usize) {
+        for p in &mut self.processed {
+            *p = false;
+        }
+        for c in &mut self.connections {
+            if let Some(mix) = c.external.take() {
+                if c.delayed.is_none() {
+                    run(&mut self.modules, &mut self.processed, c.mod_out, len);
+                }
+                let cabled = match c.delayed {
+                    Some(ref last) => &last[..],
+                    None => self.modules[c.mod_out].output(c.output),
+                };
+                c.mixed.clear();
+                for i in 0..len {
+                    let from = if cabled.is_empty() { 0.0 } else { cabled[i % cabled.len()] };
+                    c.mixed.push(mix.apply(from, c.fed[i % c.fed.len()]));
+                }
+                self.modules[c.mod_in].feed(c.input, &c.mixed);
+                continue;
+            }
+            match c.delayed {
+                Some(ref last) if last.is_empty() => (),
+                Some(ref last) => self.modules[c.mod_in].feed(c.input, last),
+                None => {
+                    run(&mut self.modules, &mut self.processed, c.mod_out, len);
+                    let (from, to) = pair(&mut self.modules, c.mod_out, c.mod_in);
+                    to.feed(c.input, from.output(c.output));
+                }
+            }
+        }
+        for c in &mut self.connections {
+            if let Some(ref mut delayed) = c.delayed {
+                run(&mut self.modules, &mut self.processed, c.mod_out, len);
+                delayed.clear();
+                delayed.extend_from_slice(self.modules[c.mod_out].output(c.output));
+            }
+        }
+    }
+
+    // Marks an output as read from outside the rack, so it is computed.
+    pub fn connect(&mut self, output: PatchPoint) {
+        self.modules[output.module].connect(output.connector);
+    }
+
+    // An output from the last feed_all, running its module now if nothing it connects to
+    // needed it. Silent unless connected.
+    pub fn output(&mut self, output: PatchPoint, len: usize) -> &[f32] {
+        run(&mut self.modules, &mut self.processed, output.module, len);
+        self.modules[output.module].output(output.connector)
     }
 }
 
-pub trait MisoWorker {
+// Gathers one sample of every input, in the order the worker gave them.
+fn gather(data: &[DataIn], i: usize, vals: &mut [f32]) {
+    for (val, d) in vals.iter_mut().zip(data.iter()) {
+        *val = d.at(i);
+    }
+}
+
+// Computes a module's outputs a sample at a time. Outputs are cabled to by name, in the order
+// extract fills them.
+pub trait MimoWorker: Send {
     fn get_data(&self) -> Vec<DataIn>;
-    fn extract(&mut self, vals: &[f32]) -> f32;
+    fn get_outputs(&self) -> &'static [&'static str];
+    // Fills outs, one value per output, from one sample of every input. Outputs which are not
+    // connected are silent whatever extract gives them.
+    fn extract(&mut self, vals: &[f32], outs: &mut [f32]);
+    // Told of each output as it is connected, for workers which can skip the rest.
+    fn connect(&mut self, _output: usize) {}
 }
 
-pub struct MisoModule<T: MisoWorker> {
+pub struct MimoModule<T: MimoWorker> {
     data: Vec<DataIn>,
+    // One sample of each input, as extract reads it.
+    vals: Vec<f32>,
+    names: &'static [&'static str],
+    // A block for each output.
+    out: Vec<Vec<f32>>,
+    // Which outputs are read.
+    connected: Vec<bool>,
+    // One sample of each output, as extract fills it.
+    sample: Vec<f32>,
     worker: T
 }
 
-impl <T: MisoWorker> MisoModule<T> {
-    pub fn new(worker: T) -> MisoModule<T> {
-        MisoModule {
-            data: worker.get_data(),
+impl <T: MimoWorker> MimoModule<T> {
+    pub fn new(worker: T) -> MimoModule<T> {
+        let names = worker.get_outputs();
+        let data = worker.get_data();
+        MimoModule {
+            vals: vec![0.0; data.len()],
+            data,
+            names,
+            out: vec![Vec::new(); names.len()],
+            connected: vec![false; names.len()],
+            sample: vec![0.0; names.len()],
             worker
         }
     }
 }
 
-impl <T: MisoWorker> Module for MisoModule<T> {
-    fn connector(&self, name: String) -> usize {
-        match (self.data.iter().position(|v| v.name == name)) {
-            Some(i) => i,
-            None => {
-                assert!(name == String::from("output"));
-                0
-            }
+impl <T: MimoWorker> Module for MimoModule<T> {
+    fn connector(&self, name: &str, direction: Direction) -> Option<usize> {
+        match direction {
+            Direction::Input => self.data.iter().position(|v| v.name == name),
+            Direction::Output => self.names.iter().position(|n| *n == name),
         }
     }
 
-    fn feed(&mut self, input: usize, v: Vec<f32>) {
+    fn feed(&mut self, input: usize, v: &[f32]) {
         self.data[input].set(v)
     }
 
-    fn extract(&mut self, output: usize, len: usize) -> Vec<f32> {
-        assert_eq!(output, 0);
-        assert!(self.data.len() <= 10);
-        let mut val = Vec::with_capacity(len);
-        let mut vecs: Vec<Vec<f32>> = self.data.iter_mut().map(|d| d.get()).collect();
-        let mut cycles = Vec::with_capacity(vecs.len());
-        for item in vecs.iter() {
-            cycles.push(item.iter().cycle());
-        }
+    fn set_default(&mut self, input: usize, value: f32) {
+        self.data[input].set_default(value)
+    }
+
+    fn get_default(&self, input: usize) -> f32 {
+        self.data[input].default()
+    }
 
-        let mut inputs: [f32; 10] = [0.0; 10];
+    // Inputs go back to their defaults unless fed again before the next block.
+    fn process(&mut self, len: usize) {
+        for out in &mut self.out {
+            out.clear();
+        }
         for i in 0..len {
-            for j in 0..cycles.len() {
-                inputs[j] = *cycles[j].next().unwrap();
+            gather(&self.data, i, &mut self.vals);
+            self.worker.extract(&self.vals, &mut self.sample);
+            for ((out, v), connected) in self.out.iter_mut().zip(self.sample.iter()).zip(self.connected.iter()) {
+                out.push(if *connected { *v } else { 0.0 });
             }
-            val.push(self.worker.extract(&inputs));
         }
-        val
+        for d in &mut self.data {
+            d.clear();
+        }
+    }
+
+    fn output(&self, output: usize) -> &[f32] {
+        &self.out[output]
+    }
+
+    fn connect(&mut self, output: usize) {
+        self.connected[output] = true;
+        self.worker.connect(output);
+    }
+
+    fn reserve(&mut self, len: usize) {
+        for out in &mut self.out {
+            out.reserve(len);
+        }
+        for d in &mut self.data {
+            d.reserve(len);
+        }
     }
 }
 
+// An input's block, or its default if nothing has been fed.
 #[derive(Debug)]
 #[derive(Clone)]
 pub struct DataIn {
-    v: Option<Vec<f32>>,
+    values: Vec<f32>,
     default: f32,
     name: String
 }
 
 impl DataIn {
     pub fn new(name: String, default: f32) -> DataIn {
-        DataIn { name, v: None, default }
+        DataIn { name, values: Vec::new(), default }
+    }
+
+    pub fn get(&self) -> &[f32] {
+        if self.values.is_empty() {
+            slice::from_ref(&self.default)
+        } else {
+            &self.values
+        }
+    }
+
+    // The value at a sample of the block, repeating a shorter block.
+    pub fn at(&self, i: usize) -> f32 {
+        let v = self.get();
+        v[i % v.len()]
+    }
+
+    pub fn set(&mut self, v: &[f32]) {
+        self.values.clear();
+        self.values.extend_from_slice(v);
+    }
+
+    // Goes back to the default.
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+
+    // Keeps only the last value of the block, to hold from now on.
+    pub fn hold(&mut self) {
+        if self.values.len() > 1 {
+            let last = self.values[self.values.len() - 1];
+            self.set(&[last]);
+        }
+    }
+
+    // Changes to value from a frame of a block of len samples on, spreading out a shorter block
+    // so the samples before the frame keep what they had.
+    pub fn set_from(&mut self, frame: usize, value: f32, len: usize) {
+        if frame == 0 {
+            self.set(&[value]);
+            return;
+        }
+        if self.values.is_empty() {
+            self.values.push(self.default);
+        }
+        let held = self.values.len();
+        for i in held..len {
+            let v = self.values[i % held];
+            self.values.push(v);
+        }
+        self.values.truncate(len);
+        for v in &mut self.values[frame.min(len)..] {
+            *v = value;
+        }
     }
 
-    pub fn get(&mut self) -> Vec<f32> {
-        let v = self.v.take();
-        v.unwrap_or(vec![self.default])
+    pub fn reserve(&mut self, len: usize) {
+        self.values.reserve(len);
     }
 
-    pub fn set(&mut self, v: Vec<f32>) {
-        self.v = Some(v);
+    pub fn set_default(&mut self, default: f32) {
+        self.default = default;
+    }
+
+    pub fn default(&self) -> f32 {
+        self.default
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
     }
 }
 
+// Changes a buffer holds for one process. Any more are dropped, as making room would allocate in
+// the audio thread.
+const MAX_CHANGES: usize = 1024;
+
+// Holds a stream for each item, which the engine builds from values changing at given frames
+// and from blocks fed to it. Each block an item carries what it was fed, with any changes made
+// from their frames on, or else the last value it had.
 #[derive(Debug)]
 pub struct BufferModule {
-    data: Vec<DataIn>
+    data: Vec<DataIn>,
+    // Which items have been fed since the last process.
+    fed: Vec<bool>,
+    // Item, frame and value of each change for the next process, in the order they were made.
+    changes: Vec<(usize, usize, f32)>,
+    // Changes dropped as there was no room for them.
+    dropped: usize,
 }
 
 impl BufferModule {
     pub fn new(data: Vec<DataIn>) -> BufferModule {
-        BufferModule { data }
+        BufferModule { fed: vec![false; data.len()], data, changes: Vec::with_capacity(MAX_CHANGES), dropped: 0 }
     }
 }
 
 impl Module for BufferModule {
-    fn connector(&self, item: String) -> usize {
-        self.data.iter().position(|v| v.name == item).unwrap()
+    // Each item is both an input and an output.
+    fn connector(&self, item: &str, _direction: Direction) -> Option<usize> {
+        self.data.iter().position(|v| v.name == item)
     }
 
-    fn feed(&mut self, input: usize, v: Vec<f32>) {
-        self.data[input].set(v)
+    fn feed(&mut self, input: usize, v: &[f32]) {
+        self.data[input].set(v);
+        self.fed[input] = true;
     }
 
-    fn extract(&mut self, output: usize, len: usize) -> Vec<f32> {
-        // TODO cycle and create a new vector.
-        let val = self.data[output].get();
-        self.data[output].set(val.clone());
-        val
+    fn feed_at(&mut self, input: usize, frame: usize, value: f32) {
+        if self.changes.len() < MAX_CHANGES {
+            self.changes.push((input, frame, value));
+        } else {
+            self.dropped += 1;
+        }
+    }
+
+    fn take_dropped(&mut self) -> usize {
+        mem::replace(&mut self.dropped, 0)
+    }
+
+    fn set_default(&mut self, input: usize, value: f32) {
+        self.data[input].set_default(value)
+    }
+
+    fn get_default(&self, input: usize) -> f32 {
+        self.data[input].default()
+    }
+
+    // Items only pass through, so an item's output waits only for the same item's input.
+    fn depends_on(&self, output: usize, input: usize) -> bool {
+        output == input
+    }
+
+    fn process(&mut self, len: usize) {
+        for (d, fed) in self.data.iter_mut().zip(self.fed.iter_mut()) {
+            if !*fed {
+                d.hold();
+            }
+            *fed = false;
+        }
+        for &(item, frame, value) in &self.changes {
+            self.data[item].set_from(frame, value, len);
+        }
+        self.changes.clear();
+    }
+
+    fn output(&self, output: usize) -> &[f32] {
+        self.data[output].get()
+    }
+
+    fn reserve(&mut self, len: usize) {
+        for d in &mut self.data {
+            d.reserve(len);
+        }
     }
 }
 
@@ -223,8 +760,8 @@ pub struct Attenuverter {
 }
 
 impl Attenuverter {
-    pub fn new() -> MisoModule<Attenuverter> {
-        MisoModule::new(Attenuverter { })
+    pub fn new() -> MimoModule<Attenuverter> {
+        MimoModule::new(Attenuverter { })
     }
 
     fn attenuvert(val: f32, input: f32) -> f32 {
@@ -233,7 +770,7 @@ impl Attenuverter {
     }
 }
 
-impl MisoWorker for Attenuverter {
+impl MimoWorker for Attenuverter {
     fn get_data(&self) -> Vec<DataIn> {
         vec![
             DataIn::new(String::from("attenuation"), 1.0),
@@ -241,8 +778,248 @@ impl MisoWorker for Attenuverter {
         ]
     }
 
-    fn extract(&mut self, vals: &[f32]) -> f32 {
-        Attenuverter::attenuvert(vals[0], vals[1])
+    fn get_outputs(&self) -> &'static [&'static str] {
+        &["output"]
+    }
+
+    fn extract(&mut self, vals: &[f32], outs: &mut [f32]) {
+        outs[0] = Attenuverter::attenuvert(vals[0], vals[1]);
+    }
+}
+
+
+// Sums two signals, each scaled by a level.
+pub struct Mixer {
+}
+
+impl Mixer {
+    pub fn new() -> MimoModule<Mixer> {
+        MimoModule::new(Mixer { })
     }
 }
 
+impl MimoWorker for Mixer {
+    fn get_data(&self) -> Vec<DataIn> {
+        vec![
+            DataIn::new(String::from("input_a"), 0.0),
+            DataIn::new(String::from("level_a"), 1.0),
+            DataIn::new(String::from("input_b"), 0.0),
+            DataIn::new(String::from("level_b"), 1.0),
+        ]
+    }
+
+    fn get_outputs(&self) -> &'static [&'static str] {
+        &["output"]
+    }
+
+    fn extract(&mut self, vals: &[f32], outs: &mut [f32]) {
+        outs[0] = vals[0] * vals[1] + vals[2] * vals[3];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32;
+    use synth::envelope::Envelope;
+    use synth::oscillator::Oscillator;
+
+    const INPUTS: usize = 24;
+
+    // Weighs each of its inputs by its position, so a value in the wrong place shows.
+    struct Weighted {
+    }
+
+    fn weighted_data() -> Vec<DataIn> {
+        (0..INPUTS).map(|i| DataIn::new(format!("in_{}", i), 1.0)).collect()
+    }
+
+    impl MimoWorker for Weighted {
+        fn get_data(&self) -> Vec<DataIn> {
+            weighted_data()
+        }
+
+        fn get_outputs(&self) -> &'static [&'static str] {
+            &["weighted", "last"]
+        }
+
+        fn extract(&mut self, vals: &[f32], outs: &mut [f32]) {
+            outs[0] = vals.iter().enumerate().map(|(i, v)| i as f32 * v).sum();
+            outs[1] = vals[INPUTS - 1];
+        }
+    }
+
+    // Sum of the weights, with every input at its default.
+    fn all_defaults() -> f32 {
+        (0..INPUTS).sum::<usize>() as f32
+    }
+
+    // Feeds the last input a block and the one before it a single value, then runs a block.
+    fn run(module: &mut dyn Module) {
+        let last = module.connector(&format!("in_{}", INPUTS - 1), Direction::Input).unwrap();
+        let before = module.connector(&format!("in_{}", INPUTS - 2), Direction::Input).unwrap();
+        assert_eq!(last, INPUTS - 1);
+        module.reserve(4);
+        module.feed(last, &[0.0, 1.0, 2.0, 3.0]);
+        module.feed(before, &[0.0]);
+        module.process(4);
+    }
+
+    fn expected() -> Vec<f32> {
+        let base = all_defaults() - (INPUTS - 1) as f32 - (INPUTS - 2) as f32;
+        (0..4).map(|v| base + (INPUTS - 1) as f32 * v as f32).collect()
+    }
+
+    // A connector given as <module>.<connector>.
+    fn point(s: &str) -> ConnectorInfo {
+        let (module, connector) = s.split_at(s.find('.').unwrap());
+        ConnectorInfo::new(module, &connector[1..])
+    }
+
+    // A rack of attenuverters with the given names and cables.
+    fn rack(names: &[&str], cables: &[(&str, &str)]) -> Result<Rack, RackError> {
+        let modules = names.iter().map(|n| ModuleInfo::new(n, "attenuverter", Box::new(Attenuverter::new()))).collect();
+        let cables = cables.iter().map(|&(from, to)| ConnectionInfo::new(point(from), point(to))).collect();
+        Rack::new(modules, cables)
+    }
+
+    // Feeds a rack input a constant block, runs the rack and reads an output.
+    fn run_rack(rack: &mut Rack, input: &str, value: f32, output: &str) -> Vec<f32> {
+        let input = point(input);
+        let input = rack.patch_point(&input.mod_name, &input.mod_conn, Direction::Input).unwrap();
+        let output = point(output);
+        let output = rack.patch_point(&output.mod_name, &output.mod_conn, Direction::Output).unwrap();
+        rack.connect(output);
+        rack.reserve(4);
+        rack.get(input.module).feed(input.connector, &[value; 4]);
+        rack.feed_all(4);
+        rack.output(output, 4).to_vec()
+    }
+
+    #[test]
+    fn unknown_modules_are_refused() {
+        let error = rack(&["a"], &[("a.output", "b.signal")]).err();
+        assert_eq!(error, Some(RackError::UnknownModule(String::from("b"))));
+    }
+
+    #[test]
+    fn duplicate_modules_are_refused() {
+        let error = rack(&["a", "b", "a"], &[]).err();
+        assert_eq!(error, Some(RackError::DuplicateModule(String::from("a"))));
+    }
+
+    #[test]
+    fn unknown_ports_are_refused() {
+        let error = rack(&["a", "b"], &[("a.output", "b.gain")]).err();
+        assert_eq!(error, Some(RackError::UnknownPort {
+            module: String::from("b"),
+            port: String::from("gain"),
+            direction: Direction::Input,
+        }));
+    }
+
+    #[test]
+    fn inputs_take_one_connection() {
+        let error = rack(&["a", "b", "c"], &[("a.output", "c.signal"), ("b.output", "c.signal")]).err();
+        assert_eq!(error, Some(RackError::DuplicateInput { module: String::from("c"), port: String::from("signal") }));
+    }
+
+    #[test]
+    fn connections_run_from_outputs_to_inputs() {
+        let error = rack(&["a", "b"], &[("a.attenuation", "b.signal")]).err();
+        assert_eq!(error, Some(RackError::DirectionMismatch {
+            module: String::from("a"),
+            port: String::from("attenuation"),
+            expected: Direction::Output,
+        }));
+    }
+
+    #[test]
+    fn modules_cannot_feed_themselves() {
+        let error = rack(&["a", "b"], &[("a.output", "a.signal")]).err();
+        assert_eq!(error, Some(RackError::Cycle(vec![String::from("a")])));
+    }
+
+    #[test]
+    fn declaration_order_does_not_change_a_chain() {
+        let mut forward = rack(&["a", "b", "c"], &[("a.output", "b.signal"), ("b.output", "c.signal")]).unwrap();
+        let mut reverse = rack(&["c", "b", "a"], &[("b.output", "c.signal"), ("a.output", "b.signal")]).unwrap();
+        let expected = run_rack(&mut forward, "a.signal", 1.0, "c.output");
+        assert_eq!(expected, vec![1.5 * 1.5 * 1.5; 4]);
+        assert_eq!(run_rack(&mut reverse, "a.signal", 1.0, "c.output"), expected);
+    }
+
+    #[test]
+    fn loops_need_a_feedback_connection() {
+        let cables = [("a.output", "b.signal"), ("b.output", "a.signal"), ("b.output", "c.signal")];
+        let error = rack(&["a", "b", "c"], &cables).err();
+        assert_eq!(error, Some(RackError::Cycle(vec![String::from("a"), String::from("b")])));
+    }
+
+    #[test]
+    fn feedback_starts_silent() {
+        let modules = vec![
+            ModuleInfo::new("mix", "mixer", Box::new(Mixer::new())),
+            ModuleInfo::new("a", "attenuverter", Box::new(Attenuverter::new())),
+        ];
+        let cables = vec![
+            ConnectionInfo::new(point("mix.output"), point("a.signal")),
+            ConnectionInfo::feedback(point("a.output"), point("mix.input_b")),
+        ];
+        let mut rack = Rack::new(modules, cables).unwrap();
+        assert_eq!(run_rack(&mut rack, "mix.input_a", 1.0, "mix.output"), vec![1.0; 4]);
+        assert_eq!(run_rack(&mut rack, "mix.input_a", 1.0, "mix.output"), vec![2.5; 4]);
+    }
+
+    #[test]
+    fn outputs_are_cabled_by_name() {
+        let modules = vec![
+            ModuleInfo::new("osc", "oscillator", Box::new(Oscillator::new(48000.0))),
+            ModuleInfo::new("env", "envelope", Box::new(Envelope::new(48000.0))),
+            ModuleInfo::new("a", "attenuverter", Box::new(Attenuverter::new())),
+            ModuleInfo::new("mix", "mixer", Box::new(Mixer::new())),
+        ];
+        let cables = vec![
+            ConnectionInfo::new(point("osc.sine"), point("a.signal")),
+            ConnectionInfo::new(point("env.end"), point("mix.input_a")),
+        ];
+        let mut rack = Rack::new(modules, cables).unwrap();
+        let primary = rack.patch_point("osc", "primary", Direction::Input).unwrap();
+        let freq_in = rack.patch_point("osc", "freq_in", Direction::Input).unwrap();
+        let square = rack.patch_point("osc", "square", Direction::Output).unwrap();
+        let attenuation = rack.patch_point("a", "attenuation", Direction::Input).unwrap();
+        let sine_out = rack.patch_point("a", "output", Direction::Output).unwrap();
+        rack.get(attenuation.module).set_default(attenuation.connector, 0.75);
+        rack.connect(sine_out);
+
+        // The primary waveform is a square, so only the sine output gives a sine.
+        rack.get(primary.module).feed(primary.connector, &[0.2]);
+        rack.get(freq_in.module).feed(freq_in.connector, &[69.0 / 127.0]);
+        assert_eq!(run_rack(&mut rack, "env.trigger", 0.0, "mix.output"), vec![1.0; 4]);
+        let freq = 440.0 / 48000.0;
+        let sine: Vec<f32> = (0..4).map(|t| (2.0 * f32::consts::PI * freq * t as f32).sin()).collect();
+        for (v, expected) in rack.output(sine_out, 4).iter().zip(sine.iter()) {
+            assert!((v - expected).abs() < 1e-4, "{} {}", v, expected);
+        }
+        // Outputs nothing reads are not computed.
+        assert_eq!(rack.output(square, 4), &[0.0; 4]);
+
+        assert_eq!(run_rack(&mut rack, "env.trigger", 1.0, "mix.output"), vec![0.0; 4]);
+    }
+
+    #[test]
+    fn mimo_module_takes_many_inputs() {
+        let mut module = MimoModule::new(Weighted { });
+        let last = module.connector("last", Direction::Output).unwrap();
+        module.connect(0);
+        module.connect(last);
+        run(&mut module);
+        assert_eq!(module.output(0), &expected()[..]);
+        assert_eq!(module.output(last), &[0.0, 1.0, 2.0, 3.0]);
+
+        // Inputs go back to their defaults once a block has run.
+        module.process(2);
+        assert_eq!(module.output(0), &[all_defaults(), all_defaults()]);
+        assert_eq!(module.output(last), &[1.0, 1.0]);
+    }
+}
@@ -2,8 +2,6 @@
 extern crate rand;
 use self::rand::random;
 use std::f32;
-use std::iter::Cycle;
-use std::slice::Iter;
 
 use synth::module;
 
@@ -63,9 +61,14 @@ impl Waveform {
     }
 }
 
+// The waveform chosen by primary, then every other waveform.
+const OUTPUTS: [&str; 5] = ["output", "sine", "square", "saw", "triangle"];
+
 pub struct Oscillator {
     t: f32,
     rate: f32,
+    // Which outputs are read, so the rest are not computed.
+    connected: [bool; 5],
 }
 
 impl Oscillator {
@@ -75,23 +78,29 @@ impl Oscillator {
         freq_hz / rate
     }
 
-    pub fn new(rate: f32) -> module::MisoModule<Oscillator> {
-        module::MisoModule::new(Oscillator {
+    pub fn new(rate: f32) -> module::MimoModule<Oscillator> {
+        module::MimoModule::new(Oscillator {
             t: 0.0,
             rate,
+            connected: [false; OUTPUTS.len()],
         })
     }
 
-    pub fn oscillate(&mut self, primary: f32, note: f32, fm_in: f32, duty_cycle_in: f32) -> f32 {
+    // Each connected output, in phase with each other.
+    pub fn oscillate(&mut self, primary: f32, note: f32, fm_in: f32, duty_cycle_in: f32, outs: &mut [f32]) {
         let freq = Oscillator::get_freq(note, self.rate);
-        let wave = Waveform::from_data(primary);
-        let res = wave.oscillate(self.t, freq, fm_in, duty_cycle_in);
+        let waves = [Waveform::from_data(primary), Waveform::Sine, Waveform::Square, Waveform::Sawtooth,
+                     Waveform::Triangle];
+        for ((out, wave), connected) in outs.iter_mut().zip(waves.iter()).zip(self.connected.iter()) {
+            if *connected {
+                *out = wave.oscillate(self.t, freq, fm_in, duty_cycle_in);
+            }
+        }
         self.t = self.t + 1.0;
-        res
     }
 }
 
-impl module::MisoWorker for Oscillator {
+impl module::MimoWorker for Oscillator {
     fn get_data(&self) -> Vec<module::DataIn> {
         vec![
             module::DataIn::new(String::from("primary"), 0.0),
@@ -101,8 +110,16 @@ impl module::MisoWorker for Oscillator {
         ]
     }
 
-    fn extract(&mut self, vals: &[f32]) -> f32 {
-        self.oscillate(vals[0], vals[1], vals[2], vals[3])
+    fn get_outputs(&self) -> &'static [&'static str] {
+        &OUTPUTS
+    }
+
+    fn extract(&mut self, vals: &[f32], outs: &mut [f32]) {
+        self.oscillate(vals[0], vals[1], vals[2], vals[3], outs)
+    }
+
+    fn connect(&mut self, output: usize) {
+        self.connected[output] = true;
     }
 }
 
@@ -0,0 +1,162 @@
+use std::f32;
+
+use synth::module;
+
+// Places a voice in the stereo field. The position is the pan input plus spread times the
+// note's distance from the middle of the keyboard, so a chord fans out across the field. All
+// inputs are signals, so pan and spread can be modulated per voice.
+pub struct Panner {
+    data: Vec<module::DataIn>,
+    left: Vec<f32>,
+    right: Vec<f32>,
+}
+
+enum Inputs {
+    Signal = 0,
+    Pan = 1,
+    Spread = 2,
+    Note = 3,
+}
+
+enum Outputs {
+    Left = 0,
+    Right = 1,
+}
+
+impl Panner {
+    pub fn new() -> Panner {
+        Panner {
+            data: vec![
+                module::DataIn::new(String::from("signal"), 0.0),
+                module::DataIn::new(String::from("pan"), 0.0),
+                module::DataIn::new(String::from("spread"), 0.0),
+                module::DataIn::new(String::from("note"), 0.5),
+            ],
+            left: Vec::new(),
+            right: Vec::new(),
+        }
+    }
+
+    // Constant power gains for a position from -1 (left) to 1 (right).
+    fn gains(position: f32) -> (f32, f32) {
+        let angle = (position.clamp(-1.0, 1.0) + 1.0) * f32::consts::PI / 4.0;
+        (angle.cos(), angle.sin())
+    }
+}
+
+impl module::Module for Panner {
+    fn connector(&self, name: &str, direction: module::Direction) -> Option<usize> {
+        match (direction, name) {
+            (module::Direction::Output, "left") => Some(Outputs::Left as usize),
+            (module::Direction::Output, "right") => Some(Outputs::Right as usize),
+            (module::Direction::Output, _) => None,
+            (module::Direction::Input, _) => self.data.iter().position(|v| v.name() == name),
+        }
+    }
+
+    fn feed(&mut self, input: usize, v: &[f32]) {
+        self.data[input].set(v);
+    }
+
+    fn set_default(&mut self, input: usize, value: f32) {
+        self.data[input].set_default(value);
+    }
+
+    fn get_default(&self, input: usize) -> f32 {
+        self.data[input].default()
+    }
+
+    // Both channels are rendered together. Inputs go back to their defaults unless fed again.
+    fn process(&mut self, len: usize) {
+        self.left.clear();
+        self.right.clear();
+        for i in 0..len {
+            let position = self.data[Inputs::Pan as usize].at(i) +
+                self.data[Inputs::Spread as usize].at(i) * (self.data[Inputs::Note as usize].at(i) - 0.5) * 2.0;
+            let signal = self.data[Inputs::Signal as usize].at(i);
+            let (l, r) = Panner::gains(position);
+            self.left.push(signal * l);
+            self.right.push(signal * r);
+        }
+        for d in &mut self.data {
+            d.clear();
+        }
+    }
+
+    fn output(&self, output: usize) -> &[f32] {
+        if output == Outputs::Left as usize {
+            &self.left
+        } else {
+            &self.right
+        }
+    }
+
+    fn reserve(&mut self, len: usize) {
+        self.left.reserve(len);
+        self.right.reserve(len);
+        for d in &mut self.data {
+            d.reserve(len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use synth::module::Module;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    // Left and right for a full scale signal at pan, spread and note.
+    fn panned(pan: f32, spread: f32, note: f32) -> (f32, f32) {
+        let mut panner = Panner::new();
+        panner.feed(Inputs::Signal as usize, &[1.0]);
+        panner.feed(Inputs::Pan as usize, &[pan]);
+        panner.feed(Inputs::Spread as usize, &[spread]);
+        panner.feed(Inputs::Note as usize, &[note]);
+        panner.process(1);
+        (panner.output(Outputs::Left as usize)[0], panner.output(Outputs::Right as usize)[0])
+    }
+
+    #[test]
+    fn centre_is_equal_power() {
+        let (l, r) = Panner::gains(0.0);
+        assert!(close(l, r));
+        assert!(close(l * l + r * r, 1.0));
+        assert_eq!(panned(0.0, 0.0, 0.5), (l, r));
+
+        // Power holds across the field.
+        for i in -10..=10 {
+            let (l, r) = Panner::gains(i as f32 / 10.0);
+            assert!(close(l * l + r * r, 1.0));
+        }
+    }
+
+    #[test]
+    fn extremes_are_one_channel() {
+        let (l, r) = panned(-1.0, 0.0, 0.5);
+        assert!(close(l, 1.0) && close(r, 0.0));
+        let (l, r) = panned(1.0, 0.0, 0.5);
+        assert!(close(l, 0.0) && close(r, 1.0));
+        // Positions past either side are held there.
+        assert_eq!(panned(-3.0, 0.0, 0.5), panned(-1.0, 0.0, 0.5));
+        assert_eq!(panned(3.0, 0.0, 0.5), panned(1.0, 0.0, 0.5));
+    }
+
+    #[test]
+    fn spread_moves_notes_from_the_middle() {
+        // Full spread puts the ends of the keyboard at the sides.
+        let (l, r) = panned(0.0, 1.0, 0.0);
+        assert!(close(l, 1.0) && close(r, 0.0));
+        let (l, r) = panned(0.0, 1.0, 1.0);
+        assert!(close(l, 0.0) && close(r, 1.0));
+        // The middle note stays put, and without spread every note does.
+        assert_eq!(panned(0.0, 1.0, 0.5), panned(0.0, 0.0, 0.5));
+        assert_eq!(panned(0.0, 0.0, 1.0), panned(0.0, 0.0, 0.5));
+        // Spread adds to pan, and the sum is held at the sides.
+        assert_eq!(panned(0.5, 0.5, 0.75), panned(0.75, 0.0, 0.5));
+        assert_eq!(panned(1.0, 1.0, 1.0), panned(1.0, 0.0, 0.5));
+    }
+}
@@ -0,0 +1,82 @@
+// Parameters which are not driven by control ports. These are kept by the engine and saved with
+// the plugin state, addressed by symbol.
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub enum ParamValue {
+    Float(f32),
+}
+
+pub struct ParamInfo {
+    pub symbol: &'static str,
+    pub name: &'static str,
+    // Turtle term for the unit, if any.
+    pub unit: Option<&'static str>,
+    pub default: f32,
+    pub minimum: f32,
+    pub maximum: f32,
+}
+
+impl ParamInfo {
+    pub fn clamp(&self, value: ParamValue) -> ParamValue {
+        match value {
+            ParamValue::Float(f) => ParamValue::Float(f.max(self.minimum).min(self.maximum)),
+        }
+    }
+}
+
+pub enum Param {
+    Tuning = 0,
+    Gain = 1,
+}
+
+pub const PARAMS: [ParamInfo; 2] = [
+    // Frequency of A4 in Hz
+    ParamInfo { symbol: "tuning", name: "Tuning", unit: Some("units:hz"), default: 440.0, minimum: 400.0, maximum: 480.0 },
+    // Linear output gain
+    ParamInfo { symbol: "gain", name: "Gain", unit: None, default: 1.0, minimum: 0.0, maximum: 2.0 },
+];
+
+pub struct Params {
+    values: Vec<ParamValue>,
+    // Parameters the host has asked to be reported back.
+    requested: Vec<bool>,
+}
+
+impl Params {
+    pub fn new() -> Params {
+        Params {
+            values: PARAMS.iter().map(|p| ParamValue::Float(p.default)).collect(),
+            requested: vec![false; PARAMS.len()],
+        }
+    }
+
+    pub fn get(&self, param: usize) -> &ParamValue {
+        &self.values[param]
+    }
+
+    pub fn get_float(&self, param: Param) -> f32 {
+        match self.values[param as usize] {
+            ParamValue::Float(f) => f,
+        }
+    }
+
+    pub fn set(&mut self, param: usize, value: ParamValue) {
+        self.values[param] = PARAMS[param].clamp(value);
+    }
+
+    pub fn request(&mut self, param: usize) {
+        self.requested[param] = true;
+    }
+
+    pub fn request_all(&mut self) {
+        self.requested.iter_mut().for_each(|r| *r = true);
+    }
+
+    pub fn take_requested(&mut self, param: usize) -> bool {
+        let r = self.requested[param];
+        self.requested[param] = false;
+        r
+    }
+}
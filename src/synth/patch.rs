@@ -0,0 +1,309 @@
+use std::fmt;
+
+use synth::module::{ConnectionInfo, ConnectorInfo, Direction, ModuleInfo, Rack, RackError};
+use synth::registry::{self, ModuleType, MODULE_TYPES};
+
+// A voice's rack as data: modules by type name with values for their inputs, and the cables
+// between them. Every rack also has the engine's own modules, which are not listed but can be
+// cabled to by name. Cables may not form a loop unless one of them is a feedback cable, which
+// carries its output a block late.
+//
+// As text, one statement per line, with # starting a comment:
+//
+//     module <name> <type> [<input>=<value> ...]
+//     cable <module>.<output> -> <module>.<input>
+//     feedback <module>.<output> -> <module>.<input>
+//
+// Modules must be declared before they are cabled. Errors give the line and column of the word at
+// fault.
+
+// The patch every voice plays until another is loaded.
+const FACTORY_PATCH: &str = include_str!("../../resources/default.patch");
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub struct ModuleSpec {
+    pub name: String,
+    pub kind: String,
+    // Values for inputs with nothing cabled to them.
+    pub values: Vec<(String, f32)>,
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub struct Patch {
+    pub modules: Vec<ModuleSpec>,
+    pub cables: Vec<ConnectionInfo>,
+}
+
+#[derive(Debug)]
+pub struct PatchError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}: {}", self.line, self.column, self.message)
+    }
+}
+
+// A word of a statement and the column it starts at, counting from 1.
+#[derive(Clone)]
+#[derive(Copy)]
+struct Word<'a> {
+    column: usize,
+    text: &'a str,
+}
+
+impl<'a> Word<'a> {
+    // Splits the word at the first separator, leaving it out of both parts.
+    fn split(self, separator: char) -> Option<(Word<'a>, Word<'a>)> {
+        self.text.find(separator).map(|i| (
+            Word { column: self.column, text: &self.text[..i] },
+            Word { column: self.column + self.text[..=i].chars().count(), text: &self.text[i + 1..] },
+        ))
+    }
+
+    fn error(self, message: String) -> (usize, String) {
+        (self.column, message)
+    }
+}
+
+// The words of a line, up to any comment.
+fn words(line: &str) -> Vec<Word<'_>> {
+    let mut words = Vec::new();
+    let mut start = None;
+    for (column, (i, c)) in line.char_indices().enumerate() {
+        match (start, c == '#' || c.is_whitespace()) {
+            (None, false) => start = Some((column + 1, i)),
+            (Some((column, s)), true) => {
+                words.push(Word { column, text: &line[s..i] });
+                start = None;
+            }
+            _ => (),
+        }
+        if c == '#' {
+            return words;
+        }
+    }
+    if let Some((column, s)) = start {
+        words.push(Word { column, text: &line[s..] });
+    }
+    words
+}
+
+impl Patch {
+    pub fn factory() -> Patch {
+        Patch::parse(FACTORY_PATCH).expect("factory patch is valid")
+    }
+
+    // Reads a patch from text, checking that everything it names exists.
+    pub fn parse(text: &str) -> Result<Patch, PatchError> {
+        let mut patch = Patch { modules: Vec::new(), cables: Vec::new() };
+        for (i, line) in text.lines().enumerate() {
+            let words = words(line);
+            let parsed = match words.first() {
+                None => Ok(()),
+                Some(w) if w.text == "module" => patch.parse_module(*w, &words[1..]),
+                Some(w) if w.text == "cable" => patch.parse_cable(*w, &words[1..], false),
+                Some(w) if w.text == "feedback" => patch.parse_cable(*w, &words[1..], true),
+                Some(w) => Err(w.error(format!("expected module, cable or feedback, found {}", w.text))),
+            };
+            parsed.map_err(|(column, message)| PatchError { line: i + 1, column, message })?;
+        }
+        Ok(patch)
+    }
+
+    fn parse_module(&mut self, statement: Word, words: &[Word]) -> Result<(), (usize, String)> {
+        let (name, kind, values) = match *words {
+            [name, kind, ref values @ ..] => (name, kind, values),
+            _ => return Err(statement.error(String::from("expected module <name> <type>"))),
+        };
+        let module_type = registry::module_type(kind.text).ok_or_else(|| {
+            let known: Vec<&str> = MODULE_TYPES.iter().map(|t| t.name).collect();
+            kind.error(format!("unknown module type {}, expected one of {}", kind.text, known.join(", ")))
+        })?;
+        if self.module_type(name.text).is_some() {
+            return Err(name.error(format!("module {} already exists", name.text)));
+        }
+
+        let mut spec = ModuleSpec { name: String::from(name.text), kind: String::from(kind.text), values: Vec::new() };
+        for value in values {
+            let (input, number) = value.split('=')
+                .ok_or_else(|| value.error(format!("expected <input>=<value>, found {}", value.text)))?;
+            if module_type.outputs.contains(&input.text) {
+                return Err(input.error(format!("{} is an output of {}, so cannot take a value", input.text, kind.text)));
+            }
+            if !module_type.inputs.contains(&input.text) {
+                return Err(input.error(format!("{} has no input {}", kind.text, input.text)));
+            }
+            let v = number.text.parse::<f32>()
+                .map_err(|_| number.error(format!("expected a number for {}, found {}", input.text, number.text)))?;
+            spec.values.push((String::from(input.text), v));
+        }
+        self.modules.push(spec);
+        Ok(())
+    }
+
+    fn parse_cable(&mut self, statement: Word, words: &[Word], feedback: bool) -> Result<(), (usize, String)> {
+        let (from, to) = match *words {
+            [from, arrow, to] if arrow.text == "->" => (from, to),
+            _ => return Err(statement.error(format!("expected {} <module>.<output> -> <module>.<input>", statement.text))),
+        };
+        let output = self.connector(from, false)?;
+        let input = self.connector(to, true)?;
+        if self.cables.iter().any(|c| *c.input() == input) {
+            return Err(to.error(format!("{} is already cabled", to.text)));
+        }
+        self.cables.push(if feedback {
+            ConnectionInfo::feedback(output, input)
+        } else {
+            ConnectionInfo::new(output, input)
+        });
+        Ok(())
+    }
+
+    // Parses <module>.<connector>, which must be an input or output of the module.
+    fn connector(&self, word: Word, input: bool) -> Result<ConnectorInfo, (usize, String)> {
+        let (module, connector) = word.split('.')
+            .ok_or_else(|| word.error(format!("expected <module>.<connector>, found {}", word.text)))?;
+        let module_type = self.module_type(module.text)
+            .ok_or_else(|| module.error(format!("unknown module {}", module.text)))?;
+        let (wanted, other) = if input { ("input", "output") } else { ("output", "input") };
+        if self.has_connector(module.text, connector.text, input) {
+            Ok(ConnectorInfo::new(module.text, connector.text))
+        } else if self.has_connector(module.text, connector.text, !input) {
+            Err(connector.error(format!("{} is an {} of {}, expected an {}", connector.text, other, module.text, wanted)))
+        } else {
+            Err(connector.error(format!("{} has no {} {}", module_type.name, wanted, connector.text)))
+        }
+    }
+
+    // Type of a module in the patch, or of an engine module.
+    pub fn module_type(&self, module: &str) -> Option<&'static ModuleType> {
+        registry::engine_module(module).or_else(|| {
+            self.modules.iter().find(|m| m.name == module).and_then(|m| registry::module_type(&m.kind))
+        })
+    }
+
+    pub fn has_connector(&self, module: &str, connector: &str, input: bool) -> bool {
+        match self.module_type(module) {
+            Some(t) if input => t.inputs.contains(&connector),
+            Some(t) => t.outputs.contains(&connector),
+            None => false,
+        }
+    }
+
+    // Describes a live rack, so it can be written out and built again. Values an input would
+    // take anyway are left out.
+    pub fn from_rack(rack: &Rack) -> Patch {
+        // Only patch modules have a type in the registry, so the engine's are left out.
+        let modules = rack.modules()
+            .filter_map(|(name, kind, module)| {
+                let values = registry::module_type(kind)?.defaults().into_iter()
+                    .filter_map(|(input, default)| {
+                        let value = module.get_default(module.connector(input, Direction::Input)?);
+                        if value != default { Some((String::from(input), value)) } else { None }
+                    })
+                    .collect();
+                Some(ModuleSpec { name: String::from(name), kind: String::from(kind), values })
+            })
+            .collect();
+        Patch { modules, cables: rack.cables().to_vec() }
+    }
+
+    // The patch's own modules, ready to go in a rack after the engine's.
+    pub fn build_modules(&self, rate: f32) -> Result<Vec<ModuleInfo>, RackError> {
+        self.modules.iter().map(|spec| {
+            let mut module = registry::build(&spec.kind, rate)
+                .ok_or_else(|| RackError::UnknownType { module: spec.name.clone(), kind: spec.kind.clone() })?;
+            for &(ref input, value) in &spec.values {
+                let index = module.connector(input, Direction::Input).ok_or_else(|| RackError::UnknownPort {
+                    module: spec.name.clone(),
+                    port: input.clone(),
+                    direction: Direction::Input,
+                })?;
+                module.set_default(index, value);
+            }
+            Ok(ModuleInfo::new(&spec.name, &spec.kind, module))
+        }).collect()
+    }
+}
+
+// Writes the patch in the form parse reads.
+impl fmt::Display for Patch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for spec in &self.modules {
+            write!(f, "module {} {}", spec.name, spec.kind)?;
+            for &(ref input, value) in &spec.values {
+                write!(f, " {}={:?}", input, value)?;
+            }
+            writeln!(f)?;
+        }
+        if !self.modules.is_empty() && !self.cables.is_empty() {
+            writeln!(f)?;
+        }
+        for cable in &self.cables {
+            let statement = if cable.is_feedback() { "feedback" } else { "cable" };
+            writeln!(f, "{} {}.{} -> {}.{}", statement, cable.output().module(), cable.output().connector(),
+                     cable.input().module(), cable.input().connector())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use synth::build_rack;
+
+    // The line, column and message parse gives for text.
+    fn error(text: &str) -> (usize, usize, String) {
+        let e = Patch::parse(text).unwrap_err();
+        (e.line, e.column, e.message)
+    }
+
+    #[test]
+    fn patches_survive_a_round_trip() {
+        let text = format!("{}\nmodule extra attenuverter attenuation=0.25\ncable extra.output -> panner.spread\n",
+                           FACTORY_PATCH.replace("cable buffer.spread -> panner.spread\n", ""));
+        for text in &[FACTORY_PATCH, &text[..]] {
+            let patch = Patch::parse(text).unwrap();
+            let rack = build_rack(48000.0, 64, &patch).unwrap();
+            let written = Patch::from_rack(&rack);
+            assert_eq!(written, patch);
+            assert_eq!(Patch::parse(&written.to_string()).unwrap(), patch);
+        }
+    }
+
+    #[test]
+    fn unknown_types_are_not_built() {
+        let spec = ModuleSpec { name: String::from("osc"), kind: String::from("wobbler"), values: Vec::new() };
+        let patch = Patch { modules: vec![spec], cables: Vec::new() };
+        let error = patch.build_modules(48000.0).err();
+        assert_eq!(error, Some(RackError::UnknownType { module: String::from("osc"), kind: String::from("wobbler") }));
+    }
+
+    #[test]
+    fn unknown_types_are_placed() {
+        let (line, column, message) = error("# osc\n\nmodule osc wobbler");
+        assert_eq!((line, column), (3, 12));
+        assert!(message.starts_with("unknown module type wobbler"));
+    }
+
+    #[test]
+    fn unknown_connectors_are_placed() {
+        let text = "module osc oscillator\ncable buffer.pan -> osc.pitch";
+        assert_eq!(error(text), (2, 25, String::from("oscillator has no input pitch")));
+    }
+
+    #[test]
+    fn outputs_are_not_inputs() {
+        let text = "module osc oscillator\n  cable buffer.pan -> osc.sine";
+        assert_eq!(error(text), (2, 27, String::from("sine is an output of osc, expected an input")));
+    }
+}
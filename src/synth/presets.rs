@@ -0,0 +1,248 @@
+use synth::SynthProperty;
+
+// Settings of the synth's control ports, named as the ports are. Ports only some variants have
+// are ignored by the others.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub struct Controls {
+    pub waveform: f32,
+    pub attack: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+    pub sec_waveform: f32,
+    pub sec_freq_mul: f32,
+    pub sec_depth: f32,
+    pub filter_freq: f32,
+    pub filter_on: f32,
+    pub pan: f32,
+    pub spread: f32,
+    pub glide: f32,
+    pub input_level: f32,
+    pub input_fm: f32,
+}
+
+impl Controls {
+    // Values by port symbol.
+    pub fn values(&self) -> [(&'static str, f32); 15] {
+        [
+            ("waveform", self.waveform),
+            ("attack", self.attack),
+            ("decay", self.decay),
+            ("sustain", self.sustain),
+            ("release", self.release),
+            ("sec_waveform", self.sec_waveform),
+            ("sec_freq_mul", self.sec_freq_mul),
+            ("sec_depth", self.sec_depth),
+            ("filter_freq", self.filter_freq),
+            ("filter_on", self.filter_on),
+            ("pan", self.pan),
+            ("spread", self.spread),
+            ("glide", self.glide),
+            ("input_level", self.input_level),
+            ("input_fm", self.input_fm),
+        ]
+    }
+
+    // Sets a control by port symbol. Symbols of other ports are ignored.
+    pub fn set(&mut self, symbol: &str, value: f32) {
+        match symbol {
+            "waveform" => self.waveform = value,
+            "attack" => self.attack = value,
+            "decay" => self.decay = value,
+            "sustain" => self.sustain = value,
+            "release" => self.release = value,
+            "sec_waveform" => self.sec_waveform = value,
+            "sec_freq_mul" => self.sec_freq_mul = value,
+            "sec_depth" => self.sec_depth = value,
+            "filter_freq" => self.filter_freq = value,
+            "filter_on" => self.filter_on = value,
+            "pan" => self.pan = value,
+            "spread" => self.spread = value,
+            "glide" => self.glide = value,
+            "input_level" => self.input_level = value,
+            "input_fm" => self.input_fm = value,
+            _ => {}
+        }
+    }
+
+    // Takes the values a property given by changes sets. Other properties are ignored.
+    pub fn apply(&mut self, prop: &SynthProperty) {
+        match *prop {
+            SynthProperty::Waveform(wave) => self.waveform = wave,
+            SynthProperty::Envelope(attack, decay, sustain, release) => {
+                self.attack = attack;
+                self.decay = decay;
+                self.sustain = sustain;
+                self.release = release;
+            }
+            SynthProperty::Secondary(wave, depth, multiplier) => {
+                self.sec_waveform = wave;
+                self.sec_depth = depth;
+                self.sec_freq_mul = multiplier;
+            }
+            SynthProperty::FilterFreq(freq) => self.filter_freq = freq,
+            SynthProperty::FilterOn(on) => self.filter_on = if on { 1.0 } else { 0.0 },
+            SynthProperty::Pan(pan, spread) => {
+                self.pan = pan;
+                self.spread = spread;
+            }
+            SynthProperty::Glide(glide) => self.glide = glide,
+            SynthProperty::Input(level, fm) => {
+                self.input_level = level;
+                self.input_fm = fm;
+            }
+            _ => {}
+        }
+    }
+
+    // Gives set the properties setting the engine to these controls, without allocating. Only
+    // groups which differ from previous are given, so settings made some other way hold until a
+    // control moves.
+    pub fn changes<F: FnMut(SynthProperty)>(&self, previous: Option<&Controls>, mut set: F) {
+        let changed = |same: &dyn Fn(&Controls) -> bool| previous.is_none_or(|p| !same(p));
+
+        if changed(&|p| p.waveform == self.waveform) {
+            set(SynthProperty::Waveform(self.waveform));
+        }
+        if changed(&|p| (p.attack, p.decay, p.sustain, p.release) == (self.attack, self.decay, self.sustain, self.release)) {
+            set(SynthProperty::Envelope(self.attack, self.decay, self.sustain, self.release));
+        }
+        if changed(&|p| (p.sec_waveform, p.sec_depth, p.sec_freq_mul) == (self.sec_waveform, self.sec_depth, self.sec_freq_mul)) {
+            set(SynthProperty::Secondary(self.sec_waveform, self.sec_depth, self.sec_freq_mul));
+        }
+        if changed(&|p| p.filter_freq == self.filter_freq) {
+            set(SynthProperty::FilterFreq(self.filter_freq));
+        }
+        if changed(&|p| p.filter_on == self.filter_on) {
+            set(SynthProperty::FilterOn(self.filter_on > 0.5));
+        }
+        if changed(&|p| (p.pan, p.spread) == (self.pan, self.spread)) {
+            set(SynthProperty::Pan(self.pan, self.spread));
+        }
+        if changed(&|p| p.glide == self.glide) {
+            set(SynthProperty::Glide(self.glide));
+        }
+        if changed(&|p| (p.input_level, p.input_fm) == (self.input_level, self.input_fm)) {
+            set(SynthProperty::Input(self.input_level, self.input_fm));
+        }
+    }
+}
+
+pub struct Preset {
+    // Names the preset in its URI.
+    pub symbol: &'static str,
+    pub name: &'static str,
+    pub controls: Controls,
+}
+
+// The factory bank, in MIDI program order.
+pub const PRESETS: [Preset; 5] = [
+    Preset {
+        symbol: "init",
+        name: "Init",
+        controls: Controls {
+            waveform: 0.0,
+            attack: 0.01,
+            decay: 0.013,
+            sustain: 0.5,
+            release: 0.25,
+            sec_waveform: 0.0,
+            sec_freq_mul: 0.0,
+            sec_depth: 0.0,
+            filter_freq: 0.0,
+            filter_on: 0.0,
+            pan: 0.0,
+            spread: 0.0,
+            glide: 0.0,
+            input_level: 0.0,
+            input_fm: 0.0,
+        },
+    },
+    Preset {
+        symbol: "soft_pad",
+        name: "Soft Pad",
+        controls: Controls {
+            waveform: 0.6,
+            attack: 0.15,
+            decay: 0.1,
+            sustain: 0.8,
+            release: 0.4,
+            sec_waveform: 0.0,
+            sec_freq_mul: 0.0,
+            sec_depth: 0.0,
+            filter_freq: 0.6,
+            filter_on: 1.0,
+            pan: 0.0,
+            spread: 0.6,
+            glide: 0.0,
+            input_level: 0.0,
+            input_fm: 0.0,
+        },
+    },
+    Preset {
+        symbol: "bright_lead",
+        name: "Bright Lead",
+        controls: Controls {
+            waveform: 0.4,
+            attack: 0.0,
+            decay: 0.02,
+            sustain: 0.7,
+            release: 0.05,
+            sec_waveform: 0.0,
+            sec_freq_mul: 0.0,
+            sec_depth: 0.0,
+            filter_freq: 0.8,
+            filter_on: 1.0,
+            pan: 0.0,
+            spread: 0.0,
+            glide: 0.08,
+            input_level: 0.0,
+            input_fm: 0.0,
+        },
+    },
+    Preset {
+        symbol: "square_bass",
+        name: "Square Bass",
+        controls: Controls {
+            waveform: 0.2,
+            attack: 0.0,
+            decay: 0.02,
+            sustain: 0.4,
+            release: 0.02,
+            sec_waveform: 0.0,
+            sec_freq_mul: 0.0,
+            sec_depth: 0.0,
+            filter_freq: 0.35,
+            filter_on: 1.0,
+            pan: 0.0,
+            spread: 0.0,
+            glide: 0.0,
+            input_level: 0.0,
+            input_fm: 0.0,
+        },
+    },
+    Preset {
+        symbol: "fm_bell",
+        name: "FM Bell",
+        controls: Controls {
+            waveform: 0.0,
+            attack: 0.0,
+            decay: 0.2,
+            sustain: 0.0,
+            release: 0.3,
+            sec_waveform: 0.0,
+            sec_freq_mul: 0.7,
+            sec_depth: 0.3,
+            filter_freq: 0.0,
+            filter_on: 0.0,
+            pan: 0.0,
+            spread: 0.3,
+            glide: 0.0,
+            input_level: 0.0,
+            input_fm: 0.0,
+        },
+    },
+];
@@ -0,0 +1,137 @@
+use synth::envelope;
+use synth::module::{self, Direction, Module};
+use synth::oscillator;
+use synth::panner;
+use synth::{buffer_default, AUDIO_IN_ITEMS, BUFFER_ITEMS};
+
+// The kinds of module a rack can hold, by type name, with what can be cabled to and from them.
+
+// Rate modules are built at when only their description is wanted.
+const NOMINAL_RATE: f32 = 48000.0;
+
+pub struct ModuleType {
+    pub name: &'static str,
+    pub inputs: &'static [&'static str],
+    pub outputs: &'static [&'static str],
+    build: fn(f32) -> Box<dyn Module>,
+}
+
+impl ModuleType {
+    pub fn build(&self, rate: f32) -> Box<dyn Module> {
+        (self.build)(rate)
+    }
+
+    // Value each input takes with nothing cabled to it, as the module itself has it.
+    pub fn defaults(&self) -> Vec<(&'static str, f32)> {
+        let module = self.build(NOMINAL_RATE);
+        self.inputs.iter()
+            .filter_map(|input| module.connector(input, Direction::Input).map(|i| (*input, module.get_default(i))))
+            .collect()
+    }
+}
+
+fn oscillator(rate: f32) -> Box<dyn Module> {
+    Box::new(oscillator::Oscillator::new(rate))
+}
+
+fn envelope(rate: f32) -> Box<dyn Module> {
+    Box::new(envelope::Envelope::new(rate))
+}
+
+fn attenuverter(_rate: f32) -> Box<dyn Module> {
+    Box::new(module::Attenuverter::new())
+}
+
+fn mixer(_rate: f32) -> Box<dyn Module> {
+    Box::new(module::Mixer::new())
+}
+
+fn panner(_rate: f32) -> Box<dyn Module> {
+    Box::new(panner::Panner::new())
+}
+
+fn buffer(_rate: f32) -> Box<dyn Module> {
+    let items = BUFFER_ITEMS.iter().enumerate()
+        .map(|(i, name)| module::DataIn::new(String::from(*name), buffer_default(i)))
+        .collect();
+    Box::new(module::BufferModule::new(items))
+}
+
+fn audio_in(_rate: f32) -> Box<dyn Module> {
+    let items = AUDIO_IN_ITEMS.iter()
+        .map(|name| module::DataIn::new(String::from(*name), 0.0))
+        .collect();
+    Box::new(module::BufferModule::new(items))
+}
+
+// Modules a patch can use.
+pub const MODULE_TYPES: [ModuleType; 5] = [
+    ModuleType {
+        name: "oscillator",
+        inputs: &["primary", "freq_in", "fm_in", "duty_cycle_in"],
+        outputs: &["output", "sine", "square", "saw", "triangle"],
+        build: oscillator,
+    },
+    ModuleType {
+        name: "envelope",
+        inputs: &["attack", "decay", "sustain", "release", "trigger", "signal"],
+        outputs: &["output", "level", "end"],
+        build: envelope,
+    },
+    ModuleType {
+        name: "attenuverter",
+        inputs: &["attenuation", "signal"],
+        outputs: &["output"],
+        build: attenuverter,
+    },
+    ModuleType {
+        name: "mixer",
+        inputs: &["input_a", "level_a", "input_b", "level_b"],
+        outputs: &["output"],
+        build: mixer,
+    },
+    ModuleType {
+        name: "panner",
+        inputs: &["signal", "pan", "spread", "note"],
+        outputs: &["left", "right"],
+        build: panner,
+    },
+];
+
+// Modules the engine adds to every rack, each named after its type. The engine feeds the buffer
+// with controls and notes and reads the output back from it, and feeds audio_in with the audio
+// input.
+pub const ENGINE_MODULES: [ModuleType; 2] = [
+    ModuleType { name: "buffer", inputs: &BUFFER_ITEMS, outputs: &BUFFER_ITEMS, build: buffer },
+    ModuleType { name: "audio_in", inputs: &[], outputs: &AUDIO_IN_ITEMS, build: audio_in },
+];
+
+pub fn module_type(name: &str) -> Option<&'static ModuleType> {
+    MODULE_TYPES.iter().find(|t| t.name == name)
+}
+
+pub fn engine_module(name: &str) -> Option<&'static ModuleType> {
+    ENGINE_MODULES.iter().find(|t| t.name == name)
+}
+
+// Builds a module a patch can use, by type name.
+pub fn build(name: &str, rate: f32) -> Option<Box<dyn Module>> {
+    module_type(name).map(|t| t.build(rate))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listed_connectors_resolve() {
+        for t in MODULE_TYPES.iter().chain(ENGINE_MODULES.iter()) {
+            let module = t.build(NOMINAL_RATE);
+            for (connectors, direction) in &[(t.inputs, Direction::Input), (t.outputs, Direction::Output)] {
+                for (i, name) in connectors.iter().enumerate() {
+                    assert_eq!(module.connector(name, *direction), Some(i), "{} {:?} {}", t.name, direction, name);
+                }
+            }
+        }
+    }
+}
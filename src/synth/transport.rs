@@ -0,0 +1,113 @@
+// Host transport position. The host only sends a position when something changes, so the
+// position is advanced locally between updates.
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct Transport {
+    pub frame: i64,
+    pub speed: f32,
+    pub bpm: f32,
+    pub bar: i64,
+    pub bar_beat: f32,
+    pub beats_per_bar: f32,
+    pub beat_unit: i32,
+}
+
+impl Transport {
+    pub fn new() -> Transport {
+        Transport {
+            frame: 0,
+            speed: 0.0,
+            bpm: 120.0,
+            bar: 0,
+            bar_beat: 0.0,
+            beats_per_bar: 4.0,
+            beat_unit: 4,
+        }
+    }
+
+    // Beats advanced per sample at the current tempo and speed.
+    pub fn beats_per_frame(&self, rate: f32) -> f32 {
+        self.speed * self.bpm / (60.0 * rate)
+    }
+
+    // Fraction of the current beat, from 0 up to 1.
+    pub fn beat_phase(&self) -> f32 {
+        self.bar_beat.fract()
+    }
+
+    pub fn advance(&mut self, frames: usize, rate: f32) {
+        self.frame += (frames as f32 * self.speed) as i64;
+        self.bar_beat += frames as f32 * self.beats_per_frame(rate);
+        if self.beats_per_bar > 0.0 {
+            while self.bar_beat >= self.beats_per_bar {
+                self.bar_beat -= self.beats_per_bar;
+                self.bar += 1;
+            }
+            while self.bar_beat < 0.0 {
+                self.bar_beat += self.beats_per_bar;
+                self.bar -= 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn playing(speed: f32) -> Transport {
+        Transport { speed, ..Transport::new() }
+    }
+
+    #[test]
+    fn speed_scales_frames_and_beats() {
+        // A beat at 120 bpm is half a second.
+        let mut transport = playing(1.0);
+        transport.advance(24000, 48000.0);
+        assert_eq!(transport.frame, 24000);
+        assert!((transport.bar_beat - 1.0).abs() < 1e-4);
+
+        let mut half = playing(0.5);
+        half.advance(24000, 48000.0);
+        assert_eq!(half.frame, 12000);
+        assert!((half.bar_beat - 0.5).abs() < 1e-4);
+
+        let mut stopped = playing(0.0);
+        stopped.advance(24000, 48000.0);
+        assert_eq!(stopped.frame, 0);
+        assert_eq!(stopped.bar_beat, 0.0);
+    }
+
+    #[test]
+    fn beats_carry_into_bars() {
+        let mut transport = Transport { bar: 2, bar_beat: 3.5, ..playing(1.0) };
+        transport.advance(24000, 48000.0);
+        assert_eq!(transport.bar, 3);
+        assert!((transport.bar_beat - 0.5).abs() < 1e-4);
+
+        // Several bars in one advance.
+        transport.advance(24000 * 9, 48000.0);
+        assert_eq!(transport.bar, 5);
+        assert!((transport.bar_beat - 1.5).abs() < 1e-3);
+
+        let mut waltz = Transport { beats_per_bar: 3.0, bar_beat: 2.5, ..playing(1.0) };
+        waltz.advance(24000, 48000.0);
+        assert_eq!(waltz.bar, 1);
+        assert!((waltz.bar_beat - 0.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn reverse_play_goes_back_through_bars() {
+        let mut transport = Transport { bar: 1, bar_beat: 0.5, ..playing(-1.0) };
+        transport.advance(24000, 48000.0);
+        assert_eq!(transport.bar, 0);
+        assert!((transport.bar_beat - 3.5).abs() < 1e-4);
+        assert_eq!(transport.frame, -24000);
+    }
+
+    #[test]
+    fn beat_phase_is_the_fraction_of_the_beat() {
+        let transport = Transport { bar_beat: 2.25, ..Transport::new() };
+        assert_eq!(transport.beat_phase(), 0.25);
+    }
+}
@@ -0,0 +1,129 @@
+use std::fmt;
+
+use synth::module;
+
+// Pitch difference, in the 0-1 note scale, at which a glide snaps to its target.
+const GLIDE_EPSILON: f32 = 1e-5;
+
+// How notes are assigned to voices.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub enum Voicing {
+    // One voice, retriggered by every note and released by any note off.
+    Single,
+    // One voice with last note priority. Overlapping notes glide without retriggering, and
+    // releasing a note returns to the latest one still held.
+    MonoLead,
+    // A voice per note, up to the given count, stealing the oldest when all are playing.
+    Poly(usize),
+}
+
+impl Voicing {
+    pub fn voice_count(&self) -> usize {
+        match *self {
+            Voicing::Single | Voicing::MonoLead => 1,
+            Voicing::Poly(n) => n,
+        }
+    }
+
+    // Reads a voicing as Display writes it.
+    pub fn parse(text: &str) -> Option<Voicing> {
+        let mut words = text.split_whitespace();
+        let voicing = match (words.next(), words.next()) {
+            (Some("single"), None) => Voicing::Single,
+            (Some("mono_lead"), None) => Voicing::MonoLead,
+            (Some("poly"), Some(n)) => match n.parse() {
+                Ok(n) if n > 0 => Voicing::Poly(n),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        match words.next() {
+            None => Some(voicing),
+            Some(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Voicing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Voicing::Single => write!(f, "single"),
+            Voicing::MonoLead => write!(f, "mono_lead"),
+            Voicing::Poly(n) => write!(f, "poly {}", n),
+        }
+    }
+}
+
+// A rack playing at most one note at a time.
+pub struct Voice {
+    pub rack: module::Rack,
+    pub note: Option<u8>,
+    // When the current note started, in notes played, so the oldest can be stolen.
+    pub started: u64,
+    pitch: f32,
+    target: f32,
+    // Frame of the next block a glide starts at.
+    glide_from: usize,
+    // Pitch for each sample of the block while gliding.
+    pitches: Vec<f32>,
+}
+
+impl Voice {
+    pub fn new(rack: module::Rack, max_block: usize) -> Voice {
+        Voice {
+            rack,
+            note: None,
+            started: 0,
+            pitch: 0.0,
+            target: 0.0,
+            glide_from: 0,
+            pitches: Vec::with_capacity(max_block),
+        }
+    }
+
+    // Not realtime safe.
+    pub fn reserve(&mut self, max_block: usize) {
+        self.rack.reserve(max_block);
+        self.pitches.reserve(max_block);
+    }
+
+    // Jumps straight to a pitch.
+    pub fn set_pitch(&mut self, pitch: f32) {
+        self.pitch = pitch;
+        self.target = pitch;
+    }
+
+    // Glides to a pitch from a frame of the next block on, or straight away if already gliding.
+    pub fn glide_to(&mut self, pitch: f32, frame: usize) {
+        if self.pitch == self.target {
+            self.glide_from = frame;
+        }
+        self.target = pitch;
+    }
+
+    // Feeds the pitch for each sample of the next block to the buffer item while gliding, where
+    // coeff is how much of the distance to the target remains after each sample. Stops once the
+    // target has been reached and fed.
+    pub fn glide(&mut self, samples: usize, coeff: f32, item: usize) {
+        if self.pitch == self.target {
+            return;
+        }
+        self.pitches.clear();
+        if (self.pitch - self.target).abs() < GLIDE_EPSILON {
+            self.pitch = self.target;
+            self.pitches.push(self.target);
+        } else {
+            for i in 0..samples {
+                if i >= self.glide_from {
+                    self.pitch = self.target + (self.pitch - self.target) * coeff;
+                }
+                self.pitches.push(self.pitch);
+            }
+        }
+        self.glide_from = 0;
+        self.rack.get(0).feed(item, &self.pitches);
+    }
+}
@@ -0,0 +1,122 @@
+use std::f32;
+use std::fmt;
+use std::fs;
+use synth::filter;
+use synth::patch::Patch;
+use synth::voice::Voice;
+use synth::module::RackError;
+use synth::build_voices;
+
+// Work the engine cannot do in the audio thread. Jobs are requested by the engine, run on a
+// non-realtime thread, and their responses swapped into the engine.
+
+// Order of the Butterworth filter designed for the output.
+const FILTER_ORDER: u32 = 4;
+
+// Longest patch path that can be asked for. Jobs are copied whole through the host's worker
+// queue, so this is kept well under the queue sizes hosts use.
+pub const PATCH_PATH_SIZE: usize = 512;
+
+// A patch file path, held without allocating so the audio thread can pass it to the worker.
+#[derive(Clone)]
+#[derive(Copy)]
+pub struct PatchPath {
+    len: usize,
+    bytes: [u8; PATCH_PATH_SIZE],
+}
+
+impl PatchPath {
+    // Keeps as much of the path as fits.
+    pub fn new(path: &[u8]) -> PatchPath {
+        let mut bytes = [0; PATCH_PATH_SIZE];
+        let kept = path.len().min(PATCH_PATH_SIZE);
+        bytes[..kept].copy_from_slice(&path[..kept]);
+        PatchPath { len: path.len(), bytes }
+    }
+
+    // The path, or None if it was too long to keep.
+    pub fn get(&self) -> Option<&[u8]> {
+        if self.len <= PATCH_PATH_SIZE {
+            Some(&self.bytes[..self.len])
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Debug for PatchPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.get() {
+            Some(path) => write!(f, "{:?}", String::from_utf8_lossy(path)),
+            None => write!(f, "<{} bytes>", self.len),
+        }
+    }
+}
+
+// The path is held inline, as boxing it would allocate in the audio thread.
+#[allow(clippy::large_enum_variant)]
+pub enum Job {
+    DesignFilter { cutoff: f32, rate: f32 },
+    // Builds voices from a patch file.
+    LoadPatch { path: PatchPath, rate: f32, max_block: usize, voices: usize },
+}
+
+// Voices built from a patch, or the ones they replaced.
+pub struct LoadedPatch {
+    pub patch: Patch,
+    pub rate: f32,
+    // Longest block the voices have room for.
+    pub max_block: usize,
+    pub voices: Vec<Voice>,
+    // The rack the voices were built with, as patch text.
+    pub text: String,
+    // Set once the engine plays the voices. The voices are then the ones they replaced, while the
+    // text is still that of the rack now playing.
+    pub played: bool,
+}
+
+impl LoadedPatch {
+    // Builds voices from the patch.
+    pub fn build(patch: Patch, rate: f32, max_block: usize, voices: usize) -> Result<LoadedPatch, RackError> {
+        let voices = build_voices(rate, max_block, voices, &patch)?;
+        let text = Patch::from_rack(&voices[0].rack).to_string();
+        Ok(LoadedPatch { patch, rate, max_block, voices, text, played: false })
+    }
+}
+
+// The output filter for a cutoff in Hz.
+pub fn lowpass(cutoff: f32, rate: f32) -> filter::Filter {
+    filter::Filter::from_cfg(filter::butterworth_lpf(FILTER_ORDER, 2.0 * f32::consts::PI * cutoff, rate))
+}
+
+// Redesigns an output filter for a new cutoff without allocating, so it can be done in the audio
+// thread.
+pub fn redesign_lowpass(filter: &mut filter::Filter, cutoff: f32, rate: f32) {
+    filter.set_cfg(filter::butterworth_lpf_iter(FILTER_ORDER, 2.0 * f32::consts::PI * cutoff, rate));
+}
+
+pub enum Response {
+    // A filter for each channel.
+    Filter(Box<[filter::Filter; 2]>),
+    Patch(Box<LoadedPatch>),
+}
+
+impl Job {
+    // Runs the job, or describes why it could not be done.
+    pub fn run(self) -> Result<Response, String> {
+        match self {
+            Job::DesignFilter { cutoff, rate } => {
+                let filter = lowpass(cutoff, rate);
+                Ok(Response::Filter(Box::new([filter.clone(), filter])))
+            }
+            Job::LoadPatch { path, rate, max_block, voices } => {
+                let path = String::from_utf8_lossy(path.get().unwrap_or(&[])).into_owned();
+                let text = fs::read_to_string(&path).map_err(|e| format!("Cannot read patch {}: {}", path, e))?;
+                let patch = Patch::parse(&text).map_err(|e| format!("Cannot load patch {}: {}", path, e))?;
+                let loaded = LoadedPatch::build(patch, rate, max_block, voices)
+                    .map_err(|e| format!("Cannot build patch {}: {}", path, e))?;
+                Ok(Response::Patch(Box::new(loaded)))
+            }
+        }
+    }
+}
@@ -0,0 +1,249 @@
+// Stand-ins for a host, and allocation counting, shared by the tests.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::ffi;
+use std::os::raw;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use lv2_raw::core::*;
+use lv2_raw::options::*;
+use lv2_raw::state::*;
+use lv2_raw::urid::*;
+use lv2_raw::worker::*;
+use lv2::urid::urid_for_const;
+
+// Counts allocations and frees made by a thread while it has counting on, so tests running alongside
+// are not counted.
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static COUNTING: Cell<bool> = const { Cell::new(false) };
+}
+
+fn count() {
+    if COUNTING.try_with(|c| c.get()).unwrap_or(false) {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count();
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        count();
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+// Allocations and frees made by f on this thread.
+pub fn allocations<F: FnOnce()>(f: F) -> usize {
+    let before = ALLOCATIONS.load(Ordering::SeqCst);
+    COUNTING.with(|c| c.set(true));
+    f();
+    COUNTING.with(|c| c.set(false));
+    ALLOCATIONS.load(Ordering::SeqCst) - before
+}
+
+// Largest message the fake worker carries.
+const MESSAGE_SIZE: usize = 1024;
+
+// Messages copied into preallocated slots, as a host's ring buffer would hold them, so passing
+// one does not allocate. A full queue refuses more.
+struct MessageQueue {
+    messages: RefCell<Vec<(usize, [u8; MESSAGE_SIZE])>>,
+    size: usize,
+}
+
+impl MessageQueue {
+    fn new(size: usize) -> MessageQueue {
+        MessageQueue { messages: RefCell::new(Vec::with_capacity(size)), size }
+    }
+}
+
+extern "C" fn push_message(handle: *mut raw::c_void, size: u32, data: *const raw::c_void) -> LV2_Worker_Status {
+    let queue = unsafe { &*(handle as *const MessageQueue) };
+    let mut messages = queue.messages.borrow_mut();
+    let size = size as usize;
+    assert!(size <= MESSAGE_SIZE);
+    if messages.len() == queue.size {
+        return LV2_WORKER_ERR_NO_SPACE;
+    }
+    let mut message = [0; MESSAGE_SIZE];
+    message[..size].copy_from_slice(unsafe { std::slice::from_raw_parts(data as *const u8, size) });
+    messages.push((size, message));
+    LV2_WORKER_SUCCESS
+}
+
+// Hands out URIDs in the order URIs are first mapped.
+extern "C" fn map_uri(handle: LV2_URID_Map_Handle, uri: *const raw::c_char) -> LV2_URID {
+    let uris = unsafe { &mut *(handle as *mut Vec<ffi::CString>) };
+    let uri = unsafe { ffi::CStr::from_ptr(uri) };
+    match uris.iter().position(|u| u.as_c_str() == uri) {
+        Some(i) => i as LV2_URID + 1,
+        None => {
+            uris.push(uri.to_owned());
+            uris.len() as LV2_URID
+        }
+    }
+}
+
+pub const NO_MORE_OPTIONS: LV2_Options_Option = LV2_Options_Option {
+    context: LV2_OPTIONS_INSTANCE, subject: 0, key: 0, size: 0, type_: 0, value: ptr::null(),
+};
+
+pub fn option(key: LV2_URID, type_: LV2_URID, size: usize, value: *const raw::c_void) -> LV2_Options_Option {
+    LV2_Options_Option { context: LV2_OPTIONS_INSTANCE, subject: 0, key, size: size as u32, type_, value }
+}
+
+// A host giving plugins a URID map and options, and a worker if asked for one. Work is done when
+// the test says, as a host would do it between blocks.
+pub struct Host {
+    map: Box<LV2_URID_Map>,
+    schedule: Box<LV2_Worker_Schedule>,
+    jobs: Box<MessageQueue>,
+    responses: Box<MessageQueue>,
+}
+
+impl Host {
+    pub fn new() -> Host {
+        Host::with_queue_size(16)
+    }
+
+    // A host whose worker takes at most size messages between calls to work.
+    pub fn with_queue_size(size: usize) -> Host {
+        let uris: Box<Vec<ffi::CString>> = Box::default();
+        let jobs = Box::new(MessageQueue::new(size));
+        Host {
+            map: Box::new(LV2_URID_Map { handle: Box::into_raw(uris) as LV2_URID_Map_Handle, map: map_uri }),
+            schedule: Box::new(LV2_Worker_Schedule {
+                handle: &*jobs as *const MessageQueue as LV2_Worker_Schedule_Handle,
+                schedule_work: push_message,
+            }),
+            jobs,
+            responses: Box::new(MessageQueue::new(size)),
+        }
+    }
+
+    pub fn urid(&self, uri: &str) -> LV2_URID {
+        let uri = ffi::CString::new(uri).unwrap();
+        map_uri(self.map.handle, uri.as_ptr())
+    }
+
+    pub fn urid_for_const(&self, uri: *const u8) -> LV2_URID {
+        urid_for_const(&*self.map, uri)
+    }
+
+    fn instantiate_with(&self, descriptor: &LV2_Descriptor, options: &[LV2_Options_Option], worker: bool)
+                        -> LV2_Handle {
+        let map = LV2_Feature {
+            URI: LV2_URID_map as *const raw::c_char,
+            data: &*self.map as *const LV2_URID_Map as *mut raw::c_void,
+        };
+        let options = LV2_Feature {
+            URI: LV2_OPTIONS_options as *const raw::c_char,
+            data: options.as_ptr() as *mut raw::c_void,
+        };
+        let schedule = LV2_Feature {
+            URI: LV2_WORKER_schedule as *const raw::c_char,
+            data: &*self.schedule as *const LV2_Worker_Schedule as *mut raw::c_void,
+        };
+        let features = [&map as *const LV2_Feature, &options, if worker { &schedule } else { ptr::null() }, ptr::null()];
+        (descriptor.instantiate)(descriptor, 48000.0, ptr::null(), features.as_ptr())
+    }
+
+    // An instance with no worker.
+    pub fn instantiate(&self, descriptor: &LV2_Descriptor, options: &[LV2_Options_Option]) -> LV2_Handle {
+        self.instantiate_with(descriptor, options, false)
+    }
+
+    pub fn instantiate_with_worker(&self, descriptor: &LV2_Descriptor, options: &[LV2_Options_Option])
+                                   -> LV2_Handle {
+        self.instantiate_with(descriptor, options, true)
+    }
+
+    // Messages scheduled since the last call to work.
+    pub fn scheduled(&self) -> usize {
+        self.jobs.messages.borrow().len()
+    }
+
+    // Does the scheduled work, then delivers the responses and ends the run.
+    pub fn work(&self, instance: LV2_Handle, worker: &LV2_Worker_Interface) {
+        let jobs: Vec<_> = self.jobs.messages.borrow_mut().drain(..).collect();
+        for (size, message) in jobs {
+            let handle = &*self.responses as *const MessageQueue as LV2_Worker_Respond_Handle;
+            (worker.work)(instance, push_message, handle, size as u32, message.as_ptr() as *const raw::c_void);
+        }
+        let responses: Vec<_> = self.responses.messages.borrow_mut().drain(..).collect();
+        for (size, message) in responses {
+            (worker.work_response)(instance, size as u32, message.as_ptr() as *const raw::c_void);
+        }
+        (worker.end_run)(instance);
+    }
+}
+
+impl Drop for Host {
+    fn drop(&mut self) {
+        drop(unsafe { Box::from_raw(self.map.handle as *mut Vec<ffi::CString>) });
+    }
+}
+
+// State a host keeps for a plugin, as the value type and bytes stored under each key.
+#[derive(Default)]
+pub struct State {
+    pub values: HashMap<LV2_URID, (LV2_URID, Vec<u8>)>,
+}
+
+extern "C" fn store_value(handle: LV2_State_Handle, key: u32, value: *const raw::c_void, size: usize, type_: u32,
+                          _flags: u32) -> LV2_State_Status {
+    let state = unsafe { &mut *(handle as *mut State) };
+    let bytes = unsafe { std::slice::from_raw_parts(value as *const u8, size) };
+    state.values.insert(key, (type_, bytes.to_vec()));
+    LV2_STATE_SUCCESS
+}
+
+extern "C" fn retrieve_value(handle: LV2_State_Handle, key: u32, size: *mut usize, type_: *mut u32, flags: *mut u32)
+                             -> *const raw::c_void {
+    let state = unsafe { &*(handle as *const State) };
+    match state.values.get(&key) {
+        Some(&(value_type, ref bytes)) => {
+            unsafe {
+                *size = bytes.len();
+                *type_ = value_type;
+                *flags = LV2_STATE_IS_POD | LV2_STATE_IS_PORTABLE;
+            }
+            bytes.as_ptr() as *const raw::c_void
+        }
+        None => ptr::null(),
+    }
+}
+
+impl State {
+    pub fn save(&mut self, instance: LV2_Handle, interface: &LV2_State_Interface) -> LV2_State_Status {
+        (interface.save)(instance, store_value, self as *mut State as LV2_State_Handle, 0, ptr::null())
+    }
+
+    pub fn restore(&mut self, instance: LV2_Handle, interface: &LV2_State_Interface) -> LV2_State_Status {
+        (interface.restore)(instance, retrieve_value, self as *mut State as LV2_State_Handle, 0, ptr::null())
+    }
+}